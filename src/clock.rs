@@ -0,0 +1,539 @@
+//! A virtual device which generates MIDI clock at a tempo,
+//! or follows the tempo of a clock sent to it,
+//! so that e.g. delay effects and lighting chases can be kept in time.
+
+use std::{collections::VecDeque, future, mem, time::Duration};
+
+use midly::{
+    live::{LiveEvent, SystemCommon, SystemRealtime},
+    MidiMessage,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+
+use crate::{
+    device::{self, Transport},
+    midi::{
+        state::{Controller14, State},
+        Event,
+    },
+};
+
+/// The name of the built-in profile for clock devices, which names their controls.
+pub const PROFILE: &str = "clock";
+
+// These must match the `tempo` and `tap` controls in `profiles/clock.yaml`,
+// which the tests check.
+const CHANNEL: u8 = 0;
+const TEMPO_CONTROLLER: u8 = 16;
+const TAP_NOTE: u8 = 0;
+
+const TICKS_PER_BEAT: f64 = 24.0;
+
+/// Each step of the song position is a 16th note.
+const TICKS_PER_POSITION: u32 = 6;
+
+const MIN_TEMPO: f64 = 20.0;
+const MAX_TEMPO: f64 = 999.0;
+
+/// An incoming clock which doesn't tick for this long has stopped,
+/// so its next tick starts measuring its tempo afresh.
+const MAX_TICK_GAP: Duration = Duration::from_millis(500);
+
+/// Taps further apart than this start a new tempo.
+const TAP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many of the latest taps the tempo is set from.
+// @Checkme: is this steady enough without taking too long to follow a change?
+const MAX_TAPS: usize = 5;
+
+/// How a clock device starts off, e.g. `{tempo: 120, running: true}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    /// The tempo to start at, in beats per minute.
+    pub tempo: f64,
+
+    /// Whether to start as soon as the clock is connected,
+    /// rather than waiting to be sent a `start`.
+    pub running: bool,
+
+    /// Roughly how many ticks of an incoming clock its tempo is averaged over,
+    /// to smooth out any jitter.
+    pub smoothing: u32,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            tempo: 120.0,
+            running: false,
+            smoothing: 24,
+        }
+    }
+}
+
+/// A virtual device which sends 24 MIDI clock ticks per beat while it's running.
+///
+/// Sending it `Start`, `Continue`, `Stop` or `SongPosition` controls it,
+/// and it sends them on to anything following it.
+/// Sending it a clock sets its tempo from the clock's (but doesn't keep it in phase),
+/// as does tapping its `tap` note.
+/// Whenever its tempo changes, it sends the new tempo
+/// as a 14-bit controller, in tenths of a beat per minute (see `profiles/clock.yaml`),
+/// and sending it that sets the tempo.
+pub struct Clock {
+    settings: Settings,
+
+    /// In beats per minute.
+    tempo: f64,
+
+    /// When the clock last ticked, and is next due to, while it's running.
+    last_tick: Option<Instant>,
+    next_tick: Option<Instant>,
+
+    /// How many ticks it is from the beginning, for the song position.
+    ticks: u32,
+
+    /// When the incoming clock last ticked, up to `smoothing` times.
+    incoming: VecDeque<Instant>,
+
+    taps: VecDeque<Instant>,
+
+    /// For following the 14-bit tempo controller.
+    state: State,
+
+    /// The tempo which was last sent, as a raw controller value.
+    sent_tempo: Option<u16>,
+
+    /// Messages waiting to be read from the device.
+    pending: Vec<Event>,
+}
+
+impl Clock {
+    pub fn new(settings: Settings) -> Self {
+        Clock {
+            settings,
+            tempo: settings.tempo,
+            last_tick: None,
+            next_tick: None,
+            ticks: 0,
+            incoming: VecDeque::new(),
+            taps: VecDeque::new(),
+            state: State::default(),
+            sent_tempo: None,
+            pending: Vec::new(),
+        }
+    }
+
+    /// The time between ticks.
+    fn interval(&self) -> Duration {
+        Duration::from_secs_f64(60.0 / (self.tempo * TICKS_PER_BEAT))
+    }
+
+    fn set_tempo(&mut self, tempo: f64) {
+        self.tempo = tempo.clamp(MIN_TEMPO, MAX_TEMPO);
+        if let (Some(last_tick), Some(_)) = (self.last_tick, self.next_tick) {
+            self.next_tick = Some(last_tick + self.interval());
+        }
+
+        let raw = (self.tempo * 10.0).round() as u16;
+        if self.sent_tempo != Some(raw) {
+            log::debug!("Clock tempo is {:.1} BPM", self.tempo);
+            self.sent_tempo = Some(raw);
+            let controller_14 = Controller14 {
+                controller: TEMPO_CONTROLLER,
+                value: raw,
+            };
+            self.pending
+                .extend(controller_14.controllers().map(|(controller, value)| {
+                    Event::from(LiveEvent::Midi {
+                        channel: CHANNEL.into(),
+                        message: MidiMessage::Controller {
+                            controller: controller.into(),
+                            value: value.into(),
+                        },
+                    })
+                }));
+        }
+    }
+
+    fn start(&mut self, now: Instant) {
+        self.ticks = 0;
+        self.run(now, SystemRealtime::Start);
+    }
+
+    fn resume(&mut self, now: Instant) {
+        self.pending.push(song_position(self.position()));
+        self.run(now, SystemRealtime::Continue);
+    }
+
+    /// Starts ticking straight away, after sending `message`.
+    fn run(&mut self, now: Instant, message: SystemRealtime) {
+        self.pending.push(Event::from(LiveEvent::Realtime(message)));
+        self.last_tick = None;
+        self.next_tick = Some(now);
+    }
+
+    fn stop(&mut self) {
+        self.pending
+            .push(Event::from(LiveEvent::Realtime(SystemRealtime::Stop)));
+        self.last_tick = None;
+        self.next_tick = None;
+    }
+
+    fn position(&self) -> u16 {
+        (self.ticks / TICKS_PER_POSITION).min(0x3FFF) as u16
+    }
+
+    fn set_position(&mut self, position: u16) {
+        self.ticks = u32::from(position) * TICKS_PER_POSITION;
+        self.pending.push(song_position(position));
+    }
+
+    /// Follows the tempo of an incoming clock which has just ticked.
+    fn follow(&mut self, now: Instant) {
+        let ticks = self.settings.smoothing.max(1) as usize + 1;
+        if let Some(tick) = average(&mut self.incoming, now, MAX_TICK_GAP, ticks) {
+            self.set_tempo(60.0 / (tick * TICKS_PER_BEAT));
+        }
+    }
+
+    /// Sets the tempo from the time between the latest taps.
+    fn tap(&mut self, now: Instant) {
+        if let Some(beat) = average(&mut self.taps, now, TAP_TIMEOUT, MAX_TAPS) {
+            self.set_tempo(60.0 / beat);
+        }
+    }
+}
+
+impl Transport for Clock {
+    type Message = Event;
+
+    async fn open(&mut self) -> Result<(), device::Error> {
+        // So that anything showing the tempo starts off right
+        self.set_tempo(self.tempo);
+        if self.settings.running {
+            self.start(Instant::now());
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+        if !self.pending.is_empty() {
+            return Ok(Some(mem::take(&mut self.pending)));
+        }
+        let Some(next_tick) = self.next_tick else {
+            // Nothing to do until the clock is started,
+            // which cancels this
+            return future::pending().await;
+        };
+        time::sleep_until(next_tick).await;
+
+        let now = Instant::now();
+        let interval = self.interval();
+        let after = next_tick + interval;
+        // If it's fallen behind, carry on from now rather than catching up
+        self.next_tick = Some(if after > now { after } else { now + interval });
+        self.last_tick = Some(next_tick);
+        self.ticks = self.ticks.saturating_add(1);
+        Ok(Some(vec![Event::from(LiveEvent::Realtime(
+            SystemRealtime::TimingClock,
+        ))]))
+    }
+
+    async fn write(&mut self, event: Event) -> Result<(), device::Error> {
+        let now = Instant::now();
+        match event.live_event() {
+            Some(LiveEvent::Realtime(SystemRealtime::TimingClock)) => self.follow(now),
+            Some(LiveEvent::Realtime(SystemRealtime::Start)) => self.start(now),
+            Some(LiveEvent::Realtime(SystemRealtime::Continue)) => self.resume(now),
+            Some(LiveEvent::Realtime(SystemRealtime::Stop)) => self.stop(),
+            Some(LiveEvent::Common(SystemCommon::SongPosition(position))) => {
+                self.set_position(position.as_int())
+            }
+            Some(LiveEvent::Midi { channel, message }) if channel.as_int() == CHANNEL => {
+                let completed = self.state.update(channel, &message);
+                match (completed.controller_14, message) {
+                    (Some(controller_14), _) if controller_14.controller == TEMPO_CONTROLLER => {
+                        self.set_tempo(f64::from(controller_14.value) / 10.0)
+                    }
+                    (_, MidiMessage::NoteOn { key, vel }) if key == TAP_NOTE && vel > 0 => {
+                        self.tap(now)
+                    }
+                    _ => (),
+                }
+            }
+            _ => log::trace!("Dropping a message which a clock doesn't follow: {event:?}"),
+        }
+        Ok(())
+    }
+}
+
+/// Adds the time of something which happens regularly (e.g. a tap) to the latest ones,
+/// keeping up to `count` of them,
+/// and returns the average time between them in seconds, once there's more than one.
+///
+/// If it's been longer than `timeout` since the last one, it starts afresh.
+fn average(
+    times: &mut VecDeque<Instant>,
+    now: Instant,
+    timeout: Duration,
+    count: usize,
+) -> Option<f64> {
+    if times.back().is_some_and(|last| now - *last > timeout) {
+        times.clear();
+    }
+    times.push_back(now);
+    if times.len() > count {
+        times.pop_front();
+    }
+
+    let first = times.front()?;
+    let average = (now - *first).as_secs_f64() / (times.len() - 1) as f64;
+    (average > 0.0).then_some(average)
+}
+
+fn song_position(position: u16) -> Event {
+    Event::from(LiveEvent::Common(SystemCommon::SongPosition(
+        position.into(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{MessageTemplate, TemplateRef},
+        message::{NumberMatch, Template},
+        profile::{Profile, SearchPath},
+    };
+
+    fn profile() -> Profile {
+        SearchPath(Vec::new()).load(PROFILE).unwrap()
+    }
+
+    /// Looks up a control (with some of its fields) in the built-in profile.
+    fn resolve(control: &str) -> MessageTemplate {
+        let template_ref: TemplateRef = serde_yaml::from_str(control).unwrap();
+        template_ref.resolve("clock", Some(&profile())).unwrap()
+    }
+
+    /// The messages for a control in the built-in profile.
+    fn generate(control: &str) -> Vec<Event> {
+        resolve(control).generate(Default::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn follows_the_tempo_control() {
+        let mut clock = Clock::new(Settings::default());
+        for event in generate("{control: tempo, value: 90 bpm}") {
+            clock.write(event).await.unwrap();
+        }
+        assert_eq!(clock.tempo, 90.0);
+    }
+
+    #[tokio::test]
+    async fn sends_the_tempo_control() {
+        let mut clock = Clock::new(Settings::default());
+        clock.open().await.unwrap();
+        let sent = clock.read().await.unwrap().unwrap();
+
+        let template = resolve("{control: tempo}");
+        let mut state = State::default();
+        let matched = sent
+            .into_iter()
+            .filter_map(|event| template.matches(&mut state, event))
+            .last()
+            .unwrap();
+        assert_eq!(matched["value"].1, NumberMatch::Value(1200));
+    }
+
+    fn realtime(message: SystemRealtime) -> Event {
+        Event::from(LiveEvent::Realtime(message))
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// A clock which has been opened, and has sent its tempo.
+    async fn opened(settings: Settings) -> Clock {
+        let mut clock = Clock::new(settings);
+        clock.open().await.unwrap();
+        let sent = clock.read().await.unwrap().unwrap();
+        assert!(!sent.contains(&realtime(SystemRealtime::TimingClock)));
+        clock
+    }
+
+    /// Reads the next ticks, checking that nothing else is sent in between,
+    /// and returns when each of them was sent after `start`.
+    async fn ticks(clock: &mut Clock, start: Instant, count: usize) -> Vec<Duration> {
+        let mut times = Vec::new();
+        for _ in 0..count {
+            let sent = clock.read().await.unwrap().unwrap();
+            assert_eq!(sent, [realtime(SystemRealtime::TimingClock)]);
+            times.push(start.elapsed());
+        }
+        times
+    }
+
+    async fn tap(clock: &mut Clock) {
+        for event in generate("{control: tap}") {
+            clock.write(event).await.unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_the_tap_control() {
+        let mut clock = Clock::new(Settings::default());
+        for _ in 0..3 {
+            tap(&mut clock).await;
+            time::advance(ms(500)).await;
+        }
+        assert_eq!(clock.tempo, 120.0);
+
+        // Only the latest taps count
+        for _ in 0..MAX_TAPS {
+            tap(&mut clock).await;
+            time::advance(ms(250)).await;
+        }
+        assert_eq!(clock.tempo, 240.0);
+
+        // After a pause, the taps start a new tempo rather than slowing it down
+        time::advance(TAP_TIMEOUT).await;
+        tap(&mut clock).await;
+        time::advance(ms(600)).await;
+        tap(&mut clock).await;
+        assert_eq!(clock.tempo, 100.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_at_the_tempo() {
+        let start = Instant::now();
+        let mut clock = opened(Settings {
+            tempo: 125.0,
+            running: true,
+            ..Settings::default()
+        })
+        .await;
+        let interval = clock.interval();
+        assert!(interval.abs_diff(ms(20)) < Duration::from_micros(1));
+
+        let times = ticks(&mut clock, start, 49).await;
+        let expected: Vec<_> = (0..49).map(|n| interval * n).collect();
+        assert_eq!(times, expected);
+
+        // A change of tempo takes effect from the last tick
+        for event in generate("{control: tempo, value: 250 bpm}") {
+            clock.write(event).await.unwrap();
+        }
+        assert!(!clock.read().await.unwrap().unwrap().is_empty());
+        assert_eq!(
+            ticks(&mut clock, start, 1).await,
+            [interval * 48 + clock.interval()]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_stops_and_continues() {
+        let start = Instant::now();
+        let mut clock = opened(Settings::default()).await;
+        assert!(time::timeout(ms(1000), clock.read()).await.is_err());
+
+        clock.write(realtime(SystemRealtime::Start)).await.unwrap();
+        let started = start.elapsed();
+        assert_eq!(
+            clock.read().await.unwrap().unwrap(),
+            [realtime(SystemRealtime::Start)]
+        );
+        // The first tick is straight away
+        assert_eq!(ticks(&mut clock, start, 15).await[0], started);
+
+        clock.write(realtime(SystemRealtime::Stop)).await.unwrap();
+        assert_eq!(
+            clock.read().await.unwrap().unwrap(),
+            [realtime(SystemRealtime::Stop)]
+        );
+        assert!(time::timeout(ms(1000), clock.read()).await.is_err());
+
+        // Carries on from the last 16th note it reached
+        clock
+            .write(realtime(SystemRealtime::Continue))
+            .await
+            .unwrap();
+        let continued = start.elapsed();
+        assert_eq!(
+            clock.read().await.unwrap().unwrap(),
+            [song_position(2), realtime(SystemRealtime::Continue)]
+        );
+        assert_eq!(ticks(&mut clock, start, 1).await, [continued]);
+
+        // From wherever it's been moved to
+        clock.write(realtime(SystemRealtime::Stop)).await.unwrap();
+        clock.write(song_position(8)).await.unwrap();
+        clock
+            .write(realtime(SystemRealtime::Continue))
+            .await
+            .unwrap();
+        assert_eq!(
+            clock.read().await.unwrap().unwrap(),
+            [
+                realtime(SystemRealtime::Stop),
+                song_position(8),
+                song_position(8),
+                realtime(SystemRealtime::Continue)
+            ]
+        );
+
+        // Starting goes back to the beginning
+        ticks(&mut clock, start, 6).await;
+        clock.write(realtime(SystemRealtime::Start)).await.unwrap();
+        clock.read().await.unwrap().unwrap();
+        assert_eq!(clock.position(), 0);
+    }
+
+    /// Sends ticks of an incoming clock to `clock`, with the given times between them.
+    async fn follow(clock: &mut Clock, intervals: &[u64]) {
+        clock
+            .write(realtime(SystemRealtime::TimingClock))
+            .await
+            .unwrap();
+        for interval in intervals {
+            time::advance(ms(*interval)).await;
+            clock
+                .write(realtime(SystemRealtime::TimingClock))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn follows_an_incoming_clock() {
+        let jittery = [18, 22, 18, 22, 18, 22];
+
+        // Averaged over the last 4 ticks, the jitter cancels out
+        let mut clock = opened(Settings {
+            smoothing: 4,
+            ..Settings::default()
+        })
+        .await;
+        follow(&mut clock, &jittery).await;
+        assert_eq!(clock.sent_tempo, Some(1250));
+
+        // Without smoothing, it follows every tick
+        let mut clock = opened(Settings {
+            smoothing: 1,
+            ..Settings::default()
+        })
+        .await;
+        follow(&mut clock, &jittery).await;
+        // 60 s / (22 ms * 24)
+        assert_eq!(clock.sent_tempo, Some(1136));
+
+        // Once the incoming clock stops, its next ticks start afresh
+        time::advance(MAX_TICK_GAP * 2).await;
+        follow(&mut clock, &[25]).await;
+        assert_eq!(clock.sent_tempo, Some(1000));
+    }
+}
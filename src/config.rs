@@ -1,11 +1,31 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+};
 
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_with::{serde_as, OneOrMany};
+
+use crate::{
+    clock,
+    gesture::Buttons,
+    midi::{mackie::Protocol, playback::Speed, port::PortMatcher},
+    profile::{self, ControlRef, FieldValue, Profile, Table},
+    queue::Overflow,
+    text::Format,
+    throttle::Limits,
+    timer::Timer,
+};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     pub devices: Vec<DeviceInfo>,
     pub mappings: HashMap<String, Vec<Mapping>>,
+
+    /// Extra directories to search for device profiles,
+    /// relative to the config file.
+    #[serde(default)]
+    pub profile_path: Vec<PathBuf>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -13,10 +33,73 @@ pub struct DeviceInfo {
     /// The name of the device. Can be anything.
     pub name: String,
 
+    /// The name of the [`Profile`] describing the device's controls, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+
+    /// The protocol the device speaks on top of MIDI, if any,
+    /// e.g. `{type: Mcu, role: Host}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+
+    /// How many messages can be waiting to go to and from the device,
+    /// and what to do when there are too many.
+    #[serde(default, skip_serializing_if = "Queue::is_default")]
+    pub queue: Queue,
+
+    /// Messages to send to the device as soon as it's connected,
+    /// before anything from the mappings,
+    /// e.g. a handshake or to put it into the right mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_connect: Vec<TemplateRef>,
+
+    /// Messages to send instead of `on_connect` whenever the device connects again,
+    /// e.g. when a native MIDI device is plugged back in.
+    /// If not given, `on_connect` is sent again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_reconnect: Option<Vec<TemplateRef>>,
+
+    /// Messages to send to the device before disconnecting from it,
+    /// e.g. to turn off all its LEDs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub on_exit: Vec<TemplateRef>,
+
     #[serde(flatten)]
     pub connection_info: ConnectionInfo,
 }
 
+/// The queues of messages for a device, e.g. `{size: 16, overflow: coalesce}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(default)]
+pub struct Queue {
+    /// How many messages can be waiting to be sent to the device.
+    pub size: usize,
+
+    /// What to do with a message for the device when there are already `size` waiting.
+    pub overflow: Overflow,
+
+    /// How many messages from the device can be waiting for the slowest mapping,
+    /// before it starts missing them.
+    // @TestMe: is this the right default?
+    pub broadcast: usize,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            size: 4,
+            overflow: Overflow::Block,
+            broadcast: 128,
+        }
+    }
+}
+
+impl Queue {
+    fn is_default(&self) -> bool {
+        *self == Queue::default()
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(untagged)]
 pub enum ConnectionInfo {
@@ -30,11 +113,93 @@ pub enum ConnectionInfo {
 
     /// MIDI connection information.
     Midi {
-        /// The name of the MIDI input device.
-        midi_in: String,
-        /// The name of the MIDI output device.
-        midi_out: String,
+        /// The name of the MIDI input device,
+        /// or a pattern matching it (see [`PortMatcher`]).
+        midi_in: PortMatcher,
+        /// The name of the MIDI output device,
+        /// or a pattern matching it (see [`PortMatcher`]).
+        midi_out: PortMatcher,
+    },
+
+    /// A virtual device, for trying out and testing configs.
+    Loopback {
+        /// Whether messages sent to the device come straight back from it,
+        /// as if the device had sent them.
+        /// Otherwise they're dropped.
+        ///
+        /// Mapping a looped-back device to itself will loop forever!
+        loopback: bool,
+    },
+
+    /// A MIDI file to play, as if it came from a device.
+    Playback {
+        /// The path to the MIDI file, relative to the config file.
+        playback: PathBuf,
+
+        /// The name of the track to play, e.g. the name of a recorded device.
+        /// If not given, every track is played.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        track: Option<String>,
+
+        /// Whether to play the file with its own timing, or as fast as possible.
+        #[serde(default)]
+        speed: Speed,
+
+        /// Whether to start again at the end of the file.
+        #[serde(default, rename = "loop")]
+        looped: bool,
     },
+
+    /// A virtual device which sends a message whenever one of its timers fires,
+    /// e.g. `{timers: {ping: {every: 1000}, fade: {after: 2000}}}`.
+    ///
+    /// The timers are the device's controls, instead of those of any `profile`.
+    Timers { timers: BTreeMap<String, Timer> },
+
+    /// A virtual device which sends MIDI clock, e.g. `{clock: {tempo: 120}}`.
+    ///
+    /// Unless it has a `profile`, its controls are those of the built-in `clock` profile.
+    Clock { clock: clock::Settings },
+}
+
+impl Config {
+    /// Splits each target given in short form (e.g. `fp.left.fader 1`)
+    /// after the longest name of a device which it starts with,
+    /// since device names can have dots in them too.
+    pub fn split_targets(&mut self) -> Result<(), Error> {
+        let names: Vec<&str> = self.devices.iter().map(|d| d.name.as_str()).collect();
+        for target in self.mappings.values_mut().flatten().map(|m| &mut m.target) {
+            let Some(short) = &target.short else {
+                continue;
+            };
+            let split = names
+                .iter()
+                .filter_map(|name| Some((*name, short.strip_prefix(name)?.strip_prefix('.')?)))
+                .max_by_key(|(name, _)| name.len());
+            if let Some((name, control)) = split {
+                let control = control
+                    .parse()
+                    .map_err(|reason| Error::BadTarget(short.clone(), reason))?;
+                target.name = name.to_string();
+                target.message_template = TemplateRef::Control {
+                    control,
+                    text: None,
+                    fields: HashMap::new(),
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes the paths to the devices' files relative to the config file's directory,
+    /// rather than the current directory.
+    pub fn relative_to(&mut self, config_dir: &Path) {
+        for device_info in self.devices.iter_mut() {
+            if let ConnectionInfo::Playback { playback, .. } = &mut device_info.connection_info {
+                *playback = config_dir.join(&playback);
+            }
+        }
+    }
 }
 
 // #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -44,30 +209,317 @@ pub enum ConnectionInfo {
 // }
 pub use crate::midi::MessageTemplate;
 
+/// Either a message template written out in full,
+/// or the name of a control in the device's [`Profile`].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "TemplateRefRepr")]
+#[serde(untagged)]
+pub enum TemplateRef {
+    Control {
+        control: ControlRef,
+
+        /// Replaces the format of the control's text,
+        /// e.g. `text: "Ch {channel+1}"`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<Format>,
+
+        /// Replaces fields of the control's template,
+        /// e.g. to give a range of values,
+        /// which can also be given in the control's units (e.g. `-10 dB`).
+        #[serde(flatten)]
+        #[serde_as(as = "HashMap<_, OneOrMany<_>>")]
+        fields: HashMap<String, Vec<FieldValue>>,
+    },
+    Template(MessageTemplate),
+}
+
+/// Also allows a control to be given as a bare string,
+/// e.g. `from: fader 3`.
+///
+/// Which form it is depends on whether it's a string, or has a `control`,
+/// so that a mistake gives the error from that form,
+/// rather than serde's "did not match any variant".
+enum TemplateRefRepr {
+    Short(ControlRef),
+    Control(ControlRepr),
+    Template(MessageTemplate),
+}
+
+#[serde_as]
+#[derive(Deserialize)]
+struct ControlRepr {
+    control: ControlRef,
+    #[serde(default)]
+    text: Option<Format>,
+    #[serde(flatten)]
+    #[serde_as(as = "HashMap<_, OneOrMany<_>>")]
+    fields: HashMap<String, Vec<FieldValue>>,
+}
+
+impl<'de> Deserialize<'de> for TemplateRefRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        let repr = match &value {
+            serde_yaml::Value::String(s) => s.parse().map(TemplateRefRepr::Short),
+            serde_yaml::Value::Mapping(map) if map.contains_key("control") => {
+                serde_yaml::from_value(value)
+                    .map(TemplateRefRepr::Control)
+                    .map_err(|err| err.to_string())
+            }
+            _ => serde_yaml::from_value(value)
+                .map(TemplateRefRepr::Template)
+                .map_err(|err| err.to_string()),
+        };
+        repr.map_err(de::Error::custom)
+    }
+}
+
+impl From<TemplateRefRepr> for TemplateRef {
+    fn from(repr: TemplateRefRepr) -> Self {
+        match repr {
+            TemplateRefRepr::Short(control) => TemplateRef::Control {
+                control,
+                text: None,
+                fields: HashMap::new(),
+            },
+            TemplateRefRepr::Control(ControlRepr {
+                control,
+                text,
+                fields,
+            }) => TemplateRef::Control {
+                control,
+                text,
+                fields,
+            },
+            TemplateRefRepr::Template(template) => TemplateRef::Template(template),
+        }
+    }
+}
+
+impl TemplateRef {
+    /// Looks up any named control in the given device's profile,
+    /// and returns the template to use.
+    pub fn resolve(
+        &self,
+        device_name: &str,
+        profile: Option<&Profile>,
+    ) -> Result<MessageTemplate, profile::Error> {
+        match (self, profile) {
+            (TemplateRef::Template(template), _) => Ok(template.clone()),
+            (
+                TemplateRef::Control {
+                    control,
+                    text,
+                    fields,
+                },
+                Some(profile),
+            ) => {
+                let mut template = profile.resolve(control, fields)?;
+                if let Some(text) = text {
+                    template.set_text(text.clone()).ok_or_else(|| {
+                        profile::Error::NoSuchField(control.to_string(), "text".to_string())
+                    })?;
+                }
+                Ok(template)
+            }
+            (TemplateRef::Control { control, .. }, None) => Err(profile::Error::NoProfile(
+                device_name.to_string(),
+                control.to_string(),
+            )),
+        }
+    }
+
+    /// The tables used by each field of a named control which has units,
+    /// so that they can be shown in those units by a text template.
+    pub fn units(&self, profile: Option<&Profile>) -> HashMap<String, Table> {
+        match (self, profile) {
+            (TemplateRef::Control { control, .. }, Some(profile)) => {
+                profile.units(control).unwrap_or_default()
+            }
+            _ => HashMap::new(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Mapping {
     #[serde(rename = "from")]
-    pub message_template: MessageTemplate,
+    pub message_template: TemplateRef,
 
     #[serde(rename = "to")]
     pub target: Target,
+
+    /// Which gesture of a button the mapping responds to, e.g. `gesture: hold`.
+    #[serde(flatten)]
+    pub buttons: Buttons,
+
+    /// How often the mapping can send each control, e.g. `rate: 50`.
+    #[serde(flatten)]
+    pub limits: Limits,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "TargetRepr")]
 pub struct Target {
     #[serde(rename = "target")]
     pub name: String,
 
     #[serde(rename = "mapping")]
-    #[serde(default)]
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub field_map: HashMap<String, String>,
 
     #[serde(flatten)]
-    pub message_template: MessageTemplate,
+    pub message_template: TemplateRef,
+
+    /// The target as it was given in short form, e.g. `sq.input[12].level`,
+    /// until it's split after the name of a device (see [`Config::split_targets`]).
+    #[serde(skip)]
+    pub short: Option<String>,
+}
+
+/// Also allows a target to be given as a string
+/// naming a device and one of its controls,
+/// e.g. `to: sq.input[12].level`.
+enum TargetRepr {
+    Short(String),
+    Full(FullTarget),
+}
+
+#[derive(Deserialize)]
+struct FullTarget {
+    #[serde(rename = "target")]
+    name: String,
+
+    #[serde(rename = "mapping")]
+    #[serde(default)]
+    field_map: HashMap<String, String>,
+
+    #[serde(flatten)]
+    message_template: TemplateRef,
+}
+
+impl<'de> Deserialize<'de> for TargetRepr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_yaml::Value::deserialize(deserializer)?;
+        match value {
+            serde_yaml::Value::String(s) => Ok(TargetRepr::Short(s)),
+            value => serde_yaml::from_value(value)
+                .map(TargetRepr::Full)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+impl TryFrom<TargetRepr> for Target {
+    type Error = String;
+
+    fn try_from(repr: TargetRepr) -> Result<Self, Self::Error> {
+        match repr {
+            TargetRepr::Short(s) => {
+                // Split at the first `.` for now,
+                // which is right unless the device's name has one too
+                let (name, control) = s
+                    .split_once('.')
+                    .ok_or_else(|| format!("expected `<device>.<control>`, found `{s}`"))?;
+                Ok(Target {
+                    name: name.trim().to_string(),
+                    field_map: HashMap::new(),
+                    message_template: TemplateRef::Control {
+                        control: control.parse()?,
+                        text: None,
+                        fields: HashMap::new(),
+                    },
+                    short: Some(s),
+                })
+            }
+            TargetRepr::Full(FullTarget {
+                name,
+                field_map,
+                message_template,
+            }) => Ok(Target {
+                name,
+                field_map,
+                message_template,
+                short: None,
+            }),
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Couldn't find device `{0}` (found in mapping)")]
     DeviceNotFound(String),
+
+    #[error("Bad target `{0}`: {1}")]
+    BadTarget(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mappings: &str) -> Result<Config, serde_yaml::Error> {
+        let source = format!(
+            "
+devices:
+  - {{name: fp, loopback: false}}
+  - {{name: fp.left, loopback: false}}
+mappings:
+  fp:
+{mappings}"
+        );
+        serde_yaml::from_str(&source)
+    }
+
+    #[test]
+    fn splits_short_targets_after_device_names() {
+        let mut config = config(
+            "
+    - {from: play, to: fp.left.fader 1}
+    - {from: play, to: \"fp.mute[2]\"}
+",
+        )
+        .unwrap();
+        config.split_targets().unwrap();
+
+        let targets: Vec<(&str, String)> = config.mappings["fp"]
+            .iter()
+            .map(|mapping| match &mapping.target.message_template {
+                TemplateRef::Control { control, .. } => {
+                    (mapping.target.name.as_str(), control.to_string())
+                }
+                TemplateRef::Template(_) => panic!("expected a control"),
+            })
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                ("fp.left", "fader[1]".to_string()),
+                ("fp", "mute[2]".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn explains_bad_templates() {
+        let err = config("    - {from: {type: NoteOnn, note: 1}, to: fp.play}\n").unwrap_err();
+        assert!(
+            err.to_string().contains("unknown variant `NoteOnn`"),
+            "{err}"
+        );
+
+        let err = config("    - {from: play, to: {target: fp, control: 3 fader}}\n").unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't follow a control name"),
+            "{err}"
+        );
+
+        let err = config("    - {from: play, to: fp}\n").unwrap_err();
+        assert!(
+            err.to_string().contains("expected `<device>.<control>`"),
+            "{err}"
+        );
+    }
 }
@@ -1,48 +1,335 @@
-use std::io;
+use std::{fmt, future::Future, io};
 
-use midly::live::LiveEvent;
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
     task::JoinSet,
 };
 
 use crate::{
-    config::{ConnectionInfo, DeviceInfo},
-    midi,
+    clock::Clock,
+    config::{ConnectionInfo, DeviceInfo, Queue},
+    message::Message,
+    midi::{
+        self,
+        device::{NativeMidi, TcpMidi},
+        playback::Playback,
+    },
+    queue::{self, Coalesce},
+    timer::{self, Timers},
 };
 
 impl DeviceInfo {
-    // @Todo @Cleanup: this shouldn't have LiveEvent in its return type
+    /// Connects to the device, which is sent the given [`Lifecycle`] messages,
+    /// once `start` is set (see [`Device::spawn`]).
+    ///
+    /// Any protocol on top of MIDI is left to the caller to [`spawn`](crate::midi::mackie::Protocol::spawn).
     pub fn connect(
         &self,
         join_set: &mut JoinSet<Result<String, Error>>,
-    ) -> Result<Device<LiveEvent<'static>>, Error> {
-        use ConnectionInfo::*;
-        match &self.connection_info {
-            TcpMidi { midi_address } => {
-                Device::tcp_midi(join_set, &self.name, midi_address.to_string())
-            }
-            Midi { midi_in, midi_out } => Device::midi(join_set, &self.name, midi_in, midi_out),
+        lifecycle: Lifecycle<Message>,
+        start: watch::Receiver<bool>,
+    ) -> Result<Device<Message>, Error> {
+        let device = match &self.connection_info {
+            ConnectionInfo::TcpMidi { midi_address } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                TcpMidi::new(midi_address),
+            ),
+            ConnectionInfo::Midi { midi_in, midi_out } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                NativeMidi::new(&self.name, midi_in, midi_out),
+            ),
+            ConnectionInfo::Loopback { loopback } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                Loopback::<Message>::new(*loopback),
+            ),
+            ConnectionInfo::Playback {
+                playback,
+                track,
+                speed,
+                looped,
+            } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                Playback::new(playback, track.as_deref(), *speed, *looped)?,
+            ),
+            ConnectionInfo::Clock { clock } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                Clock::new(*clock),
+            ),
+            ConnectionInfo::Timers { timers } => Device::spawn(
+                join_set,
+                &self.name,
+                &self.queue,
+                lifecycle,
+                start,
+                Timers::new(timers)?,
+            ),
+        };
+        Ok(device)
+    }
+}
+
+/// A way of talking to a device, e.g. MIDI over TCP.
+///
+/// [`Device::spawn`] runs a transport in its own task,
+/// and takes care of the channels to and from the rest of the program.
+pub trait Transport: Send + 'static {
+    type Message: Clone + fmt::Debug + Send + Sync + 'static;
+
+    /// Connects to the device.
+    fn open(&mut self) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Waits for the next messages from the device,
+    /// or returns `None` if the device has disconnected.
+    ///
+    /// This must be cancel safe,
+    /// as it's given up on whenever there's a message to [`write`](Transport::write).
+    fn read(&mut self) -> impl Future<Output = Result<Option<Vec<Self::Message>>, Error>> + Send;
+
+    /// Sends a message to the device.
+    fn write(&mut self, message: Self::Message) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// How many times the transport has connected to the device,
+    /// which [`Device::spawn`] checks to know when to send `on_connect` or `on_reconnect`.
+    ///
+    /// By default, the transport connects once, in [`open`](Transport::open).
+    /// Transports which connect later (e.g. whenever a device is plugged in)
+    /// should return from [`read`](Transport::read) when they do, even with no messages.
+    fn connections(&self) -> usize {
+        1
+    }
+
+    /// Disconnects from the device,
+    /// once there's nothing left to read or write.
+    fn close(&mut self) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Messages to send to a device as it connects and disconnects,
+/// e.g. to put it into the right mode, or to turn off its LEDs.
+#[derive(Debug, Clone)]
+pub struct Lifecycle<M> {
+    /// Sent when the device first connects,
+    /// before anything else sent to it.
+    pub on_connect: Vec<M>,
+
+    /// Sent instead of `on_connect` whenever the device connects again,
+    /// e.g. when a native MIDI device is plugged back in.
+    pub on_reconnect: Vec<M>,
+
+    /// Sent once everything else sent to the device has been sent,
+    /// before disconnecting from it.
+    pub on_exit: Vec<M>,
+}
+
+impl<M> Default for Lifecycle<M> {
+    fn default() -> Self {
+        Lifecycle {
+            on_connect: Vec::new(),
+            on_reconnect: Vec::new(),
+            on_exit: Vec::new(),
         }
     }
 }
 
+/// A connected device,
+/// with channels for sending it messages and receiving the messages it sends.
 pub struct Device<Message> {
     /// The name of the device. Can be anything.
     // @Todo: This could probably be a reference into the originating DeviceInfo
     pub name: String,
 
     /// This is the sender to which we send messages for this device.
-    pub tx: mpsc::Sender<Message>,
+    pub tx: queue::Sender<Message>,
 
-    /// This is a clone of the sender moved to the main device callback.
-    /// Use [`Device::subscribe`] to receive messages from this device.
-    pub broadcast_tx: broadcast::Sender<Message>,
+    /// Only used to [`subscribe`](Device::subscribe) to the messages from this device.
+    ///
+    /// The device's task has the only sender,
+    /// so that the receivers see the channel close once the device has finished.
+    broadcast_rx: broadcast::Receiver<Message>,
 }
 
-impl<Message> Device<Message> {
+impl<Message: Clone> Device<Message> {
+    /// Receives every message sent by the device from now on,
+    /// until the device finishes.
     pub fn subscribe(&self) -> broadcast::Receiver<Message> {
-        self.broadcast_tx.subscribe()
+        self.broadcast_rx.resubscribe()
+    }
+}
+
+impl<M: Coalesce + Clone + fmt::Debug + Send + Sync + 'static> Device<M> {
+    /// Runs a transport in a new task,
+    /// broadcasting every message it reads,
+    /// and writing every message sent to the device.
+    ///
+    /// Messages are converted to and from the transport's own kind of message,
+    /// and messages which it can't send are dropped.
+    ///
+    /// The queues to and from the device are the sizes given by `queue`.
+    ///
+    /// The transport isn't opened until `start` is set,
+    /// so that everything can subscribe to the device before its first message
+    /// (e.g. the first message of a file being played back).
+    ///
+    /// Whenever the transport connects,
+    /// the device is sent `lifecycle.on_connect` (or `on_reconnect`)
+    /// before anything which is waiting to be sent to it.
+    ///
+    /// The task finishes when the device disconnects,
+    /// or when nothing can send to the device any more,
+    /// in which case it's sent `lifecycle.on_exit` before it's closed.
+    pub fn spawn<T>(
+        join_set: &mut JoinSet<Result<String, Error>>,
+        name: &str,
+        queue: &Queue,
+        lifecycle: Lifecycle<M>,
+        mut start: watch::Receiver<bool>,
+        mut transport: T,
+    ) -> Self
+    where
+        T: Transport,
+        M: From<T::Message>,
+        T::Message: TryFrom<M>,
+    {
+        let (broadcast_tx, broadcast_rx) = broadcast::channel(queue.broadcast.max(1));
+        let (tx, mut rx) = queue::channel(queue.size, queue.overflow);
+        let cloned_name = name.to_string();
+
+        join_set.spawn(async move {
+            let name = cloned_name;
+
+            // If the sender has gone, so has whatever would have started the device
+            if start.wait_for(|started| *started).await.is_err() {
+                return Ok(format!("Device {name} finished without starting"));
+            }
+            transport.open().await?;
+
+            let mut connections = 0;
+            let mut disconnected = false;
+            loop {
+                if transport.connections() > connections {
+                    let messages = if connections == 0 {
+                        &lifecycle.on_connect
+                    } else {
+                        &lifecycle.on_reconnect
+                    };
+                    for message in messages {
+                        write(&mut transport, &name, message.clone()).await?;
+                    }
+                    connections = transport.connections();
+                }
+
+                tokio::select! {
+                    messages = transport.read() => {
+                        let Some(messages) = messages? else {
+                            log::info!("Device {name} disconnected");
+                            disconnected = true;
+                            break;
+                        };
+                        for message in messages {
+                            // Ignore the return value;
+                            // error case is when there are no receivers,
+                            // which we don't care about.
+                            let _ = broadcast_tx.send(M::from(message));
+                        }
+                    }
+                    message = rx.recv() => {
+                        let Some(message) = message else { break };
+                        write(&mut transport, &name, message).await?;
+                    }
+                }
+            }
+
+            if !disconnected {
+                for message in lifecycle.on_exit {
+                    write(&mut transport, &name, message).await?;
+                }
+            }
+
+            transport.close().await?;
+            Ok(format!("Device {name} finished"))
+        });
+
+        Device {
+            name: name.to_string(),
+            broadcast_rx,
+            tx,
+        }
+    }
+}
+
+/// Writes a message to a transport,
+/// unless it's not the kind of message the transport can send.
+async fn write<M, T>(transport: &mut T, name: &str, message: M) -> Result<(), Error>
+where
+    M: fmt::Debug,
+    T: Transport,
+    T::Message: TryFrom<M>,
+{
+    log::trace!("Sending a message to {name}: {message:?}");
+    let Ok(message) = T::Message::try_from(message) else {
+        log::warn!("Dropping a message which device {name} can't send");
+        return Ok(());
+    };
+    transport.write(message).await
+}
+
+/// A virtual device,
+/// which sends back every message sent to it if `echo` is set,
+/// and otherwise drops them.
+pub struct Loopback<Message> {
+    echo: bool,
+    tx: mpsc::UnboundedSender<Message>,
+    rx: mpsc::UnboundedReceiver<Message>,
+}
+
+impl<Message> Loopback<Message> {
+    pub fn new(echo: bool) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Loopback { echo, tx, rx }
+    }
+}
+
+impl<Message: Clone + fmt::Debug + Send + Sync + 'static> Transport for Loopback<Message> {
+    type Message = Message;
+
+    async fn open(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<Message>>, Error> {
+        // This holds a sender, so never runs out
+        Ok(self.rx.recv().await.map(|message| vec![message]))
+    }
+
+    async fn write(&mut self, message: Message) -> Result<(), Error> {
+        if self.echo {
+            // This holds the receiver, so can't fail
+            let _ = self.tx.send(message);
+        } else {
+            log::trace!("Dropping a message for a loopback device: {message:?}");
+        }
+        Ok(())
     }
 }
 
@@ -53,4 +340,7 @@ pub enum Error {
 
     #[error("MIDI device error: {0}")]
     Midi(#[from] midi::device::Error),
+
+    #[error("{0}")]
+    Timer(#[from] timer::Error),
 }
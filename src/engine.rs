@@ -0,0 +1,684 @@
+//! Owns the connected devices,
+//! and runs the mappings between them.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        watch,
+    },
+    task::{AbortHandle, JoinError, JoinSet},
+    time::{self, Instant},
+};
+
+use crate::{
+    clock,
+    config::{
+        self, Config, ConnectionInfo, DeviceInfo, Mapping, MessageTemplate, Target, TemplateRef,
+    },
+    device::{self, Device, Lifecycle},
+    gesture::Gestures,
+    message::{Message, Template, Transform, Transformer},
+    monitor::{Direction, Monitor},
+    profile::{self, Profile},
+    queue,
+    throttle::{Limits, Throttle},
+    timer,
+};
+
+/// The connected devices, and the tasks running them and the mappings between them.
+///
+/// ```no_run
+/// # async fn example() -> Result<(), gobetween::engine::Error> {
+/// use std::time::Duration;
+///
+/// use gobetween::Engine;
+///
+/// let mut engine = Engine::start("config.yaml".as_ref(), None)?;
+/// let mut from_fp = engine.subscribe("fp")?;
+/// while let Ok(event) = from_fp.recv().await {
+///     println!("fp sent {event}");
+/// }
+/// engine.stop(Duration::from_secs(2)).await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Engine {
+    devices: HashMap<String, Device<Message>>,
+
+    /// The names of the devices, in the order they were connected.
+    order: Vec<String>,
+
+    tasks: JoinSet<Result<String, device::Error>>,
+
+    /// The tasks running the protocols,
+    /// which are stopped before the devices.
+    helpers: Vec<AbortHandle>,
+
+    /// Prints each message sent by a mapping.
+    monitor: Option<Monitor>,
+
+    /// Set once the devices can start (see [`start_devices`](Engine::start_devices)).
+    started: watch::Sender<bool>,
+
+    /// Set once the mappings should [`stop`](Engine::stop).
+    stopping: watch::Sender<bool>,
+}
+
+/// A mapping from the config file,
+/// with its templates looked up in the devices' profiles.
+pub struct Route {
+    /// The name of the device to map from.
+    pub from: String,
+
+    /// The name of the device to map to.
+    pub to: String,
+
+    /// Identifies the mapping, in the same way as in YAML errors,
+    /// e.g. `mappings.fp[0]`.
+    pub label: String,
+
+    /// Usually a [`Transformer`] (or [`Gestures`] for buttons),
+    /// which can be between any kinds of [`Template`](crate::Template).
+    pub transformer: Box<dyn Transform>,
+
+    /// How often the mapping can send each control.
+    pub limits: Limits,
+}
+
+impl Engine {
+    /// An engine with no devices.
+    ///
+    /// If a monitor is given, it's used to print every message sent by a mapping.
+    pub fn new(monitor: Option<Monitor>) -> Self {
+        Engine {
+            devices: HashMap::new(),
+            order: Vec::new(),
+            tasks: JoinSet::new(),
+            helpers: Vec::new(),
+            monitor,
+            started: watch::channel(false).0,
+            stopping: watch::channel(false).0,
+        }
+    }
+
+    /// Loads a config file,
+    /// connects to its devices, and starts running its mappings.
+    pub fn start(config_path: &Path, monitor: Option<Monitor>) -> Result<Self, Error> {
+        let config = load_config(config_path)?;
+        let profiles = load_profiles(config_path, &config)?;
+
+        let mut engine = Engine::new(monitor);
+        engine.connect_all(&config.devices, &profiles)?;
+        for route in resolve_mappings(config.mappings, &profiles)? {
+            engine.add_route(route)?;
+        }
+        engine.start_devices();
+        Ok(engine)
+    }
+
+    /// Connects to a device,
+    /// and starts running its protocol, if any.
+    ///
+    /// Each device must have a different name.
+    ///
+    /// The device doesn't start until [`start_devices`](Engine::start_devices) is called
+    /// (or straight away if it already has been).
+    ///
+    /// The profile is used to look up any controls in the device's messages (e.g. `on_connect`).
+    pub fn connect(
+        &mut self,
+        device_info: &DeviceInfo,
+        profile: Option<&Profile>,
+    ) -> Result<(), Error> {
+        log::info!("Connecting to device: {device_info:?}");
+        let name = &device_info.name;
+        if self.devices.contains_key(name) {
+            return Err(Error::DuplicateDevice(name.clone()));
+        }
+        let on_connect = generate_messages(name, &device_info.on_connect, profile)?;
+        let mut lifecycle = Lifecycle {
+            on_reconnect: match &device_info.on_reconnect {
+                Some(on_reconnect) => generate_messages(name, on_reconnect, profile)?,
+                None => on_connect.clone(),
+            },
+            on_connect,
+            on_exit: generate_messages(name, &device_info.on_exit, profile)?,
+        };
+        if let Some(protocol) = &device_info.protocol {
+            // The other end has forgotten us if it's been unplugged
+            let handshake = protocol.handshake();
+            lifecycle.on_connect.extend(handshake.iter().cloned());
+            lifecycle.on_reconnect.extend(handshake);
+        }
+        let device = device_info.connect(&mut self.tasks, lifecycle, self.started.subscribe())?;
+        if let Some(protocol) = &device_info.protocol {
+            self.helpers.push(protocol.spawn(&device, &mut self.tasks));
+        }
+        self.order.push(device_info.name.clone());
+        self.devices.insert(device_info.name.clone(), device);
+        Ok(())
+    }
+
+    /// Connects to each of the devices, with their profiles keyed by device name.
+    pub fn connect_all(
+        &mut self,
+        device_infos: &[DeviceInfo],
+        profiles: &HashMap<String, Profile>,
+    ) -> Result<(), Error> {
+        for device_info in device_infos {
+            self.connect(device_info, profiles.get(&device_info.name))?;
+        }
+        Ok(())
+    }
+
+    /// Lets the devices connect and start sending messages,
+    /// once the mappings (and anything else) have subscribed to them,
+    /// so that nothing misses their first messages.
+    pub fn start_devices(&self) {
+        self.started.send_replace(true);
+    }
+
+    /// Starts running a mapping between two connected devices.
+    ///
+    /// If the mapping falls behind the messages from the device it maps from,
+    /// it skips the ones it's missed.
+    /// Messages which would go over the mapping's limits are held back (see [`Throttle`]).
+    /// It finishes when either device has stopped.
+    pub fn add_route(&mut self, route: Route) -> Result<(), Error> {
+        let from_rx = self.subscribe(&route.from)?;
+        let to_tx = self.sender(&route.to)?;
+        self.tasks
+            .spawn(route.run(from_rx, to_tx, self.monitor, self.stopping.subscribe()));
+        Ok(())
+    }
+
+    /// The device with the given name.
+    pub fn device(&self, name: &str) -> Result<&Device<Message>, Error> {
+        self.devices
+            .get(name)
+            .ok_or_else(|| config::Error::DeviceNotFound(name.to_string()).into())
+    }
+
+    /// The devices, in the order they were connected.
+    pub fn devices(&self) -> impl Iterator<Item = &Device<Message>> {
+        self.order.iter().map(|name| &self.devices[name])
+    }
+
+    /// Receives every message sent by the named device from now on.
+    pub fn subscribe(&self, name: &str) -> Result<broadcast::Receiver<Message>, Error> {
+        Ok(self.device(name)?.subscribe())
+    }
+
+    /// A handle for sending messages to the named device.
+    pub fn sender(&self, name: &str) -> Result<queue::Sender<Message>, Error> {
+        Ok(self.device(name)?.tx.clone())
+    }
+
+    /// Sends a message to the named device.
+    pub async fn send(&self, name: &str, message: Message) -> Result<(), Error> {
+        self.device(name)?
+            .tx
+            .send(message)
+            .await
+            .map_err(|_| Error::Disconnected(name.to_string()))
+    }
+
+    /// Waits for one of the devices or mappings to finish,
+    /// and returns its message,
+    /// or `None` if they've all finished.
+    pub async fn join_next(&mut self) -> Option<Result<String, Error>> {
+        self.tasks
+            .join_next()
+            .await
+            .map(|join_result| match join_result {
+                Ok(task_result) => task_result.map_err(Error::from),
+                Err(join_err) => Err(join_err.into()),
+            })
+    }
+
+    /// Stops all the devices and mappings straight away.
+    pub async fn shutdown(mut self) {
+        self.tasks.shutdown().await;
+    }
+
+    /// Stops the mappings (which first send anything they're holding back),
+    /// then lets each device finish sending what's waiting for it and its `on_exit` messages,
+    /// and disconnects from it.
+    ///
+    /// Anything still running after `timeout` is stopped straight away.
+    /// Returns how each device (and anything else still running) finished,
+    /// as [`join_next`](Engine::join_next) does.
+    pub async fn stop(mut self, timeout: Duration) -> Vec<Result<String, Error>> {
+        // Devices which haven't started still send their `on_connect` and `on_exit` messages
+        self.start_devices();
+        self.stopping.send_replace(true);
+        for helper in self.helpers.drain(..) {
+            helper.abort();
+        }
+        // The devices finish once nothing else can send to them
+        self.devices.clear();
+
+        let deadline = Instant::now() + timeout;
+        let mut results = Vec::new();
+        loop {
+            match time::timeout_at(deadline, self.join_next()).await {
+                Ok(Some(Err(Error::Join(err)))) if err.is_cancelled() => {}
+                Ok(Some(result)) => results.push(result),
+                Ok(None) => break,
+                Err(_) => {
+                    results.push(Err(Error::Timeout(self.tasks.len())));
+                    self.tasks.shutdown().await;
+                    break;
+                }
+            }
+        }
+        results
+    }
+}
+
+impl Route {
+    /// Runs the mapping, until either device has stopped or `stopping` is set.
+    async fn run(
+        self,
+        mut from_rx: broadcast::Receiver<Message>,
+        to_tx: queue::Sender<Message>,
+        monitor: Option<Monitor>,
+        mut stopping: watch::Receiver<bool>,
+    ) -> Result<String, device::Error> {
+        let Route {
+            from,
+            to,
+            label,
+            mut transformer,
+            limits,
+        } = self;
+        let mut throttle = Throttle::new(limits);
+        let mut finished = None;
+        loop {
+            let due = [throttle.next_due(), transformer.next_due()]
+                .into_iter()
+                .flatten()
+                .min();
+            let new_msgs = tokio::select! {
+                msg = from_rx.recv() => match msg {
+                    Ok(msg) => {
+                        let now = Instant::now();
+                        throttle.offer(transformer.transform_message(msg, now), now)
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!(
+                            "Mapping {label} fell behind, and missed {missed} messages from {from}"
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => {
+                        finished = Some(format!("Mapping {label} finished, because {from} has stopped"));
+                        flush(transformer.as_mut(), &mut throttle)
+                    }
+                },
+
+                _ = time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                    let now = Instant::now();
+                    let mut new_msgs = throttle.take_due(now);
+                    new_msgs.extend(throttle.offer(transformer.tick(now), now));
+                    new_msgs
+                }
+
+                // Only ever set, so the engine has gone if this fails
+                _ = stopping.changed() => {
+                    finished = Some(format!("Mapping {label} stopped"));
+                    flush(transformer.as_mut(), &mut throttle)
+                }
+            };
+
+            for new_msg in new_msgs {
+                if let Some(monitor) = monitor {
+                    monitor.print(&to, Direction::Out, &new_msg, Some(&label));
+                }
+                if to_tx.send(new_msg).await.is_err() {
+                    return Ok(format!(
+                        "Mapping {label} finished, because {to} has stopped"
+                    ));
+                }
+            }
+            if let Some(finished) = finished {
+                return Ok(finished);
+            }
+        }
+    }
+}
+
+/// Everything a mapping has waiting to be sent, for when it finishes:
+/// whatever its transformer would send later by itself
+/// (e.g. a tap which is waiting to see if there's a double press),
+/// and the last values which were held back.
+fn flush(transformer: &mut dyn Transform, throttle: &mut Throttle) -> Vec<Message> {
+    let now = Instant::now();
+    let mut messages = Vec::new();
+    while let Some(due) = transformer.next_due() {
+        messages.extend(throttle.offer(transformer.tick(due), now));
+    }
+    messages.extend(throttle.take_all(now));
+    messages
+}
+
+/// Reads and parses a config file.
+///
+/// Paths in the config file are made relative to it.
+pub fn load_config(config_path: &Path) -> Result<Config, Error> {
+    let config_file = File::open(config_path).map_err(|err| Error::IO(config_path.into(), err))?;
+    let mut config: Config = serde_yaml::from_reader(config_file)?;
+    config.relative_to(config_path.parent().unwrap_or(Path::new(".")));
+    config.split_targets()?;
+
+    log::trace!("Parsed config: {config:?}");
+
+    Ok(config)
+}
+
+/// Loads the profiles for any devices which have them, keyed by device name,
+/// including the profiles naming the controls of any timers and clock devices.
+pub fn load_profiles(
+    config_path: &Path,
+    config: &Config,
+) -> Result<HashMap<String, Profile>, Error> {
+    let config_dir = config_path.parent().unwrap_or(Path::new("."));
+    let search_path = profile::SearchPath::new(config_dir, &config.profile_path);
+    let mut profiles = HashMap::new();
+    for device_info in config.devices.iter() {
+        let profile = match (&device_info.connection_info, &device_info.profile) {
+            (ConnectionInfo::Timers { timers }, _) => timer::profile(timers)?,
+            (_, Some(profile_name)) => search_path.load(profile_name)?,
+            (ConnectionInfo::Clock { .. }, None) => search_path.load(clock::PROFILE)?,
+            (_, None) => continue,
+        };
+        profiles.insert(device_info.name.clone(), profile);
+    }
+    Ok(profiles)
+}
+
+/// Generates the messages given by some templates for a device, e.g. its `on_connect`,
+/// each of which must give a single value for every field.
+pub fn generate_messages(
+    device_name: &str,
+    templates: &[TemplateRef],
+    profile: Option<&Profile>,
+) -> Result<Vec<Message>, Error> {
+    let mut messages = Vec::new();
+    for template in templates {
+        let template = template.resolve(device_name, profile)?;
+        let events = template
+            .generate(Default::default())
+            .ok_or_else(|| Error::Incomplete(device_name.to_string(), template))?;
+        messages.extend(events.into_iter().map(Message::from));
+    }
+    Ok(messages)
+}
+
+/// Looks up the controls in each mapping,
+/// and sets up a [`Transformer`] for it.
+///
+/// The routes from each device are in the same order as its mappings.
+pub fn resolve_mappings(
+    mappings: HashMap<String, Vec<Mapping>>,
+    profiles: &HashMap<String, Profile>,
+) -> Result<Vec<Route>, Error> {
+    let mut routes = Vec::new();
+    for (from_name, mappings) in mappings {
+        for (
+            i,
+            Mapping {
+                message_template: from_template,
+                target:
+                    Target {
+                        name: to_name,
+                        message_template: to_template,
+                        field_map,
+                        ..
+                    },
+                buttons,
+                limits,
+            },
+        ) in mappings.into_iter().enumerate()
+        {
+            // Lets output text show the input's fields in their units, e.g. `{value_db}`
+            let units = from_template
+                .units(profiles.get(&from_name))
+                .into_iter()
+                .map(|(field, table)| (field_map.get(&field).cloned().unwrap_or(field), table))
+                .collect();
+            let from_template = from_template.resolve(&from_name, profiles.get(&from_name))?;
+            let mut to_template = to_template.resolve(&to_name, profiles.get(&to_name))?;
+            to_template.set_units(&units);
+            log::debug!(
+                "Mapping from {from_name}: {from_template:?} to {to_name}: {to_template:?}"
+            );
+
+            let transformer = Transformer::new(from_template, to_template, field_map);
+            routes.push(Route {
+                label: format!("mappings.{from_name}[{i}]"),
+                from: from_name.clone(),
+                to: to_name,
+                transformer: if buttons.is_default() {
+                    Box::new(transformer)
+                } else {
+                    Box::new(Gestures::new(transformer, buttons))
+                },
+                limits,
+            });
+        }
+    }
+    Ok(routes)
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Couldn't open config file `{0}`: {1}")]
+    IO(PathBuf, io::Error),
+
+    #[error("Couldn't parse config file: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("{0}")]
+    Config(#[from] config::Error),
+
+    #[error("{0}")]
+    Profile(#[from] profile::Error),
+
+    #[error("{0}")]
+    Device(#[from] device::Error),
+
+    #[error("{0}")]
+    Timer(#[from] timer::Error),
+
+    #[error("There's more than one device called `{0}`")]
+    DuplicateDevice(String),
+
+    #[error("Couldn't send to device `{0}`, because it has stopped")]
+    Disconnected(String),
+
+    #[error("Task panicked or was cancelled: {0}")]
+    Join(#[from] JoinError),
+
+    #[error("Message for device `{0}` doesn't give one value for every field: {1:?}")]
+    Incomplete(String, MessageTemplate),
+
+    #[error("Gave up waiting for {0} devices or mappings to finish")]
+    Timeout(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use midly::{live::LiveEvent, num::u4, MidiMessage};
+
+    use super::*;
+    use crate::{midi::Event, record::Recording};
+
+    fn loopback(name: &str) -> DeviceInfo {
+        serde_yaml::from_str(&format!("{{name: {name}, loopback: false}}")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_devices_with_the_same_name() {
+        let mut engine = Engine::new(None);
+        engine.connect(&loopback("fp"), None).unwrap();
+        assert!(matches!(
+            engine.connect(&loopback("fp"), None),
+            Err(Error::DuplicateDevice(name)) if name == "fp"
+        ));
+        assert_eq!(engine.devices().count(), 1);
+
+        engine.start_devices();
+        assert_eq!(engine.stop(Duration::from_secs(1)).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn finishes_mappings_when_their_device_does() {
+        let dir = std::env::temp_dir().join(format!("gobetween-engine-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let event = Event::from(LiveEvent::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::Controller {
+                controller: 7.into(),
+                value: 100.into(),
+            },
+        });
+        Recording {
+            tracks: vec![("fp".to_string(), vec![(Duration::ZERO, event)])],
+        }
+        .save(&dir.join("fp.mid"))
+        .unwrap();
+        let config_path = dir.join("config.yaml");
+        fs::write(
+            &config_path,
+            "
+devices:
+  - {name: fp, playback: fp.mid, speed: fast}
+  - {name: out, loopback: false}
+mappings:
+  fp:
+    - from: {type: ControlChange}
+      to: {target: out, type: ControlChange}
+",
+        )
+        .unwrap();
+
+        let mut engine = Engine::start(&config_path, None).unwrap();
+        let mut finished = Vec::new();
+        for _ in 0..2 {
+            let result = time::timeout(Duration::from_secs(5), engine.join_next()).await;
+            finished.push(result.unwrap().unwrap().unwrap());
+        }
+        finished.sort();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            finished,
+            [
+                "Device fp finished",
+                "Mapping mappings.fp[0] finished, because fp has stopped"
+            ]
+        );
+        engine.stop(Duration::from_secs(1)).await;
+    }
+
+    fn control_change(value: u8) -> Message {
+        Message::Midi(Event::from(LiveEvent::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::Controller {
+                controller: 7.into(),
+                value: value.into(),
+            },
+        }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_held_back_values_when_stopped() {
+        let template: MessageTemplate = serde_yaml::from_str("{type: ControlChange}").unwrap();
+        let route = Route {
+            from: "in".to_string(),
+            to: "out".to_string(),
+            label: "mappings.in[0]".to_string(),
+            transformer: Box::new(Transformer::new(template.clone(), template, HashMap::new())),
+            limits: Limits {
+                rate: Some(1.0),
+                deadband: None,
+            },
+        };
+        let (from_tx, from_rx) = broadcast::channel(8);
+        let (to_tx, mut to_rx) = queue::channel(8, queue::Overflow::Block);
+        let (stopping_tx, stopping_rx) = watch::channel(false);
+        let task = tokio::spawn(route.run(from_rx, to_tx, None, stopping_rx));
+
+        for value in 1..=3 {
+            from_tx.send(control_change(value)).unwrap();
+        }
+        assert_eq!(to_rx.recv().await, Some(control_change(1)));
+        // The paused clock only moves on once the mapping has nothing left to do,
+        // i.e. it has held back the others until a second has passed
+        time::sleep(Duration::from_millis(10)).await;
+
+        stopping_tx.send_replace(true);
+        let finished = time::timeout(Duration::from_secs(1), task).await;
+        assert_eq!(
+            finished.unwrap().unwrap().unwrap(),
+            "Mapping mappings.in[0] stopped"
+        );
+        assert_eq!(to_rx.recv().await, Some(control_change(3)));
+        assert_eq!(to_rx.recv().await, None);
+    }
+
+    #[test]
+    fn flushes_taps_waiting_for_a_double_press() {
+        let template: MessageTemplate =
+            serde_yaml::from_str("{type: ControlChange, value: 1-127}").unwrap();
+        let buttons = serde_yaml::from_str("{gesture: tap, double_time: 300}").unwrap();
+        let mut transformer = Gestures::new(
+            Transformer::new(template.clone(), template, HashMap::new()),
+            buttons,
+        );
+        let mut throttle = Throttle::new(Limits::default());
+
+        let now = Instant::now();
+        assert!(transformer
+            .transform_message(control_change(100), now)
+            .is_empty());
+        assert!(transformer
+            .transform_message(control_change(0), now)
+            .is_empty());
+        assert_eq!(
+            flush(&mut transformer, &mut throttle),
+            [control_change(100)]
+        );
+        assert_eq!(transformer.next_due(), None);
+    }
+
+    #[tokio::test]
+    async fn starts_handshakes_when_devices_connect() {
+        let device_info: DeviceInfo =
+            serde_yaml::from_str("{name: mcu, loopback: true, protocol: {type: Mcu, role: Host}}")
+                .unwrap();
+        let mut engine = Engine::new(None);
+        engine.connect(&device_info, None).unwrap();
+        let mut rx = engine.subscribe("mcu").unwrap();
+        engine.start_devices();
+
+        // The loopback device sends back what it's sent
+        let sent = time::timeout(Duration::from_secs(1), rx.recv()).await;
+        assert_eq!(
+            sent.unwrap().unwrap(),
+            Message::from(Event::sysex(&[0x00, 0x00, 0x66, 0x14, 0x00]).unwrap())
+        );
+        engine.stop(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,499 @@
+//! Turns the presses and releases of buttons into gestures,
+//! such as holding a button down or pressing it twice,
+//! so that one button can do different things.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use tokio::time::Instant;
+
+use crate::{
+    message::{Match, Message, Template, Transform, Transformer},
+    midi::Event,
+};
+
+/// How long a button has to be held down for [`Gesture::Hold`],
+/// unless the mapping gives a `hold_time`.
+// @Checkme: does this feel right on a real surface?
+const DEFAULT_HOLD_TIME: Duration = Duration::from_millis(500);
+
+/// How soon a second press has to come for [`Gesture::Double`],
+/// unless the mapping gives a `double_time`.
+const DEFAULT_DOUBLE_TIME: Duration = Duration::from_millis(300);
+
+/// Something a button can do, which a mapping can respond to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Gesture {
+    /// As soon as the button is pressed.
+    Press,
+
+    /// When the button is released.
+    Release,
+
+    /// When the button is released before `hold_time`,
+    /// and, if the mapping gives a `double_time`,
+    /// isn't pressed again within that time of the first press.
+    Tap,
+
+    /// Once the button has been held down for `hold_time`.
+    Hold,
+
+    /// When the button is pressed again within `double_time` of the first press.
+    Double,
+}
+
+/// Which gesture of a button a mapping responds to, and how it's timed,
+/// e.g. `{gesture: hold, hold_time: 800}`.
+///
+/// Times are in milliseconds.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    /// If not given, both presses and releases are mapped,
+    /// which is only useful with `debounce`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gesture: Option<Gesture>,
+
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_time: Option<Duration>,
+
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub double_time: Option<Duration>,
+
+    /// How long to ignore a button for after it's pressed or released,
+    /// in case its contacts bounce.
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debounce: Option<Duration>,
+}
+
+impl Buttons {
+    /// Whether the mapping responds to every message, rather than to buttons.
+    pub fn is_default(&self) -> bool {
+        *self == Buttons::default()
+    }
+
+    fn hold_time(&self) -> Duration {
+        self.hold_time.unwrap_or(DEFAULT_HOLD_TIME)
+    }
+
+    fn double_time(&self) -> Duration {
+        self.double_time.unwrap_or(DEFAULT_DOUBLE_TIME)
+    }
+}
+
+/// Messages which can press and release buttons.
+pub trait Button {
+    /// Which button the message presses or releases, if any,
+    /// and whether it's pressed.
+    fn button(&self) -> Option<(Vec<u8>, bool)>;
+}
+
+impl Button for Event {
+    fn button(&self) -> Option<(Vec<u8>, bool)> {
+        Event::button(self).map(|(key, pressed)| (key.to_vec(), pressed))
+    }
+}
+
+/// A [`Transformer`] which only sends messages for one gesture of each button.
+///
+/// Only buttons which the input template matches when they're pressed are followed,
+/// and their releases are followed whether or not they match.
+/// The output is generated from the press,
+/// or from the release for releases which match.
+pub struct Gestures<Fr: Template, To> {
+    transformer: Transformer<Fr, To>,
+    options: Buttons,
+    buttons: HashMap<Vec<u8>, ButtonState>,
+}
+
+#[derive(Default)]
+struct ButtonState {
+    /// Whether the button is down.
+    down: bool,
+
+    /// When the button last went down.
+    pressed_at: Option<Instant>,
+
+    /// What the input template matched when the button last went down.
+    matched: Option<Match>,
+
+    /// Until when changes are ignored, while the button might be bouncing.
+    settles_at: Option<Instant>,
+
+    /// The latest change while the button was bouncing (and its match),
+    /// which is made once it's settled, if it's still different.
+    bounced: Option<(bool, Option<Match>)>,
+
+    /// Whether this press has already been a hold or a double press,
+    /// so isn't anything else.
+    used: bool,
+
+    /// When to send a tap, if it's waiting to see whether there's a double press.
+    tap_at: Option<Instant>,
+}
+
+impl<Fr, To> Gestures<Fr, To>
+where
+    Fr: Template,
+    Fr::Message: Button + Clone,
+    To: Template,
+{
+    pub fn new(transformer: Transformer<Fr, To>, options: Buttons) -> Self {
+        Gestures {
+            transformer,
+            options,
+            buttons: HashMap::new(),
+        }
+    }
+
+    /// Follows a message from the device,
+    /// and returns the matches to send messages for.
+    fn follow(&mut self, msg: Fr::Message, now: Instant) -> Vec<Match> {
+        let button = msg.button();
+        // Every message goes to the input template, to keep its state up to date
+        let matched = self.transformer.matches(msg);
+        let Some((key, down)) = button else {
+            return Vec::new();
+        };
+        if down && matched.is_none() {
+            return Vec::new();
+        }
+
+        let state = match self.buttons.entry(key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(_) if !down => return Vec::new(),
+            Entry::Vacant(entry) => entry.insert(ButtonState::default()),
+        };
+
+        if state.settles_at.is_some_and(|settles_at| now < settles_at) {
+            state.bounced = Some((down, matched));
+            return Vec::new();
+        }
+        state.bounced = None;
+        state
+            .change(down, matched, now, &self.options)
+            .into_iter()
+            .collect()
+    }
+
+    fn generate(&self, matches: Vec<Match>) -> Vec<Message>
+    where
+        To::Message: Into<Message>,
+    {
+        matches
+            .into_iter()
+            .filter_map(|mat| self.transformer.generate(mat))
+            .flatten()
+            .map(Into::into)
+            .collect()
+    }
+}
+
+impl ButtonState {
+    /// Presses or releases the button,
+    /// and returns the match to send messages for, if that's a gesture.
+    fn change(
+        &mut self,
+        down: bool,
+        matched: Option<Match>,
+        now: Instant,
+        options: &Buttons,
+    ) -> Option<Match> {
+        if down == self.down {
+            return None;
+        }
+        self.down = down;
+        if let Some(debounce) = options.debounce {
+            self.settles_at = Some(now + debounce);
+        }
+
+        if down {
+            let previous = self.pressed_at.replace(now);
+            let doubled = !self.used
+                && previous.is_some_and(|previous| now - previous <= options.double_time());
+            self.used = false;
+            self.matched = matched.clone();
+
+            match options.gesture {
+                None | Some(Gesture::Press) => matched,
+                Some(Gesture::Double) if doubled => {
+                    self.used = true;
+                    matched
+                }
+                Some(Gesture::Tap) if self.tap_at.is_some() => {
+                    // It's a double press, so neither press is a tap
+                    self.tap_at = None;
+                    self.used = true;
+                    None
+                }
+                _ => None,
+            }
+        } else {
+            let pressed_at = self.pressed_at?;
+            match options.gesture {
+                None => matched,
+                Some(Gesture::Release) => matched.or_else(|| self.matched.clone()),
+                Some(Gesture::Tap) if !self.used && now - pressed_at < options.hold_time() => {
+                    match options.double_time {
+                        Some(double_time) => {
+                            self.tap_at = Some((pressed_at + double_time).max(now));
+                            None
+                        }
+                        None => self.matched.clone(),
+                    }
+                }
+                _ => None,
+            }
+        }
+    }
+
+    /// When the button next needs [`tick`](ButtonState::tick), if ever.
+    fn next_due(&self, options: &Buttons) -> Option<Instant> {
+        let settled = self.bounced.as_ref().and(self.settles_at);
+        let held = match (options.gesture, self.pressed_at) {
+            (Some(Gesture::Hold), Some(pressed_at)) if self.down && !self.used => {
+                Some(pressed_at + options.hold_time())
+            }
+            _ => None,
+        };
+        [settled, held, self.tap_at].into_iter().flatten().min()
+    }
+
+    /// Returns the matches to send messages for which are due by `now`.
+    fn tick(&mut self, now: Instant, options: &Buttons) -> Vec<Match> {
+        let mut matches = Vec::new();
+
+        if self.settles_at.is_some_and(|settles_at| now >= settles_at) {
+            if let Some((down, matched)) = self.bounced.take() {
+                matches.extend(self.change(down, matched, now, options));
+            }
+        }
+
+        if let (Some(Gesture::Hold), Some(pressed_at)) = (options.gesture, self.pressed_at) {
+            if self.down && !self.used && now >= pressed_at + options.hold_time() {
+                self.used = true;
+                matches.extend(self.matched.clone());
+            }
+        }
+
+        if self.tap_at.is_some_and(|tap_at| now >= tap_at) {
+            self.tap_at = None;
+            matches.extend(self.matched.clone());
+        }
+
+        matches
+    }
+}
+
+impl<Fr, To> Transform for Gestures<Fr, To>
+where
+    Fr: Template + Send,
+    Fr::State: Send,
+    Fr::Message: TryFrom<Message> + Button + Clone,
+    To: Template + Send,
+    To::Message: Into<Message>,
+{
+    fn transform_message(&mut self, msg: Message, now: Instant) -> Vec<Message> {
+        let Ok(msg) = Fr::Message::try_from(msg) else {
+            return Vec::new();
+        };
+        let matches = self.follow(msg, now);
+        self.generate(matches)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        self.buttons
+            .values()
+            .filter_map(|state| state.next_due(&self.options))
+            .min()
+    }
+
+    fn tick(&mut self, now: Instant) -> Vec<Message> {
+        let matches = self
+            .buttons
+            .values_mut()
+            .flat_map(|state| state.tick(now, &self.options))
+            .collect();
+        self.generate(matches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{live::LiveEvent, MidiMessage};
+
+    use super::*;
+    use crate::message::NumberMatch;
+
+    fn matched() -> Option<Match> {
+        Some(Match::from([(
+            "velocity".to_string(),
+            (0, NumberMatch::Value(127)),
+        )]))
+    }
+
+    fn buttons(yaml: &str) -> Buttons {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// A button which was pressed at `start`.
+    fn pressed(start: Instant, options: &Buttons) -> (ButtonState, Option<Match>) {
+        let mut state = ButtonState::default();
+        let press = state.change(true, matched(), start, options);
+        (state, press)
+    }
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn presses_and_releases() {
+        let start = Instant::now();
+        let options = buttons("{gesture: press}");
+        let (mut state, press) = pressed(start, &options);
+        assert_eq!(press, matched());
+        assert_eq!(state.change(true, matched(), start + ms(1), &options), None);
+        assert_eq!(state.change(false, None, start + ms(2), &options), None);
+
+        let options = buttons("{gesture: release}");
+        let (mut state, press) = pressed(start, &options);
+        assert_eq!(press, None);
+        // A release which doesn't match is sent with what the press matched
+        assert_eq!(
+            state.change(false, None, start + ms(1), &options),
+            matched()
+        );
+    }
+
+    #[test]
+    fn taps_before_the_hold_time() {
+        let start = Instant::now();
+        let options = buttons("{gesture: tap, hold_time: 100}");
+        let (mut state, press) = pressed(start, &options);
+        assert_eq!(press, None);
+        assert_eq!(
+            state.change(false, None, start + ms(99), &options),
+            matched()
+        );
+
+        let (mut state, _) = pressed(start, &options);
+        assert_eq!(state.change(false, None, start + ms(100), &options), None);
+    }
+
+    #[test]
+    fn holds_once_the_hold_time_has_passed() {
+        let start = Instant::now();
+        let options = buttons("{gesture: hold, hold_time: 100}");
+        let (mut state, press) = pressed(start, &options);
+        assert_eq!(press, None);
+        assert_eq!(state.next_due(&options), Some(start + ms(100)));
+        assert!(state.tick(start + ms(99), &options).is_empty());
+        assert_eq!(state.tick(start + ms(100), &options), [matched().unwrap()]);
+        assert_eq!(state.next_due(&options), None);
+        assert_eq!(state.change(false, None, start + ms(150), &options), None);
+
+        // Released too soon
+        let (mut state, _) = pressed(start, &options);
+        assert_eq!(state.change(false, None, start + ms(50), &options), None);
+        assert_eq!(state.next_due(&options), None);
+    }
+
+    #[test]
+    fn taps_wait_to_see_if_there_is_a_double_press() {
+        let start = Instant::now();
+        let options = buttons("{gesture: tap, double_time: 300}");
+        let (mut state, _) = pressed(start, &options);
+        assert_eq!(state.change(false, None, start + ms(50), &options), None);
+        assert_eq!(state.next_due(&options), Some(start + ms(300)));
+        assert_eq!(state.tick(start + ms(300), &options), [matched().unwrap()]);
+
+        // Pressed again, so it's a double press instead
+        let (mut state, _) = pressed(start, &options);
+        state.change(false, None, start + ms(50), &options);
+        assert_eq!(
+            state.change(true, matched(), start + ms(200), &options),
+            None
+        );
+        assert_eq!(state.next_due(&options), None);
+        assert_eq!(state.change(false, None, start + ms(250), &options), None);
+    }
+
+    #[test]
+    fn double_presses() {
+        let start = Instant::now();
+        let options = buttons("{gesture: double, double_time: 300}");
+        let (mut state, _) = pressed(start, &options);
+        state.change(false, None, start + ms(50), &options);
+        assert_eq!(
+            state.change(true, matched(), start + ms(300), &options),
+            matched()
+        );
+
+        // A third press starts again
+        state.change(false, None, start + ms(350), &options);
+        assert_eq!(
+            state.change(true, matched(), start + ms(400), &options),
+            None
+        );
+
+        let (mut state, _) = pressed(start, &options);
+        state.change(false, None, start + ms(50), &options);
+        assert_eq!(
+            state.change(true, matched(), start + ms(301), &options),
+            None
+        );
+    }
+
+    fn note_on(vel: u8) -> Message {
+        Message::Midi(Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: 60.into(),
+                vel: vel.into(),
+            },
+        }))
+    }
+
+    #[test]
+    fn debounces_and_still_sends_the_last_change() {
+        let template: crate::config::MessageTemplate =
+            serde_yaml::from_str("{type: NoteOn, channel: 0, note: 60}").unwrap();
+        let transformer = Transformer::new(template.clone(), template, HashMap::new());
+        let mut gestures = Gestures::new(transformer, buttons("{debounce: 20}"));
+        let start = Instant::now();
+
+        assert_eq!(
+            gestures.transform_message(note_on(127), start),
+            [note_on(127)]
+        );
+        // Released while it might be bouncing, so only once it's settled
+        assert!(gestures
+            .transform_message(note_on(0), start + ms(5))
+            .is_empty());
+        assert_eq!(gestures.next_due(), Some(start + ms(20)));
+        assert_eq!(gestures.tick(start + ms(20)), [note_on(0)]);
+        assert_eq!(gestures.next_due(), None);
+
+        // A bounce which ends where it started isn't sent
+        assert_eq!(
+            gestures.transform_message(note_on(127), start + ms(40)),
+            [note_on(127)]
+        );
+        assert!(gestures
+            .transform_message(note_on(0), start + ms(45))
+            .is_empty());
+        assert!(gestures
+            .transform_message(note_on(127), start + ms(50))
+            .is_empty());
+        assert!(gestures.tick(start + ms(60)).is_empty());
+    }
+}
@@ -0,0 +1,683 @@
+//! Builds a mapping by watching which controls are moved on the devices.
+
+use std::{collections::HashMap, fs, path::Path};
+
+use futures::FutureExt;
+use midly::{
+    live::{LiveEvent, SystemCommon},
+    num::u7,
+    MidiMessage,
+};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+
+use gobetween::{
+    config::{Config, Mapping, MessageTemplate, Target, TemplateRef},
+    engine::{self, Engine},
+    message::Message,
+    message::{Number, Range},
+    midi::{
+        message_template::SysExByte,
+        state::{self, State},
+        Event,
+    },
+};
+
+use crate::SHUTDOWN_TIMEOUT;
+
+/// The kind of message sent by a control,
+/// along with whatever identifies the control (e.g. the note number).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Kind {
+    NoteOn { note: u8 },
+    NoteOff { note: u8 },
+    ControlChange { controller: u8 },
+    Nrpn { parameter: u16 },
+    ProgramChange,
+    PolyPressure { note: u8 },
+    ChannelPressure,
+    PitchBend,
+    SysEx(Vec<u8>),
+}
+
+/// Every message seen from one control while learning.
+#[derive(Debug, Clone)]
+struct Observed {
+    min: u32,
+    max: u32,
+    count: usize,
+
+    /// The order in which the controls were first seen, to break ties.
+    order: usize,
+}
+
+/// Which control was moved, and the range of values it sent.
+#[derive(Debug, Clone)]
+struct Learnt {
+    device: String,
+    channel: u8,
+    kind: Kind,
+    min: u32,
+    max: u32,
+}
+
+/// Connects to the devices in the config file,
+/// asks the user to move the control to map from and then the control to map to,
+/// and adds a mapping between them to the config file.
+pub async fn learn(config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let config = engine::load_config(config_path)?;
+    let profiles = engine::load_profiles(config_path, &config)?;
+
+    let mut engine = Engine::new(None);
+    engine.connect_all(&config.devices, &profiles)?;
+
+    let mut streams = Vec::new();
+    for device in engine.devices() {
+        let name = device.name.clone();
+        streams.push(BroadcastStream::new(device.subscribe()).map(move |msg| (name.clone(), msg)));
+    }
+    let mut stream = futures::stream::select_all(streams);
+    engine.start_devices();
+    let mut stdin = BufReader::new(tokio::io::stdin()).lines();
+
+    println!("Move the control to map from, then press Enter.");
+    let from = capture(&mut stream, &mut stdin).await?;
+    println!("Learnt {}", from.description());
+
+    println!("Move the control to map to, then press Enter.");
+    let to = capture(&mut stream, &mut stdin).await?;
+    println!("Learnt {}", to.description());
+
+    let mapping = learnt_mapping(&from, &to);
+
+    let entry = serde_yaml::to_string(&vec![&mapping])?;
+    println!("Adding mapping to {}:\n{entry}", config_path.display());
+    add_mapping(config_path, &config, &from.device, &mapping, &entry)?;
+
+    engine.stop(SHUTDOWN_TIMEOUT).await;
+    Ok(())
+}
+
+/// A mapping from one learnt control to another.
+fn learnt_mapping(from: &Learnt, to: &Learnt) -> Mapping {
+    let mut field_map = HashMap::new();
+    if let (Some(from_field), Some(to_field)) = (from.value_field(), to.value_field()) {
+        if from_field != to_field {
+            field_map.insert(from_field.to_string(), to_field.to_string());
+        }
+    }
+
+    // The output's value has to be generated from whatever the input matched,
+    // so a button (or SysEx) sends the other control's highest value,
+    // and a range sets a control which only sent one value to that value
+    let to_value = match (from.is_range(), to.is_range()) {
+        (false, _) => Number::Value(to.max),
+        (true, false) => Number::Range(Range(to.max, to.max)),
+        (true, true) => Number::Range(Range(to.min, to.max)),
+    };
+
+    Mapping {
+        message_template: TemplateRef::Template(from.template(from.value())),
+        target: Target {
+            name: to.device.clone(),
+            field_map,
+            message_template: TemplateRef::Template(to.template(to_value)),
+            short: None,
+        },
+        buttons: Default::default(),
+        limits: Default::default(),
+    }
+}
+
+/// Records messages until the user presses Enter,
+/// and returns the control which sent the most of them.
+async fn capture<S, R>(
+    stream: &mut S,
+    stdin: &mut tokio::io::Lines<R>,
+) -> Result<Learnt, Box<dyn std::error::Error>>
+where
+    S: Stream<Item = (String, Result<Message, BroadcastStreamRecvError>)> + Unpin,
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    // Ignore anything sent before we asked
+    while let Some(Some(_)) = stream.next().now_or_never() {}
+
+    let mut states: HashMap<String, State> = HashMap::new();
+    let mut observed: HashMap<(String, u8, Kind), Observed> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            Some((device, msg)) = stream.next() => {
+                // Only MIDI controls can be learnt
+                let Ok(Message::Midi(event)) = msg else { continue };
+                let state = states.entry(device.clone()).or_default();
+                let Some((channel, kind, value)) = identify(state, &event) else {
+                    continue;
+                };
+
+                let order = observed.len();
+                let seen = observed.entry((device, channel, kind)).or_insert(Observed {
+                    min: value,
+                    max: value,
+                    count: 0,
+                    order,
+                });
+                seen.min = seen.min.min(value);
+                seen.max = seen.max.max(value);
+                seen.count += 1;
+            }
+
+            line = stdin.next_line() => {
+                if line?.is_none() {
+                    return Err("stdin closed while learning".into());
+                }
+
+                let best = observed
+                    .iter()
+                    .max_by_key(|(_, seen)| (seen.count, std::cmp::Reverse(seen.order)));
+                match best {
+                    Some(((device, channel, kind), seen)) => {
+                        return Ok(Learnt {
+                            device: device.clone(),
+                            channel: *channel,
+                            kind: kind.clone(),
+                            min: seen.min,
+                            max: seen.max,
+                        });
+                    }
+                    None => println!("Didn't see any messages; move the control, then press Enter."),
+                }
+            }
+        }
+    }
+}
+
+/// Works out which control sent a message, and the value it sent.
+fn identify(state: &mut State, event: &Event) -> Option<(u8, Kind, u32)> {
+    match event.live_event()? {
+        LiveEvent::Midi { channel, message } => {
+            let completed = state.update(channel, &message);
+            let channel = channel.as_int();
+            if let Some(nrpn) = completed.nrpn {
+                return Some((
+                    channel,
+                    Kind::Nrpn {
+                        parameter: nrpn.parameter,
+                    },
+                    nrpn.value as u32,
+                ));
+            }
+
+            Some(match message {
+                MidiMessage::NoteOn { key, vel } => (
+                    channel,
+                    Kind::NoteOn { note: key.as_int() },
+                    vel.as_int() as u32,
+                ),
+                MidiMessage::NoteOff { key, vel } => (
+                    channel,
+                    Kind::NoteOff { note: key.as_int() },
+                    vel.as_int() as u32,
+                ),
+                // Only part of a message (e.g. an NRPN), the same as when mapping
+                MidiMessage::Controller { controller, .. }
+                    if state::is_sequence_controller(controller.as_int()) =>
+                {
+                    return None;
+                }
+                MidiMessage::Controller { controller, value } => (
+                    channel,
+                    Kind::ControlChange {
+                        controller: controller.as_int(),
+                    },
+                    value.as_int() as u32,
+                ),
+                MidiMessage::ProgramChange { program } => {
+                    (channel, Kind::ProgramChange, program.as_int() as u32)
+                }
+                MidiMessage::Aftertouch { key, vel } => (
+                    channel,
+                    Kind::PolyPressure { note: key.as_int() },
+                    vel.as_int() as u32,
+                ),
+                MidiMessage::ChannelAftertouch { vel } => {
+                    (channel, Kind::ChannelPressure, vel.as_int() as u32)
+                }
+                MidiMessage::PitchBend { bend } => {
+                    (channel, Kind::PitchBend, bend.0.as_int() as u32)
+                }
+            })
+        }
+        LiveEvent::Common(SystemCommon::SysEx(data)) => {
+            Some((0, Kind::SysEx(u7::slice_as_int(data).to_vec()), 0))
+        }
+        _ => None,
+    }
+}
+
+impl Learnt {
+    /// The name of the field which holds the control's value,
+    /// or `None` for SysEx.
+    fn value_field(&self) -> Option<&'static str> {
+        match self.kind {
+            Kind::NoteOn { .. } | Kind::NoteOff { .. } => Some("velocity"),
+            Kind::ControlChange { .. } | Kind::Nrpn { .. } => Some("value"),
+            Kind::ProgramChange => Some("program"),
+            Kind::PolyPressure { .. } | Kind::ChannelPressure => Some("pressure"),
+            Kind::PitchBend => Some("bend"),
+            Kind::SysEx(_) => None,
+        }
+    }
+
+    fn description(&self) -> String {
+        match self.value_field() {
+            Some(field) => format!(
+                "{:?} on channel {} ({field} {}-{}) from {}",
+                self.kind, self.channel, self.min, self.max, self.device
+            ),
+            None => format!("{:?} from {}", self.kind, self.device),
+        }
+    }
+
+    /// Whether the control sent more than one value.
+    fn is_range(&self) -> bool {
+        self.value_field().is_some() && self.min != self.max
+    }
+
+    /// The values which the control sent.
+    fn value(&self) -> Number {
+        if self.is_range() {
+            Number::Range(Range(self.min, self.max))
+        } else {
+            Number::Value(self.min)
+        }
+    }
+
+    /// A template for the control's messages, with the given value.
+    fn template(&self, value: Number) -> MessageTemplate {
+        let channel = vec![Number::Value(self.channel as u32)];
+        let value = vec![value];
+        let number = |n: u32| vec![Number::Value(n)];
+
+        match &self.kind {
+            Kind::NoteOn { note } => MessageTemplate::NoteOn {
+                channel,
+                note: number(*note as u32),
+                velocity: value,
+            },
+            Kind::NoteOff { note } => MessageTemplate::NoteOff {
+                channel,
+                note: number(*note as u32),
+                velocity: value,
+            },
+            Kind::ControlChange { controller } => MessageTemplate::ControlChange {
+                channel,
+                controller: number(*controller as u32),
+                value,
+            },
+            Kind::Nrpn { parameter } => MessageTemplate::Nrpn {
+                channel,
+                parameter: number(*parameter as u32),
+                value,
+            },
+            Kind::ProgramChange => MessageTemplate::ProgramChange {
+                channel,
+                program: value,
+                bank: Vec::new(),
+            },
+            Kind::PolyPressure { note } => MessageTemplate::PolyPressure {
+                channel,
+                note: number(*note as u32),
+                pressure: value,
+            },
+            Kind::ChannelPressure => MessageTemplate::ChannelPressure {
+                channel,
+                pressure: value,
+            },
+            Kind::PitchBend => MessageTemplate::PitchBend {
+                channel,
+                bend: value,
+            },
+            Kind::SysEx(data) => MessageTemplate::SysEx {
+                data: data.iter().map(|byte| SysExByte::Byte(*byte)).collect(),
+            },
+        }
+    }
+}
+
+/// Adds the mapping to the end of the device's mappings in the config file.
+///
+/// This tries to insert `entry` (the mapping as YAML) into the file as text,
+/// to keep any comments and formatting,
+/// and falls back to rewriting the whole file if that doesn't work.
+fn add_mapping(
+    config_path: &Path,
+    config: &Config,
+    device: &str,
+    mapping: &Mapping,
+    entry: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let source = fs::read_to_string(config_path)?;
+    let count = config.mappings.get(device).map_or(0, Vec::len);
+
+    let inserted = insert_mapping(&source, device, entry).filter(|new_source| {
+        serde_yaml::from_str::<Config>(new_source)
+            .map(|new| new.mappings.get(device).map_or(0, Vec::len) == count + 1)
+            .unwrap_or(false)
+    });
+
+    let new_source = match inserted {
+        Some(new_source) => new_source,
+        None => {
+            log::warn!(
+                "Couldn't find where to add the mapping in {}, so rewriting it (comments will be lost)",
+                config_path.display()
+            );
+            rewrite_mapping(&source, device, mapping)?
+        }
+    };
+
+    fs::write(config_path, new_source)?;
+    Ok(())
+}
+
+/// Adds the mapping to the end of `mappings.<device>` by rewriting the whole file,
+/// which loses any comments and formatting.
+fn rewrite_mapping(
+    source: &str,
+    device: &str,
+    mapping: &Mapping,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut value: serde_yaml::Value = serde_yaml::from_str(source)?;
+    let mappings = value
+        .get_mut("mappings")
+        .and_then(|mappings| mappings.as_mapping_mut())
+        .ok_or("config has no `mappings`")?;
+    let list = mappings
+        .entry(device.into())
+        .or_insert_with(|| serde_yaml::Value::Sequence(Vec::new()));
+    list.as_sequence_mut()
+        .ok_or_else(|| format!("`mappings.{device}` isn't a list"))?
+        .push(serde_yaml::to_value(mapping)?);
+    Ok(serde_yaml::to_string(&value)?)
+}
+
+/// Inserts a list entry at the end of `mappings.<device>` in a block-style YAML file.
+fn insert_mapping(source: &str, device: &str, entry: &str) -> Option<String> {
+    let lines: Vec<&str> = source.lines().collect();
+    let indent = |line: &str| line.len() - line.trim_start().len();
+    let is_content = |line: &str| !line.trim().is_empty() && !line.trim_start().starts_with('#');
+
+    let start = lines
+        .iter()
+        .position(|line| line.trim_end() == "mappings:")?;
+    let end = (start + 1..lines.len())
+        .find(|&i| is_content(lines[i]) && indent(lines[i]) == 0)
+        .unwrap_or(lines.len());
+    let last_content = |from: usize, to: usize| (from..to).rev().find(|&i| is_content(lines[i]));
+
+    let child_indent = (start + 1..end)
+        .find(|&i| is_content(lines[i]))
+        .map_or(2, |i| indent(lines[i]));
+    let device_line = (start + 1..end).find(|&i| {
+        indent(lines[i]) == child_indent && lines[i].trim_end().trim_start() == format!("{device}:")
+    });
+
+    let (insert_at, item_indent, header) = match device_line {
+        Some(d) => {
+            let block_end = (d + 1..end)
+                .find(|&i| is_content(lines[i]) && indent(lines[i]) <= child_indent)
+                .unwrap_or(end);
+            let item_indent = (d + 1..block_end)
+                .find(|&i| is_content(lines[i]))
+                .map_or(child_indent + 2, |i| indent(lines[i]));
+            (last_content(d, block_end)? + 1, item_indent, None)
+        }
+        None => {
+            let at = last_content(start, end)? + 1;
+            let header = format!("{}{device}:", " ".repeat(child_indent));
+            (at, child_indent + 2, Some(header))
+        }
+    };
+
+    let mut new_lines: Vec<String> = lines[..insert_at].iter().map(|l| l.to_string()).collect();
+    new_lines.extend(header);
+    new_lines.extend(
+        entry
+            .lines()
+            .map(|line| format!("{}{line}", " ".repeat(item_indent))),
+    );
+    new_lines.extend(lines[insert_at..].iter().map(|l| l.to_string()));
+
+    let mut new_source = new_lines.join("\n");
+    new_source.push('\n');
+    Some(new_source)
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::num::u4;
+
+    use gobetween::message::Transformer;
+
+    use super::*;
+
+    fn learnt(kind: Kind, min: u32, max: u32) -> Learnt {
+        Learnt {
+            device: "desk".to_string(),
+            channel: 0,
+            kind,
+            min,
+            max,
+        }
+    }
+
+    fn note_on(note: u8, vel: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::NoteOn {
+                key: note.into(),
+                vel: vel.into(),
+            },
+        })
+    }
+
+    fn control_change(controller: u8, value: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+        })
+    }
+
+    /// Runs a message through the learnt mapping.
+    fn transform(from: &Learnt, to: &Learnt, event: Event) -> Option<Vec<Event>> {
+        let mapping = learnt_mapping(from, to);
+        let (TemplateRef::Template(input), TemplateRef::Template(output)) =
+            (mapping.message_template, mapping.target.message_template)
+        else {
+            panic!("learnt mappings should use templates");
+        };
+        Transformer::new(input, output, mapping.target.field_map).transform(event)
+    }
+
+    #[test]
+    fn identifies_controls_like_mappings_do() {
+        let mut state = State::default();
+        let mut identify =
+            |event| identify(&mut state, &event).map(|(_, kind, value)| (kind, value));
+
+        assert_eq!(
+            identify(control_change(7, 100)),
+            Some((Kind::ControlChange { controller: 7 }, 100))
+        );
+
+        // Each part of an NRPN, until it's complete
+        for (controller, value) in [(99, 1), (98, 2), (6, 3)] {
+            assert_eq!(identify(control_change(controller, value)), None);
+        }
+        assert_eq!(
+            identify(control_change(38, 4)),
+            Some((Kind::Nrpn { parameter: 130 }, 388))
+        );
+
+        // Other controllers which are only part of a message
+        for controller in [0, 32, 96, 97, 0x0F, 0x2F] {
+            assert_eq!(identify(control_change(controller, 1)), None);
+        }
+    }
+
+    #[test]
+    fn maps_ranges_to_ranges() {
+        let fader = learnt(Kind::ControlChange { controller: 7 }, 0, 127);
+        let pad = learnt(Kind::NoteOn { note: 36 }, 1, 127);
+        assert_eq!(
+            transform(&fader, &pad, control_change(7, 127)),
+            Some(vec![note_on(36, 127)])
+        );
+        assert_eq!(
+            transform(&fader, &pad, control_change(7, 0)),
+            Some(vec![note_on(36, 1)])
+        );
+    }
+
+    #[test]
+    fn maps_buttons_to_the_highest_value() {
+        let button = learnt(Kind::NoteOn { note: 60 }, 127, 127);
+        let fader = learnt(Kind::ControlChange { controller: 7 }, 0, 100);
+        assert_eq!(
+            transform(&button, &fader, note_on(60, 127)),
+            Some(vec![control_change(7, 100)])
+        );
+
+        let sysex = learnt(Kind::SysEx(vec![0x7E, 0x01]), 0, 0);
+        assert_eq!(
+            transform(&sysex, &fader, Event::sysex(&[0x7E, 0x01]).unwrap()),
+            Some(vec![control_change(7, 100)])
+        );
+    }
+
+    #[test]
+    fn maps_ranges_to_single_values() {
+        let fader = learnt(Kind::ControlChange { controller: 7 }, 0, 127);
+        let button = learnt(Kind::NoteOn { note: 60 }, 127, 127);
+        assert_eq!(
+            transform(&fader, &button, control_change(7, 64)),
+            Some(vec![note_on(60, 127)])
+        );
+
+        let sysex = learnt(Kind::SysEx(vec![0x7E, 0x01]), 0, 0);
+        assert_eq!(
+            transform(&fader, &sysex, control_change(7, 64)),
+            Some(vec![Event::sysex(&[0x7E, 0x01]).unwrap()])
+        );
+    }
+
+    const SOURCE: &str = "\
+# The desk
+devices:
+  - {name: desk, loopback: false}
+  - {name: lights, loopback: false}
+
+mappings:
+  # Faders
+  desk:
+    - {from: {type: NoteOn, note: 1}, to: {target: lights, type: NoteOn, note: 3}}
+
+# The end
+";
+
+    const ENTRY: &str =
+        "- from: {type: NoteOn, note: 2}\n  to: {target: lights, type: NoteOn, note: 3}\n";
+
+    #[test]
+    fn inserts_mappings_into_existing_devices() {
+        assert_eq!(
+            insert_mapping(SOURCE, "desk", ENTRY).unwrap(),
+            "\
+# The desk
+devices:
+  - {name: desk, loopback: false}
+  - {name: lights, loopback: false}
+
+mappings:
+  # Faders
+  desk:
+    - {from: {type: NoteOn, note: 1}, to: {target: lights, type: NoteOn, note: 3}}
+    - from: {type: NoteOn, note: 2}
+      to: {target: lights, type: NoteOn, note: 3}
+
+# The end
+"
+        );
+    }
+
+    #[test]
+    fn inserts_mappings_for_new_devices() {
+        assert_eq!(
+            insert_mapping(SOURCE, "lights", ENTRY).unwrap(),
+            "\
+# The desk
+devices:
+  - {name: desk, loopback: false}
+  - {name: lights, loopback: false}
+
+mappings:
+  # Faders
+  desk:
+    - {from: {type: NoteOn, note: 1}, to: {target: lights, type: NoteOn, note: 3}}
+  lights:
+    - from: {type: NoteOn, note: 2}
+      to: {target: lights, type: NoteOn, note: 3}
+
+# The end
+"
+        );
+    }
+
+    #[test]
+    fn only_inserts_mappings_into_block_style_files() {
+        let source = "devices: []\nmappings: {desk: []}\n";
+        assert_eq!(insert_mapping(source, "desk", ENTRY), None);
+    }
+
+    #[test]
+    fn rewrites_files_without_comments() {
+        let mapping: Mapping = serde_yaml::from_str(
+            "{from: {type: NoteOn, note: 2}, to: {target: lights, type: NoteOn, note: 3}}",
+        )
+        .unwrap();
+        let source = "# The desk\ndevices: []\nmappings: {desk: [{from: {type: NoteOn, note: 1}, to: {target: lights, type: NoteOn, note: 3}}]}\n";
+        let new_source = rewrite_mapping(source, "desk", &mapping).unwrap();
+
+        assert!(!new_source.contains('#'));
+        let config: Config = serde_yaml::from_str(&new_source).unwrap();
+        assert_eq!(config.mappings["desk"].len(), 2);
+    }
+
+    #[test]
+    fn falls_back_to_rewriting_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("gobetween-learn-{}.yaml", std::process::id()));
+        let source = "# The desk\ndevices: []\nmappings: {desk: []}\n";
+        fs::write(&path, source).unwrap();
+
+        let config: Config = serde_yaml::from_str(source).unwrap();
+        let mapping: Mapping = serde_yaml::from_str(
+            "{from: {type: NoteOn, note: 2}, to: {target: lights, type: NoteOn, note: 3}}",
+        )
+        .unwrap();
+        let result = add_mapping(&path, &config, "desk", &mapping, ENTRY);
+        let new_source = fs::read_to_string(&path);
+        let _ = fs::remove_file(&path);
+
+        result.unwrap();
+        let config: Config = serde_yaml::from_str(&new_source.unwrap()).unwrap();
+        assert_eq!(config.mappings["desk"].len(), 1);
+    }
+}
@@ -0,0 +1,26 @@
+//! Bounces MIDI messages between devices,
+//! transforming them along the way.
+//!
+//! A [`Config`] lists the devices, and the mappings between them.
+//! An [`Engine`] connects to the devices and runs the mappings,
+//! each of which is a [`Transformer`] from one [`Template`] to another.
+
+pub mod clock;
+pub mod config;
+pub mod device;
+pub mod engine;
+pub mod gesture;
+pub mod message;
+pub mod midi;
+pub mod monitor;
+pub mod profile;
+pub mod queue;
+pub mod record;
+pub mod text;
+pub mod throttle;
+pub mod timer;
+
+pub use config::Config;
+pub use device::Device;
+pub use engine::{Engine, Route};
+pub use message::{Number, Template, Transformer};
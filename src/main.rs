@@ -1,27 +1,63 @@
-mod config;
-mod device;
-mod message;
-mod midi;
+mod learn;
+mod mock;
+mod test;
 
-use config::{Config, Mapping, Target};
-use message::Transformer;
+use gobetween::{
+    config::{ConnectionInfo, DeviceInfo},
+    engine::{self, Engine},
+    midi::port::{PortMatcher, Ports},
+    monitor::{Direction, Monitor},
+    record::Recorder,
+};
 
-use std::{collections::HashMap, fs::File, path::PathBuf, str::FromStr};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    time::Duration,
+};
 
-use clap::{builder::TypedValueParser as _, Parser};
-use tokio::task::JoinSet;
+use clap::{builder::TypedValueParser as _, Parser, Subcommand};
+use tokio::signal;
 use tokio_stream::{wrappers::BroadcastStream, StreamExt};
 
+/// How long to wait for the devices to be sent what's waiting for them, when exiting.
+// @Checkme: is this long enough for a slow TCP device?
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// Bounce MIDI commands between devices
 #[derive(Parser, Debug)]
-#[command(name = "gobetween", version, about, arg_required_else_help = true)]
+#[command(
+    name = "gobetween",
+    version,
+    about,
+    arg_required_else_help = true,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
     /// The path to the config file defining the devices in the system.
-    config: PathBuf,
+    #[arg(required = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Print each message going in and out of each device
+    #[arg(long)]
+    monitor: bool,
+
+    /// Record the messages sent by each device to a MIDI file,
+    /// which is saved on Ctrl-C
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
 
     /// Logging level
     #[arg(
         long,
+        global = true,
         value_name = "LEVEL",
         default_value = "info",
         value_parser = clap::builder::PossibleValuesParser::new(["error", "warn", "info", "debug", "trace", "off"])
@@ -30,9 +66,73 @@ struct Args {
     log: log::LevelFilter,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add a mapping to a config file
+    /// by moving the control to map from and then the control to map to
+    Learn {
+        /// The path to the config file defining the devices in the system.
+        config: PathBuf,
+    },
+
+    /// Connect to the devices in a config file and print the messages they send,
+    /// without running any mappings
+    Monitor {
+        /// The path to the config file defining the devices in the system.
+        config: PathBuf,
+    },
+
+    /// Connect to the devices in a config file and record the messages they send
+    /// to a MIDI file, with a track for each device, until Ctrl-C
+    Record {
+        /// The path to the config file defining the devices in the system.
+        config: PathBuf,
+
+        /// The MIDI file to write
+        file: PathBuf,
+
+        /// Only record this device (can be given more than once)
+        #[arg(long = "device", value_name = "NAME")]
+        devices: Vec<String>,
+    },
+
+    /// Check that the mappings in a config file turn the messages in some test cases
+    /// into the expected messages, without connecting to any devices
+    Test {
+        /// The path to the config file defining the devices in the system.
+        config: PathBuf,
+
+        /// The path to the file of test cases.
+        cases: PathBuf,
+    },
+
+    /// Pretend to be a TCP MIDI device, printing the messages sent to it,
+    /// and sending messages typed as hex bytes
+    MockTcp {
+        /// The address to listen on, e.g. `localhost:44332`, or just a port
+        address: String,
+
+        /// Send every message straight back
+        #[arg(long)]
+        echo: bool,
+    },
+
+    /// List the native MIDI ports, for use as `midi_in` and `midi_out`
+    Ports {
+        /// Print the ports as JSON
+        #[arg(long, conflicts_with = "yaml")]
+        json: bool,
+
+        /// Print a `devices:` section for a config file,
+        /// with a device for each input which has an output of the same name
+        #[arg(long)]
+        yaml: bool,
+    },
+}
+
 // @Todo: proper error handling
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
     // Parse the command line arguments
     let args = Args::parse();
 
@@ -42,97 +142,156 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .format_timestamp(None)
         .init();
 
-    let config_file = File::open(&args.config)?;
-    let config: Config = serde_yaml::from_reader(config_file)?;
+    match args.command {
+        Some(Command::Ports { json, yaml }) => ports(json, yaml).map(|()| ExitCode::SUCCESS),
+        Some(Command::Learn { config }) => learn::learn(&config).await.map(|()| ExitCode::SUCCESS),
+        Some(Command::Test { config, cases }) => test::test(&config, &cases),
+        Some(Command::MockTcp { address, echo }) => mock::mock_tcp(&address, echo)
+            .await
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Monitor { config }) => {
+            let options = RunOptions {
+                monitor: Some(Monitor::new()),
+                ..Default::default()
+            };
+            run(&config, options).await
+        }
+        Some(Command::Record {
+            config,
+            file,
+            devices,
+        }) => {
+            let options = RunOptions {
+                record: Some((file, devices)),
+                ..Default::default()
+            };
+            run(&config, options).await
+        }
+        None => {
+            let config = args
+                .config
+                .expect("clap should require a config file when there's no subcommand");
+            let options = RunOptions {
+                monitor: args.monitor.then(Monitor::new),
+                run_mappings: true,
+                record: args.record.map(|file| (file, Vec::new())),
+            };
+            run(&config, options).await
+        }
+    }
+}
+
+/// What to do with the devices once they're connected.
+#[derive(Default)]
+struct RunOptions {
+    /// Print each message going in and out of each device.
+    monitor: Option<Monitor>,
+
+    /// Run the mappings in the config file.
+    run_mappings: bool,
+
+    /// Record the messages sent by the named devices (or all of them, if none are named)
+    /// to a MIDI file.
+    record: Option<(PathBuf, Vec<String>)>,
+}
 
-    log::trace!("Parsed config: {config:?}");
+/// Connects to the devices in the config file,
+/// and runs its mappings (if `run_mappings` is set)
+/// until all the devices and mappings have finished,
+/// or until Ctrl-C or SIGTERM,
+/// which lets the devices finish sending what's waiting for them (and their `on_exit` messages).
+///
+/// Returns an error status if any of them failed.
+async fn run(
+    config_path: &Path,
+    options: RunOptions,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let RunOptions {
+        monitor,
+        run_mappings,
+        record,
+    } = options;
+    let config = engine::load_config(config_path)?;
 
     if config.devices.is_empty() {
         log::warn!(
             "No devices specified in config file `{}`, exiting!",
-            args.config.display()
+            config_path.display()
         );
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
     }
 
-    // Connect to the specified devices
-    let mut devices = HashMap::new();
-    let mut join_set = JoinSet::new();
-    for device_info in config.devices {
-        log::info!("Connecting to device: {device_info:?}");
-        devices.insert(
-            device_info.name.clone(),
-            device_info.connect(&mut join_set)?,
-        );
-    }
+    let profiles = engine::load_profiles(config_path, &config)?;
 
-    for (from_name, mappings) in config.mappings {
-        let from_device = devices
-            .get(&from_name)
-            .ok_or_else(|| config::Error::DeviceNotFound(from_name.clone()))?;
-
-        for Mapping {
-            message_template: from_template,
-            target:
-                Target {
-                    name: to_name,
-                    message_template: to_template,
-                    field_map,
-                },
-        } in mappings
-        {
-            let to_tx = devices
-                .get(&to_name)
-                .ok_or_else(|| config::Error::DeviceNotFound(to_name.clone()))?
-                .tx
-                .clone();
-            let mut from_tx = from_device.subscribe();
-
-            let transformer = Transformer {
-                input: from_template,
-                output: to_template,
-                field_map,
-            };
+    // Connect to the specified devices
+    let mut engine = Engine::new(monitor);
+    engine.connect_all(&config.devices, &profiles)?;
 
-            join_set.spawn(async move {
-                loop {
-                    // @XXX: don't unwrap
-                    let msg = from_tx.recv().await.unwrap();
-                    if let Some(new_msg) = transformer.transform(msg) {
-                        // @XXX: don't unwrap
-                        to_tx.send(new_msg).await.unwrap();
-                    }
-                }
-            });
+    if run_mappings {
+        for route in engine::resolve_mappings(config.mappings, &profiles)? {
+            engine.add_route(route)?;
         }
     }
 
     let mut streams = Vec::new();
-    for device in devices.values() {
+    for device in engine.devices() {
         let rx = device.subscribe();
-        streams.push(BroadcastStream::new(rx));
+        let name = device.name.clone();
+        streams.push(BroadcastStream::new(rx).map(move |msg| (name.clone(), msg)));
     }
     let mut message_echo_stream = futures::stream::select_all(streams);
 
+    let mut recording = None;
+    if let Some((file, names)) = record {
+        // One track per device, in the order they're in the config file
+        let recorded = if names.is_empty() {
+            engine.devices().collect()
+        } else {
+            names
+                .iter()
+                .map(|name| engine.device(name))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        log::info!("Recording to `{}`, press Ctrl-C to stop", file.display());
+        recording = Some((file, Recorder::start(recorded)));
+    }
+    engine.start_devices();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    let mut listening = true;
+
+    // The number of tasks which have failed,
+    // so that the exit status can show it
+    let mut failed = 0;
+
     loop {
         tokio::select! {
             // Print all broadcasted messages for debugging
-            Some(msg) = message_echo_stream.next() => {
+            Some((name, msg)) = message_echo_stream.next() => {
                 log::trace!("Got a message: {msg:?}");
+                if let (Some(monitor), Ok(event)) = (monitor, &msg) {
+                    monitor.print(&name, Direction::In, event, None);
+                }
             }
 
             // Join all the spawned tasks,
             // so that we can (in principle) do something with the return values.
-            Some(join_result) = join_set.join_next() => {
-                match join_result {
-                    // Task joined properly, returning the happy-path message for that device
-                    Ok(Ok(msg)) => log::info!("Task joined with message: {msg}"),
-
-                    // Task joined properly, returning an Err
-                    Ok(Err(err)) => log::error!("Task joined with error: {err}"),
+            join_result = engine.join_next() => {
+                // Nothing's left running
+                let Some(join_result) = join_result else { break };
+                failed += usize::from(report(join_result));
+            }
 
-                    // Task didn't join properly
-                    Err(join_err) => log::error!("Join error: {join_err}"),
+            signal = &mut signal, if listening => {
+                match signal {
+                    Ok(signal) => {
+                        log::info!("Got {signal}, shutting down");
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!("Couldn't listen for signals: {err}");
+                        listening = false;
+                    }
                 }
             }
 
@@ -140,5 +299,114 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // Lets the devices finish sending what's waiting for them, and their `on_exit` messages
+    for join_result in engine.stop(SHUTDOWN_TIMEOUT).await {
+        failed += usize::from(report(join_result));
+    }
+
+    if let Some((file, recorder)) = recording {
+        recorder.finish().await?.save(&file)?;
+        log::info!("Saved recording to `{}`", file.display());
+    }
+
+    if failed > 0 {
+        log::error!("{failed} device or mapping tasks failed");
+        return Ok(ExitCode::FAILURE);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Logs how a device or mapping finished, and returns whether it failed.
+fn report(join_result: Result<String, engine::Error>) -> bool {
+    match join_result {
+        // Task finished, returning the happy-path message for that device
+        Ok(msg) => {
+            log::info!("Task joined with message: {msg}");
+            false
+        }
+
+        // Task returned an Err, or didn't join properly
+        Err(err) => {
+            log::error!("Task joined with error: {err}");
+            true
+        }
+    }
+}
+
+/// Waits for Ctrl-C (SIGINT), or for SIGTERM (e.g. from a service manager),
+/// and returns which it was.
+async fn shutdown_signal() -> io::Result<&'static str> {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = signal::ctrl_c() => result.map(|()| "SIGINT"),
+            _ = terminate.recv() => Ok("SIGTERM"),
+        }
+    }
+
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.map(|()| "Ctrl-C")
+}
+
+/// Prints the native MIDI ports.
+fn ports(json: bool, yaml: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let ports = Ports::list()?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&ports)?);
+    } else if yaml {
+        let mut devices = Vec::new();
+        for input in ports.inputs.iter() {
+            if ports.outputs.iter().any(|output| output.name == input.name) {
+                // Turns e.g. `PreSonus FP8 #2` into `presonus_fp8_2`
+                let mut name: String = input
+                    .name
+                    .to_lowercase()
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|word| !word.is_empty())
+                    .collect::<Vec<_>>()
+                    .join("_");
+                if devices.iter().any(|d: &DeviceInfo| d.name == name) {
+                    name = format!("{name}_{}", input.index);
+                }
+
+                devices.push(DeviceInfo {
+                    name,
+                    profile: None,
+                    protocol: None,
+                    queue: Default::default(),
+                    on_connect: Vec::new(),
+                    on_reconnect: None,
+                    on_exit: Vec::new(),
+                    connection_info: ConnectionInfo::Midi {
+                        midi_in: PortMatcher::Name(input.name.clone()),
+                        midi_out: PortMatcher::Name(input.name.clone()),
+                    },
+                });
+            } else {
+                println!("# Input with no matching output: {}", input.name);
+            }
+        }
+        for output in ports.outputs.iter() {
+            if !ports.inputs.iter().any(|input| input.name == output.name) {
+                println!("# Output with no matching input: {}", output.name);
+            }
+        }
+        print!(
+            "{}",
+            serde_yaml::to_string(&HashMap::from([("devices", devices)]))?
+        );
+    } else {
+        println!("Inputs:");
+        for port in ports.inputs.iter() {
+            println!("  {}: {}", port.index, port.name);
+        }
+        println!("Outputs:");
+        for port in ports.outputs.iter() {
+            println!("  {}: {}", port.index, port.name);
+        }
+    }
+
     Ok(())
 }
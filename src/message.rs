@@ -2,28 +2,83 @@ use std::{collections::HashMap, fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
+use tokio::time::Instant;
 
+use crate::{midi::Event, queue::Coalesce};
+
+/// A message to or from any kind of device,
+/// which is what the devices' channels carry.
+///
+/// Each kind of device (and [`Template`]) deals with one kind of message,
+/// and converts to and from this with `From` and `TryFrom`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// A MIDI message, including SysEx.
+    Midi(Event),
+
+    /// An OSC message.
+    Osc { address: String, args: Vec<OscArg> },
+
+    /// The values of some consecutive DMX channels,
+    /// starting at `channel` (counting from 1).
+    Dmx {
+        universe: u16,
+        channel: u16,
+        values: Vec<u8>,
+    },
+
+    /// A line of text.
+    Text(String),
+}
+
+/// An argument of an OSC message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+}
+
+/// A description of some messages,
+/// which can both match incoming messages and generate outgoing ones.
 pub trait Template {
     type Message;
 
+    /// Whatever needs to be remembered from earlier messages
+    /// to match messages which are sent in several parts (e.g. MIDI NRPNs).
+    type State: Default;
+
     /// Checks if the given message matches the template,
     /// and if it does,
-    /// returns a [`Match`](Self::Match) describing the qualities of the match.
-    fn matches(&self, msg: Self::Message) -> Option<Match>;
+    /// returns a [`Match`] describing the qualities of the match.
+    ///
+    /// Every incoming message should be given to this,
+    /// whether or not it's expected to match,
+    /// so that the state stays up to date.
+    fn matches(&self, state: &mut Self::State, msg: Self::Message) -> Option<Match>;
 
     /// Given the qualities of a matched message,
-    /// generates the appropriate output message.
-    fn generate(&self, matched: Match) -> Option<Self::Message>;
+    /// generates the appropriate output messages.
+    fn generate(&self, matched: Match) -> Option<Vec<Self::Message>>;
 }
 
-/// Takes an input message and transforms it into the desired output message,
+/// Takes an input message and transforms it into the desired output messages,
 /// if the given input message matches the input template.
-pub struct Transformer<Fr, To> {
+pub struct Transformer<Fr: Template, To> {
     // @Todo: none of these should be pub,
     // use a From impl or something similar instead
+    /// The template which input messages are matched against.
     pub input: Fr,
+
+    /// The template which output messages are generated from.
     pub output: To,
+
+    /// Renames the fields of the input's match before generating the output,
+    /// e.g. `{bend: value}`.
     pub field_map: HashMap<String, String>,
+
+    /// What the input template remembers between messages.
+    pub state: Fr::State,
 }
 
 impl<Fr, To> Transformer<Fr, To>
@@ -31,18 +86,165 @@ where
     Fr: Template,
     To: Template,
 {
-    pub fn transform(&self, in_msg: Fr::Message) -> Option<To::Message> {
-        self.input.matches(in_msg).and_then(|mat| {
-            let mapped_mat = mat
-                .into_iter()
+    pub fn new(input: Fr, output: To, field_map: HashMap<String, String>) -> Self {
+        Transformer {
+            input,
+            output,
+            field_map,
+            state: Default::default(),
+        }
+    }
+
+    /// Transforms a message,
+    /// returning `None` if it didn't match the input template
+    /// (or the output couldn't be generated from it).
+    pub fn transform(&mut self, in_msg: Fr::Message) -> Option<Vec<To::Message>> {
+        self.matches(in_msg).and_then(|mat| self.generate(mat))
+    }
+
+    /// Matches a message against the input template,
+    /// with the fields of the match renamed by the field map.
+    pub fn matches(&mut self, in_msg: Fr::Message) -> Option<Match> {
+        self.input.matches(&mut self.state, in_msg).map(|mat| {
+            mat.into_iter()
                 .map(|(field, val)| {
                     let mapped_field = self.field_map.get(&field).cloned().unwrap_or(field);
                     (mapped_field, val)
                 })
-                .collect();
-            self.output.generate(mapped_mat)
+                .collect()
         })
     }
+
+    /// Generates the output messages for a match from [`matches`](Transformer::matches).
+    pub fn generate(&self, mapped_mat: Match) -> Option<Vec<To::Message>> {
+        self.output.generate(mapped_mat)
+    }
+}
+
+/// A [`Transformer`] between any kinds of message,
+/// so that the mappings between different kinds of device can be run in the same way.
+pub trait Transform: Send {
+    /// Transforms a message which arrived at `now`,
+    /// ignoring it if it isn't the kind of message the input template matches.
+    fn transform_message(&mut self, msg: Message, now: Instant) -> Vec<Message>;
+
+    /// When the transformer next has messages to send by itself
+    /// (e.g. once a button has been held down for long enough), if ever.
+    fn next_due(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Returns the messages which are due to be sent by `now`.
+    fn tick(&mut self, _now: Instant) -> Vec<Message> {
+        Vec::new()
+    }
+}
+
+impl<Fr, To> Transform for Transformer<Fr, To>
+where
+    Fr: Template + Send,
+    Fr::State: Send,
+    Fr::Message: TryFrom<Message>,
+    To: Template + Send,
+    To::Message: Into<Message>,
+{
+    fn transform_message(&mut self, msg: Message, _now: Instant) -> Vec<Message> {
+        let Ok(msg) = Fr::Message::try_from(msg) else {
+            return Vec::new();
+        };
+        self.transform(msg)
+            .into_iter()
+            .flatten()
+            .map(Into::into)
+            .collect()
+    }
+}
+
+impl From<Event> for Message {
+    fn from(event: Event) -> Self {
+        Message::Midi(event)
+    }
+}
+
+impl TryFrom<Message> for Event {
+    type Error = Message;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::Midi(event) => Ok(event),
+            message => Err(message),
+        }
+    }
+}
+
+impl Coalesce for Message {
+    fn supersedes(&self, earlier: &Self) -> bool {
+        match (self, earlier) {
+            (Message::Midi(event), Message::Midi(earlier)) => event.supersedes(earlier),
+            (
+                Message::Osc { address, args },
+                Message::Osc {
+                    address: earlier_address,
+                    args: earlier_args,
+                },
+            ) => address == earlier_address && args.len() == earlier_args.len(),
+            (
+                Message::Dmx {
+                    universe,
+                    channel,
+                    values,
+                },
+                Message::Dmx {
+                    universe: earlier_universe,
+                    channel: earlier_channel,
+                    values: earlier_values,
+                },
+            ) => {
+                universe == earlier_universe
+                    && channel == earlier_channel
+                    && values.len() == earlier_values.len()
+            }
+            _ => false,
+        }
+    }
+
+    fn depends_on_earlier(&self) -> bool {
+        match self {
+            Message::Midi(event) => event.depends_on_earlier(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Message::Midi(event) => event.fmt(f),
+            Message::Osc { address, args } => {
+                write!(f, "OSC {address}")?;
+                for arg in args {
+                    match arg {
+                        OscArg::Int(n) => write!(f, " {n}")?,
+                        OscArg::Float(x) => write!(f, " {x:?}")?,
+                        OscArg::String(s) => write!(f, " {s:?}")?,
+                    }
+                }
+                Ok(())
+            }
+            Message::Dmx {
+                universe,
+                channel,
+                values,
+            } => {
+                write!(f, "DMX universe {universe} channel {channel}")?;
+                for value in values {
+                    write!(f, " {value}")?;
+                }
+                Ok(())
+            }
+            Message::Text(text) => write!(f, "Text {text:?}"),
+        }
+    }
 }
 
 /// Represents a specification for a number or range of numbers,
@@ -148,12 +350,28 @@ where
     None
 }
 
+/// Generates the value for one field of an output message,
+/// from the [`Number`]s given for that field in the template,
+/// and the corresponding part of the input match (if any).
+///
+/// If the input has the field, the [`Number`] at the index which it matched is used.
+/// If it doesn't, a field with a single value is still generated,
+/// so that e.g. the fixed note of a named control, or an `on_connect` message,
+/// doesn't need anything mapping to it.
+pub fn generate_many(numbers: &[Number], matched: Option<(u32, NumberMatch)>) -> Option<u32> {
+    match (numbers, matched) {
+        (numbers, Some((ix, number_match))) => numbers.get(ix as usize)?.generate(number_match),
+        ([Number::Value(val)], None) => Some(*val),
+        (_, None) => None,
+    }
+}
+
 // @Cleanup: put this in the same place as Number
 pub type Match = HashMap<String, (u32, NumberMatch)>;
 
 // @Cleanup: put this in the same place as Number
 /// The information returned when part of a message is matched against a [`Number`].
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum NumberMatch {
     /// Contains the matched value.
     Value(u32),
@@ -164,3 +382,132 @@ pub enum NumberMatch {
     /// and 1 meaning the end of the range.
     Range(f64),
 }
+
+#[cfg(test)]
+mod tests {
+    use midly::{live::LiveEvent, MidiMessage};
+
+    use super::*;
+
+    fn volume(value: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::Controller {
+                controller: 7.into(),
+                value: value.into(),
+            },
+        })
+    }
+
+    fn osc(address: &str, args: Vec<OscArg>) -> Message {
+        Message::Osc {
+            address: address.to_owned(),
+            args,
+        }
+    }
+
+    fn dmx(universe: u16, channel: u16, values: &[u8]) -> Message {
+        Message::Dmx {
+            universe,
+            channel,
+            values: values.to_vec(),
+        }
+    }
+
+    #[test]
+    fn converts_midi_events() {
+        let event = volume(100);
+        let message = Message::from(event.clone());
+        assert_eq!(message, Message::Midi(event.clone()));
+        assert_eq!(Event::try_from(message), Ok(event));
+
+        // Anything else is given back untouched
+        for message in [
+            osc("/1/fader1", vec![OscArg::Float(0.5)]),
+            dmx(1, 10, &[255]),
+            Message::Text("hello".to_owned()),
+        ] {
+            assert_eq!(Event::try_from(message.clone()), Err(message));
+        }
+    }
+
+    #[test]
+    fn supersedes_messages_setting_the_same_thing() {
+        let fader = osc("/1/fader1", vec![OscArg::Float(0.5)]);
+        assert!(osc("/1/fader1", vec![OscArg::Float(0.7)]).supersedes(&fader));
+        assert!(!osc("/1/fader2", vec![OscArg::Float(0.7)]).supersedes(&fader));
+        assert!(!osc("/1/fader1", vec![]).supersedes(&fader));
+
+        let dimmers = dmx(1, 10, &[0, 0]);
+        assert!(dmx(1, 10, &[255, 128]).supersedes(&dimmers));
+        assert!(!dmx(2, 10, &[255, 128]).supersedes(&dimmers));
+        assert!(!dmx(1, 11, &[255, 128]).supersedes(&dimmers));
+        assert!(!dmx(1, 10, &[255]).supersedes(&dimmers));
+
+        let text = Message::Text("hello".to_owned());
+        assert!(!Message::Text("hello".to_owned()).supersedes(&text));
+
+        // Different kinds of message never replace each other
+        let midi = Message::from(volume(100));
+        assert!(Message::from(volume(90)).supersedes(&midi));
+        assert!(!midi.supersedes(&fader));
+        assert!(!fader.supersedes(&dimmers));
+
+        for message in [fader, dimmers, text] {
+            assert!(!message.depends_on_earlier());
+        }
+    }
+
+    #[test]
+    fn displays_messages() {
+        assert_eq!(
+            osc(
+                "/track/1/name",
+                vec![
+                    OscArg::Int(3),
+                    OscArg::Float(1.0),
+                    OscArg::String("Kick".to_owned())
+                ]
+            )
+            .to_string(),
+            r#"OSC /track/1/name 3 1.0 "Kick""#
+        );
+        assert_eq!(
+            dmx(1, 10, &[255, 0]).to_string(),
+            "DMX universe 1 channel 10 255 0"
+        );
+        assert_eq!(
+            Message::Text("hello".to_owned()).to_string(),
+            r#"Text "hello""#
+        );
+    }
+
+    #[test]
+    fn generates_from_the_matched_number() {
+        let numbers = [Number::Value(10), Number::Range(Range(20, 30))];
+        assert_eq!(
+            generate_many(&numbers, Some((0, NumberMatch::Value(3)))),
+            Some(10)
+        );
+        assert_eq!(
+            generate_many(&numbers, Some((1, NumberMatch::Range(0.5)))),
+            Some(25)
+        );
+        assert_eq!(
+            generate_many(&numbers, Some((2, NumberMatch::Value(3)))),
+            None
+        );
+        assert_eq!(generate_many(&numbers, None), None);
+    }
+
+    #[test]
+    fn generates_single_values_without_a_match() {
+        assert_eq!(generate_many(&[Number::Value(7)], None), Some(7));
+        assert_eq!(generate_many(&[Number::Any], None), None);
+        // A range in the input doesn't become a fixed value in the output
+        assert_eq!(
+            generate_many(&[Number::Value(7)], Some((0, NumberMatch::Range(0.5)))),
+            None
+        );
+    }
+}
@@ -1,179 +1,272 @@
 use bytes::BytesMut;
-use futures::FutureExt;
-use midir::{MidiInput, MidiOutput};
-use midly::{live::LiveEvent, stream::MidiStream};
+use std::{io, time::Duration};
+
+use midir::{MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
-    sync::{broadcast, mpsc},
-    task::JoinSet,
+    sync::mpsc,
+    time::{self, Interval, MissedTickBehavior},
 };
 
-use crate::device::{self, Device};
-
-impl Device<LiveEvent<'static>> {
-    /// Connects to a TCP over MIDI device.
-    pub fn tcp_midi(
-        join_set: &mut JoinSet<Result<String, device::Error>>,
-        name: &str,
-        addr: String,
-    ) -> Result<Self, device::Error> {
-        let (broadcast_tx, _broadcast_rx) = broadcast::channel(128); // @TestMe: is this the right capacity?
-        let (tx, mut rx): (mpsc::Sender<LiveEvent<'_>>, mpsc::Receiver<_>) = mpsc::channel(4);
-        let cloned_broadcast_tx = broadcast_tx.clone();
-        let cloned_name = name.to_string();
-
-        join_set.spawn(async move {
-            let mut socket = TcpStream::connect(&addr).await?;
-
-            log::info!("Connected to device at address {addr}");
-
-            let broadcast_tx = cloned_broadcast_tx;
-            let name = cloned_name;
-
-            let mut buf = BytesMut::new();
-            let mut out_buf = Vec::new();
-            let mut stream = MidiStream::new();
-
-            loop {
-                tokio::select! {
-                    bytes_read = socket.read_buf(&mut buf) => {
-                        let _bytes_read = bytes_read?;
-                        stream.feed(&buf, |live_event| {
-                            // Ignore the return value;
-                            // error case is when there are no receivers,
-                            // which we don't care about.
-                            let _ = broadcast_tx.send(live_event.to_static());
-                        });
-
-                        // @Note: this relies on the guarantee from BytesMut
-                        // that the memory is contiguous.
-                        // Specifically,
-                        // that BytesMut::Deref<[u8]>
-                        // returns all of the contents of the buffer.
-                        buf.clear();
-                    }
-                    Some(live_event) = rx.recv() => {
-                        log::trace!("Sending a MIDI message to {name}: {live_event:?}");
-                        live_event.write_std(&mut out_buf)?;
-                        socket.write_all(&out_buf).await?;
-                        out_buf.clear();
-                    }
-                    else => { break }
-                }
-            }
+use crate::{
+    device::{self, Transport},
+    message::Message,
+    midi::{
+        port::{Change, MidirPorts, PortMatcher, Selected, Supervisor},
+        stream::Stream,
+        Event,
+    },
+};
+
+/// How often to check whether a native MIDI device has been plugged in or unplugged.
+const PORT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A MIDI over TCP device.
+pub struct TcpMidi {
+    /// The address and port of the device,
+    /// e.g. `"123.456.40.13:8033"`.
+    address: String,
+    socket: Option<TcpStream>,
+    buf: BytesMut,
+    stream: Stream,
+}
+
+impl TcpMidi {
+    pub fn new(address: &str) -> Self {
+        TcpMidi {
+            address: address.to_string(),
+            socket: None,
+            buf: BytesMut::new(),
+            stream: Stream::new(),
+        }
+    }
+
+    fn socket(&mut self) -> Result<&mut TcpStream, io::Error> {
+        self.socket
+            .as_mut()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+impl Transport for TcpMidi {
+    type Message = Event;
+
+    async fn open(&mut self) -> Result<(), device::Error> {
+        let socket = TcpStream::connect(&self.address).await?;
+        // Send each message straight away, rather than waiting to fill a packet,
+        // which would hold up e.g. MIDI clock
+        socket.set_nodelay(true)?;
+        self.socket = Some(socket);
+        log::info!("Connected to device at address {}", self.address);
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+        let Some(socket) = &mut self.socket else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+        };
+        if socket.read_buf(&mut self.buf).await? == 0 {
+            return Ok(None);
+        }
+
+        let mut events = Vec::new();
+        self.stream
+            .feed(&self.buf, |live_event| events.push(Event::from(live_event)));
+
+        // @Note: this relies on the guarantee from BytesMut
+        // that the memory is contiguous.
+        // Specifically,
+        // that BytesMut::Deref<[u8]>
+        // returns all of the contents of the buffer.
+        self.buf.clear();
+
+        Ok(Some(events))
+    }
+
+    async fn write(&mut self, event: Event) -> Result<(), device::Error> {
+        self.socket()?.write_all(event.as_bytes()).await?;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), device::Error> {
+        self.socket()?.shutdown().await?;
+        Ok(())
+    }
+}
 
-            Ok("TCP MIDI device task finished".to_string())
-        });
+/// A native MIDI device,
+/// which is connected to whenever its ports are plugged in.
+///
+/// Any messages sent to it while it's unplugged are dropped.
+pub struct NativeMidi {
+    name: String,
+    ports: Option<(PortMatcher, PortMatcher)>,
+    supervisor: Option<Supervisor<MidirPorts>>,
+    poll: Option<Interval>,
+    connection: Option<(MidiInputConnection<()>, MidiOutputConnection)>,
+
+    /// How many times the device has been connected to.
+    connections: usize,
+
+    /// The messages from the input,
+    /// which arrive in midir's callback.
+    events_tx: mpsc::UnboundedSender<Event>,
+    events_rx: mpsc::UnboundedReceiver<Event>,
+}
 
-        Ok(Device {
+impl NativeMidi {
+    pub fn new(name: &str, in_port: &PortMatcher, out_port: &PortMatcher) -> Self {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        NativeMidi {
             name: name.to_string(),
-            broadcast_tx,
-            tx,
-        })
+            ports: Some((in_port.clone(), out_port.clone())),
+            supervisor: None,
+            poll: None,
+            connection: None,
+            connections: 0,
+            events_tx,
+            events_rx,
+        }
     }
 
-    /// Connects to a native MIDI device.
-    pub fn midi(
-        join_set: &mut JoinSet<Result<String, device::Error>>,
-        name: &str,
-        in_name: &str,
-        out_name: &str,
-    ) -> Result<Self, device::Error> {
-        // @TestMe: is this the right capacity?
-        let (broadcast_tx, _broadcast_rx) = broadcast::channel(128);
-        let (tx, mut rx): (mpsc::Sender<LiveEvent<'_>>, mpsc::Receiver<_>) = mpsc::channel(4);
-        let cloned_broadcast_tx = broadcast_tx.clone();
-
-        let orig_name = name;
-
-        // @XXX: shouldn't clone these
-        let name = name.to_string();
-        let in_name = in_name.to_string();
-        let out_name = out_name.to_string();
-
-        join_set.spawn(
-            async move {
-                let broadcast_tx = cloned_broadcast_tx;
-                let mut stream = MidiStream::new();
-
-                // @Checkme: does using "name" make sense here?
-                let input = MidiInput::new(&name)?;
-                let output = MidiOutput::new(&name)?;
-
-                let mut input_port = None;
-                for port in input.ports().iter() {
-                    let name = input.port_name(port)?;
-                    log::trace!("Found input port: {name}");
-                    if name == in_name {
-                        input_port = Some(port.clone());
-                        break;
+    /// Connects or disconnects if the ports have been plugged in or unplugged,
+    /// and returns whether it's just connected.
+    fn check_ports(&mut self) -> bool {
+        let Some(supervisor) = &mut self.supervisor else {
+            return false;
+        };
+        let name = &self.name;
+
+        match supervisor.poll() {
+            Some(Change::Appeared { input, output }) => {
+                // Close any old connection first
+                self.connection = None;
+                match connect(name, &input, &output, self.events_tx.clone()) {
+                    Ok(new_connection) => {
+                        log::info!("Connected to device {name}");
+                        self.connection = Some(new_connection);
+                        self.connections += 1;
+                        return true;
                     }
-                }
-
-                let input_port =
-                    input_port.ok_or_else(|| Error::CouldntFindMidiInput(in_name.to_string()))?;
-
-                let _input_connection = input.connect(
-                    &input_port,
-                    // @Checkme: does using "name" make sense here?
-                    &name,
-                    move |_timestamp, midi_bytes, ()| {
-                        stream.feed(midi_bytes, |live_event| {
-                            // Ignore the return value;
-                            // error case is when there are no receivers,
-                            // which we don't care about.
-                            let _ = broadcast_tx.send(live_event.to_static());
-                        })
-                    },
-                    (),
-                )?;
-
-                let mut output_port = None;
-                for port in output.ports().iter() {
-                    let name = output.port_name(port)?;
-                    log::trace!("Found output port: {name}");
-                    if name == out_name {
-                        output_port = Some(port.clone());
-                        break;
+                    Err(err) => {
+                        supervisor.reset(format!(
+                            "Couldn't connect to device {name}: {err}; trying again"
+                        ));
                     }
                 }
+            }
+            Some(Change::Disappeared) => {
+                log::warn!("Device {name} was unplugged");
+                self.connection = None;
+            }
+            None => (),
+        }
+        false
+    }
+}
 
-                let output_port = output_port
-                    .ok_or_else(|| Error::CouldntFindMidiOutput(out_name.to_string()))?;
+impl Transport for NativeMidi {
+    type Message = Event;
+
+    async fn open(&mut self) -> Result<(), device::Error> {
+        if let Some((in_port, out_port)) = self.ports.take() {
+            // @Checkme: does using "name" make sense here?
+            self.supervisor = Some(Supervisor::new(
+                MidirPorts::new(&self.name)?,
+                in_port,
+                out_port,
+            ));
+        }
+        let mut poll = time::interval(PORT_POLL_INTERVAL);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.poll = Some(poll);
+        Ok(())
+    }
 
-                // @Checkme: does using "name" make sense here?
-                let mut output_connection = output.connect(&output_port, &name)?;
+    async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+        loop {
+            let Some(poll) = &mut self.poll else {
+                return Err(io::Error::from(io::ErrorKind::NotConnected).into());
+            };
+            tokio::select! {
+                _ = poll.tick() => {
+                    if self.check_ports() {
+                        // So that the device can be sent its `on_connect` messages
+                        return Ok(Some(Vec::new()));
+                    }
+                }
+                // This holds a sender, so never runs out
+                Some(event) = self.events_rx.recv() => return Ok(Some(vec![event])),
+            }
+        }
+    }
 
-                log::info!("Connected to device {name}");
+    fn connections(&self) -> usize {
+        self.connections
+    }
 
-                let mut buf = Vec::new();
-                while let Some(live_event) = rx.recv().await {
-                    live_event.write_std::<&mut [u8]>(buf.as_mut())?;
-                    output_connection.send(&buf)?;
-                    buf.clear();
+    async fn write(&mut self, event: Event) -> Result<(), device::Error> {
+        let name = &self.name;
+        match &mut self.connection {
+            Some((_input_connection, output_connection)) => {
+                if let Err(err) = output_connection.send(event.as_bytes()) {
+                    self.connection = None;
+                    if let Some(supervisor) = &mut self.supervisor {
+                        supervisor.reset(format!("Couldn't send to device {name}: {err}"));
+                    }
                 }
-
-                Ok("MIDI device task finished".to_string())
             }
-            .map(|res: Result<_, self::Error>| res.map_err(device::Error::from)),
-        );
-
-        Ok(Device {
-            name: orig_name.to_string(),
-            broadcast_tx,
-            tx,
-        })
+            None => {
+                log::trace!("Dropping message for unplugged device {name}: {event:?}");
+            }
+        }
+        Ok(())
     }
 }
 
+/// Connects to the given native MIDI ports,
+/// passing on every message from the input.
+fn connect(
+    name: &str,
+    input: &Selected,
+    output: &Selected,
+    events_tx: mpsc::UnboundedSender<Event>,
+) -> Result<(MidiInputConnection<()>, MidiOutputConnection), Error> {
+    let mut stream = Stream::new();
+
+    // @Checkme: does using "name" make sense here?
+    let midi_input = MidiInput::new(name)?;
+    let input_port = input.port("input", &midi_input)?;
+    let input_connection = midi_input.connect(
+        &input_port,
+        // @Checkme: does using "name" make sense here?
+        name,
+        move |_timestamp, midi_bytes, ()| {
+            stream.feed(midi_bytes, |live_event| {
+                // Ignore the return value;
+                // error case is when the device has finished,
+                // which we don't care about.
+                let _ = events_tx.send(Event::from(live_event));
+            })
+        },
+        (),
+    )?;
+
+    let midi_output = MidiOutput::new(name)?;
+    let output_port = output.port("output", &midi_output)?;
+    let output_connection = midi_output.connect(&output_port, name)?;
+
+    Ok((input_connection, output_connection))
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("IO error: {0}")]
     IO(#[from] std::io::Error),
 
-    #[error("Channel send error, couldn't send MIDI message {0:?}")]
-    Send(#[from] tokio::sync::mpsc::error::SendError<LiveEvent<'static>>),
+    #[error("Channel send error, couldn't send message {0:?}")]
+    Send(#[from] tokio::sync::mpsc::error::SendError<Message>),
 
     #[error("MIDI init error: {0}")]
     MidirInit(#[from] midir::InitError),
@@ -190,9 +283,17 @@ pub enum Error {
     #[error("MIDI send error: {0}")]
     MidirSend(#[from] midir::SendError),
 
-    #[error("Couldn't find MIDI input with name: {0}")]
-    CouldntFindMidiInput(String),
+    #[error(
+        "Couldn't find MIDI {0} matching {1} (found {2:?}; run `gobetween ports` to list them)"
+    )]
+    NoMatchingPort(&'static str, String, Vec<String>),
+
+    #[error("Couldn't read MIDI file: {0}")]
+    Smf(#[from] midly::Error),
+
+    #[error("No track named `{0}` in MIDI file `{1}` (found {2:?})")]
+    NoSuchTrack(String, String, Vec<String>),
 
-    #[error("Couldn't find MIDI output with name: {0}")]
-    CouldntFindMidiOutput(String),
+    #[error("More than one MIDI {0} matches {1} (found {2:?}); use `occurrence` to choose one")]
+    AmbiguousPort(&'static str, String, Vec<String>),
 }
@@ -0,0 +1,279 @@
+use std::fmt;
+
+use midly::{
+    live::{LiveEvent, SystemCommon},
+    num::u7,
+    MidiMessage,
+};
+
+use crate::midi::state;
+
+/// An owned MIDI message.
+///
+/// [`LiveEvent::to_static`] throws away the contents of SysEx messages,
+/// so instead of passing around `LiveEvent<'static>`,
+/// we keep hold of the raw bytes,
+/// and parse them into a [`LiveEvent`] whenever we need to look inside.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Event(Vec<u8>);
+
+impl Event {
+    /// Creates a SysEx message from its data bytes,
+    /// i.e. not including the leading `0xF0` or trailing `0xF7`,
+    /// or returns `None` if any of them is more than `0x7F`.
+    pub fn sysex(data: &[u8]) -> Option<Self> {
+        if data.iter().any(|byte| *byte > 0x7F) {
+            return None;
+        }
+        Some(Event::from(LiveEvent::Common(SystemCommon::SysEx(
+            u7::slice_from_int(data),
+        ))))
+    }
+
+    /// Parses the message.
+    ///
+    /// Every `Event` is written from a `LiveEvent`, so this should always succeed,
+    /// unless that `LiveEvent` wasn't valid MIDI (e.g. an undefined status).
+    pub fn live_event(&self) -> Option<LiveEvent<'_>> {
+        LiveEvent::parse(&self.0).ok()
+    }
+
+    /// The raw bytes of the message, ready to be sent to a device.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Splits the message into what it controls and the value it sets,
+    /// e.g. the channel and number of a controller, and the controller's value.
+    ///
+    /// The value comes with how many bits it has.
+    /// Messages which don't set a value (e.g. notes, or the parameter number of an NRPN)
+    /// are all control, with no value.
+    pub fn control_value(&self) -> (&[u8], Option<(u32, u32)>) {
+        let Some(LiveEvent::Midi { message, .. }) = self.live_event() else {
+            return (&self.0, None);
+        };
+        match message {
+            MidiMessage::Controller { controller, value }
+                if !state::is_sequence_controller(controller.as_int())
+                    || state::is_data_entry(controller.as_int()) =>
+            {
+                (&self.0[..2], Some((value.as_int().into(), 7)))
+            }
+            MidiMessage::Aftertouch { vel, .. } => (&self.0[..2], Some((vel.as_int().into(), 7))),
+            MidiMessage::ChannelAftertouch { vel } => {
+                (&self.0[..1], Some((vel.as_int().into(), 7)))
+            }
+            MidiMessage::PitchBend { bend } => (&self.0[..1], Some((bend.0.as_int().into(), 14))),
+            _ => (&self.0, None),
+        }
+    }
+
+    /// Which button the message presses or releases, if it's a button,
+    /// i.e. the channel and note or controller number,
+    /// and whether it's pressed.
+    ///
+    /// A note on with no velocity, or a controller set to 0, is a release.
+    pub fn button(&self) -> Option<([u8; 2], bool)> {
+        let Some(LiveEvent::Midi { channel, message }) = self.live_event() else {
+            return None;
+        };
+        let channel = channel.as_int();
+        match message {
+            MidiMessage::NoteOn { key, vel } => Some(([0x90 | channel, key.as_int()], vel > 0)),
+            MidiMessage::NoteOff { key, .. } => Some(([0x90 | channel, key.as_int()], false)),
+            MidiMessage::Controller { controller, value }
+                if !state::is_sequence_controller(controller.as_int()) =>
+            {
+                Some(([0xB0 | channel, controller.as_int()], value > 0))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this message sets the value of an NRPN or RPN.
+    pub fn is_data_entry(&self) -> bool {
+        matches!(
+            self.live_event(),
+            Some(LiveEvent::Midi {
+                message: MidiMessage::Controller { controller, .. },
+                ..
+            }) if state::is_data_entry(controller.as_int())
+        )
+    }
+
+    /// Whether this message sets the same thing as an earlier one,
+    /// i.e. the same controller, pitch bend or pressure on the same channel,
+    /// so that the earlier one needn't be sent.
+    ///
+    /// The MSB of a 14-bit controller (0-31) also supersedes its LSB (32-63),
+    /// as it resets the LSB,
+    /// so the two halves are replaced together and the LSB still follows the MSB.
+    /// Controllers which are part of a sequence (e.g. NRPNs) never do.
+    pub fn supersedes(&self, earlier: &Event) -> bool {
+        let (
+            Some(LiveEvent::Midi { channel, message }),
+            Some(LiveEvent::Midi {
+                channel: earlier_channel,
+                message: earlier_message,
+            }),
+        ) = (self.live_event(), earlier.live_event())
+        else {
+            return false;
+        };
+        if channel != earlier_channel {
+            return false;
+        }
+
+        match (message, earlier_message) {
+            (
+                MidiMessage::Controller { controller, .. },
+                MidiMessage::Controller {
+                    controller: earlier_controller,
+                    ..
+                },
+            ) => {
+                let (controller, earlier_controller) =
+                    (controller.as_int(), earlier_controller.as_int());
+                (controller == earlier_controller
+                    || (controller < 32 && earlier_controller == controller + 32))
+                    && state::is_replaceable_controller(controller)
+                    && state::is_replaceable_controller(earlier_controller)
+            }
+            (
+                MidiMessage::Aftertouch { key, .. },
+                MidiMessage::Aftertouch {
+                    key: earlier_key, ..
+                },
+            ) => key == earlier_key,
+            (MidiMessage::ChannelAftertouch { .. }, MidiMessage::ChannelAftertouch { .. })
+            | (MidiMessage::PitchBend { .. }, MidiMessage::PitchBend { .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this message uses the ones before it,
+    /// i.e. a program change uses the bank select before it.
+    pub fn depends_on_earlier(&self) -> bool {
+        matches!(
+            self.live_event(),
+            Some(LiveEvent::Midi {
+                message: MidiMessage::ProgramChange { .. },
+                ..
+            })
+        )
+    }
+}
+
+impl From<LiveEvent<'_>> for Event {
+    fn from(live_event: LiveEvent<'_>) -> Self {
+        let mut bytes = Vec::new();
+        // Writing to a Vec can't fail
+        let _ = live_event.write_std(&mut bytes);
+        Event(bytes)
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.live_event() {
+            Some(live_event) => live_event.fmt(f),
+            None => write!(f, "Invalid({:02X?})", self.0),
+        }
+    }
+}
+
+/// A human-readable description of the message,
+/// with channels numbered from 0 as in message templates.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(live_event) = self.live_event() else {
+            return write!(f, "Invalid {:02X?}", self.0);
+        };
+        match live_event {
+            LiveEvent::Midi { channel, message } => {
+                write!(f, "ch {channel:<2} ")?;
+                match message {
+                    MidiMessage::NoteOn { key, vel } => write!(f, "NoteOn note {key} velocity {vel}"),
+                    MidiMessage::NoteOff { key, vel } => {
+                        write!(f, "NoteOff note {key} velocity {vel}")
+                    }
+                    MidiMessage::Aftertouch { key, vel } => {
+                        write!(f, "PolyPressure note {key} pressure {vel}")
+                    }
+                    MidiMessage::Controller { controller, value } => {
+                        write!(f, "ControlChange controller {controller} value {value}")
+                    }
+                    MidiMessage::ProgramChange { program } => {
+                        write!(f, "ProgramChange program {program}")
+                    }
+                    MidiMessage::ChannelAftertouch { vel } => {
+                        write!(f, "ChannelPressure pressure {vel}")
+                    }
+                    MidiMessage::PitchBend { bend } => write!(f, "PitchBend bend {}", bend.0),
+                }
+            }
+            LiveEvent::Common(SystemCommon::SysEx(data)) => {
+                write!(f, "SysEx")?;
+                for byte in data.iter() {
+                    write!(f, " {:02X}", byte.as_int())?;
+                }
+                Ok(())
+            }
+            other => write!(f, "{other:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sysex_data_is_7_bit() {
+        let event = Event::sysex(&[0x00, 0x7F]).unwrap();
+        assert_eq!(event.as_bytes(), [0xF0, 0x00, 0x7F, 0xF7]);
+        assert!(Event::sysex(&[0x00, 0xF7]).is_none());
+    }
+
+    fn control_change(controller: u8, value: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+        })
+    }
+
+    #[test]
+    fn supersedes_both_halves_of_14_bit_controllers() {
+        let msb = |value| control_change(0, value);
+        let lsb = |value| control_change(32, value);
+        assert!(msb(2).supersedes(&msb(1)));
+        assert!(msb(2).supersedes(&lsb(1)));
+        assert!(lsb(2).supersedes(&lsb(1)));
+        // The LSB has to stay after the MSB
+        assert!(!lsb(2).supersedes(&msb(1)));
+
+        assert!(control_change(7, 2).supersedes(&control_change(39, 1)));
+        assert!(!control_change(7, 2).supersedes(&control_change(40, 1)));
+        assert!(!control_change(40, 2).supersedes(&control_change(8, 1)));
+    }
+
+    #[test]
+    fn never_supersedes_sequences() {
+        for controller in [6, 38, 98, 99, 0x0F, 0x2F] {
+            assert!(!control_change(controller, 2).supersedes(&control_change(controller, 1)));
+        }
+        assert!(!control_change(6, 2).supersedes(&control_change(38, 1)));
+        assert!(!control_change(0x0F, 2).supersedes(&control_change(0x2F, 1)));
+
+        let program_change = Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::ProgramChange { program: 1.into() },
+        });
+        assert!(program_change.depends_on_earlier());
+        assert!(!control_change(0, 1).depends_on_earlier());
+    }
+}
@@ -0,0 +1,454 @@
+//! The handshakes and keepalives of the Mackie Control Universal (MCU) and HUI protocols.
+//!
+//! The controls themselves are described by the `mcu` and `hui` profiles;
+//! this only deals with the messages which the other end expects
+//! before (and while) it will talk to us.
+
+use std::time::Duration;
+
+use midly::{
+    live::{LiveEvent, SystemCommon},
+    num::u7,
+    MidiMessage,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    task::{AbortHandle, JoinSet},
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    device::{self, Device},
+    message::Message,
+    midi::Event,
+};
+
+/// The SysEx header of MCU messages,
+/// i.e. the Mackie manufacturer ID followed by the MCU's device ID.
+const MCU_HEADER: [u8; 4] = [0x00, 0x00, 0x66, 0x14];
+
+/// MCU SysEx message types.
+const MCU_DEVICE_QUERY: u8 = 0x00;
+const MCU_HOST_CONNECTION_QUERY: u8 = 0x01;
+const MCU_HOST_CONNECTION_REPLY: u8 = 0x02;
+const MCU_HOST_CONNECTION_CONFIRMATION: u8 = 0x03;
+const MCU_HOST_CONNECTION_ERROR: u8 = 0x04;
+const MCU_VERSION_REQUEST: u8 = 0x13;
+const MCU_VERSION_REPLY: u8 = 0x14;
+
+/// What we tell the host when we're pretending to be an MCU.
+const MCU_SERIAL_NUMBER: &[u8; 7] = b"GOBTWN1";
+const MCU_CHALLENGE: [u8; 4] = [0x13, 0x37, 0x42, 0x07];
+const MCU_VERSION: &[u8] = b"V1.02";
+
+/// How often a HUI host has to ping the surface to keep it online.
+const HUI_PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A protocol which a device speaks on top of plain MIDI.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum Protocol {
+    /// Mackie Control Universal.
+    Mcu { role: Role },
+
+    /// Mackie HUI.
+    Hui { role: Role },
+}
+
+/// Which end of the protocol gobetween is playing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// gobetween is the host (i.e. the DAW), driving a surface.
+    Host,
+
+    /// gobetween is pretending to be a surface, talking to a DAW.
+    Surface,
+}
+
+impl Protocol {
+    /// The messages which start the protocol's handshake,
+    /// which are sent whenever the device connects (see [`Lifecycle`](device::Lifecycle)),
+    /// after its own `on_connect` or `on_reconnect` messages.
+    pub fn handshake(&self) -> Vec<Message> {
+        match self {
+            Protocol::Mcu { role: Role::Host } => mcu_sysex(MCU_DEVICE_QUERY, &[])
+                .map(Message::from)
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Spawns a task which answers the other end of the protocol
+    /// (and pings it, where necessary)
+    /// for as long as the device is connected,
+    /// and returns a handle for stopping it.
+    ///
+    /// The caller sends the device the [`handshake`](Protocol::handshake) whenever it connects.
+    pub fn spawn(
+        &self,
+        device: &Device<Message>,
+        join_set: &mut JoinSet<Result<String, device::Error>>,
+    ) -> AbortHandle {
+        let protocol = self.clone();
+        let name = device.name.clone();
+        let tx = device.tx.clone();
+        let mut rx = device.subscribe();
+
+        join_set.spawn(async move {
+            let mut ping = time::interval(HUI_PING_INTERVAL);
+            ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            // Either way, the device has finished
+            'task: loop {
+                tokio::select! {
+                    msg = rx.recv() => {
+                        let event = match msg {
+                            Ok(Message::Midi(event)) => event,
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(_)) => continue,
+                            Err(RecvError::Closed) => break,
+                        };
+
+                        for reply in protocol.reply(&name, &event) {
+                            if tx.send(reply.into()).await.is_err() {
+                                break 'task;
+                            }
+                        }
+                    }
+
+                    _ = ping.tick(), if matches!(protocol, Protocol::Hui { role: Role::Host }) => {
+                        if tx.send(hui_ping(false).into()).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            Ok(format!("{protocol:?} protocol task for {name} finished"))
+        })
+    }
+
+    /// Returns the messages to send in reply to a message from the device.
+    fn reply(&self, name: &str, event: &Event) -> Vec<Event> {
+        let Some(live_event) = event.live_event() else {
+            return Vec::new();
+        };
+        match (self, live_event) {
+            (Protocol::Mcu { role }, LiveEvent::Common(SystemCommon::SysEx(data))) => {
+                let Some(data) = u7::slice_as_int(data).strip_prefix(&MCU_HEADER[..]) else {
+                    return Vec::new();
+                };
+                let Some((&message_type, data)) = data.split_first() else {
+                    return Vec::new();
+                };
+                mcu_reply(*role, name, message_type, data)
+            }
+
+            (
+                Protocol::Hui { role },
+                LiveEvent::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel },
+                },
+            ) if channel == 0 && key == 0 => match (role, vel.as_int()) {
+                (Role::Surface, 0x00) => vec![hui_ping(true)],
+                (Role::Host, 0x7F) => {
+                    log::trace!("Got HUI ping reply from {name}");
+                    Vec::new()
+                }
+                _ => Vec::new(),
+            },
+
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Returns the replies to an MCU SysEx message.
+fn mcu_reply(role: Role, name: &str, message_type: u8, data: &[u8]) -> Vec<Event> {
+    match (role, message_type) {
+        (Role::Surface, MCU_DEVICE_QUERY) => {
+            log::debug!("Got MCU device query from {name}");
+            let mut reply = MCU_SERIAL_NUMBER.to_vec();
+            reply.extend(MCU_CHALLENGE);
+            mcu_sysex(MCU_HOST_CONNECTION_QUERY, &reply)
+                .into_iter()
+                .collect()
+        }
+
+        (Role::Surface, MCU_HOST_CONNECTION_REPLY) => {
+            let response = data.get(7..11).unwrap_or_default();
+            if response == mcu_challenge_response(MCU_CHALLENGE) {
+                log::info!("Connected to MCU host {name}");
+                mcu_sysex(MCU_HOST_CONNECTION_CONFIRMATION, MCU_SERIAL_NUMBER)
+                    .into_iter()
+                    .collect()
+            } else {
+                log::warn!("MCU host {name} gave the wrong challenge response");
+                mcu_sysex(MCU_HOST_CONNECTION_ERROR, MCU_SERIAL_NUMBER)
+                    .into_iter()
+                    .collect()
+            }
+        }
+
+        (Role::Surface, MCU_VERSION_REQUEST) => mcu_sysex(MCU_VERSION_REPLY, MCU_VERSION)
+            .into_iter()
+            .collect(),
+
+        (Role::Host, MCU_HOST_CONNECTION_QUERY) => {
+            let (Some(serial), Some(challenge)) = (data.get(..7), data.get(7..11)) else {
+                log::warn!("Got a malformed MCU host connection query from {name}");
+                return Vec::new();
+            };
+            let mut reply = serial.to_vec();
+            reply.extend(mcu_challenge_response([
+                challenge[0],
+                challenge[1],
+                challenge[2],
+                challenge[3],
+            ]));
+            mcu_sysex(MCU_HOST_CONNECTION_REPLY, &reply)
+                .into_iter()
+                .collect()
+        }
+
+        (Role::Host, MCU_HOST_CONNECTION_CONFIRMATION) => {
+            log::info!("Connected to MCU surface {name}");
+            Vec::new()
+        }
+
+        (Role::Host, MCU_HOST_CONNECTION_ERROR) => {
+            log::warn!("MCU surface {name} rejected the connection");
+            Vec::new()
+        }
+
+        _ => Vec::new(),
+    }
+}
+
+/// The response which the host should give to the surface's challenge
+/// in the MCU host connection query.
+fn mcu_challenge_response(l: [u8; 4]) -> [u8; 4] {
+    let [l1, l2, l3, l4] = l.map(|b| b as i32);
+    [
+        0x7F & (l1 + (l2 ^ 0x0A) - l4),
+        0x7F & (l3.checked_shr(l4 as u32).unwrap_or(0) ^ (l1 + l4)),
+        0x7F & ((l4 - (l3 << 2)) ^ (l1 | l2)),
+        0x7F & (l2 - l3 + (0xF0 ^ (l4 << 4))),
+    ]
+    .map(|b| b as u8)
+}
+
+/// An MCU SysEx message of the given type,
+/// if all of the data is 7-bit (which it is unless the other end sent a bad message).
+fn mcu_sysex(message_type: u8, data: &[u8]) -> Option<Event> {
+    let mut bytes = MCU_HEADER.to_vec();
+    bytes.push(message_type);
+    bytes.extend(data);
+    Event::sysex(&bytes)
+}
+
+/// A HUI ping (from the host) or ping reply (from the surface).
+fn hui_ping(reply: bool) -> Event {
+    Event::from(LiveEvent::Midi {
+        channel: 0.into(),
+        message: MidiMessage::NoteOn {
+            key: 0.into(),
+            vel: if reply { 0x7F } else { 0x00 }.into(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::watch;
+
+    use super::*;
+    use crate::{
+        config::Queue,
+        device::{Lifecycle, Loopback},
+    };
+
+    const HOST: Protocol = Protocol::Mcu { role: Role::Host };
+    const SURFACE: Protocol = Protocol::Mcu {
+        role: Role::Surface,
+    };
+
+    #[test]
+    fn answers_mcu_challenges() {
+        for (challenge, response) in [
+            ([0x00, 0x00, 0x00, 0x00], [0x0A, 0x00, 0x00, 0x70]),
+            ([0x13, 0x37, 0x42, 0x07], [0x49, 0x1A, 0x48, 0x75]),
+            ([0x7F, 0x7F, 0x7F, 0x7F], [0x75, 0x7E, 0x7C, 0x00]),
+        ] {
+            assert_eq!(mcu_challenge_response(challenge), response);
+        }
+    }
+
+    /// The replies of one end to each of the messages from the other.
+    fn replies(protocol: &Protocol, events: &[Event]) -> Vec<Event> {
+        events
+            .iter()
+            .flat_map(|event| protocol.reply("other end", event))
+            .collect()
+    }
+
+    fn mcu(message_type: u8, data: &[u8]) -> Event {
+        mcu_sysex(message_type, data).unwrap()
+    }
+
+    #[test]
+    fn connects_mcu_hosts_and_surfaces() {
+        let handshake: Vec<Event> = HOST
+            .handshake()
+            .into_iter()
+            .map(|message| Event::try_from(message).unwrap())
+            .collect();
+        assert_eq!(handshake, [mcu(MCU_DEVICE_QUERY, &[])]);
+
+        let query = replies(&SURFACE, &handshake);
+        assert_eq!(
+            query,
+            [mcu(MCU_HOST_CONNECTION_QUERY, b"GOBTWN1\x13\x37\x42\x07")]
+        );
+
+        let reply = replies(&HOST, &query);
+        assert_eq!(
+            reply,
+            [mcu(MCU_HOST_CONNECTION_REPLY, b"GOBTWN1\x49\x1A\x48\x75")]
+        );
+
+        let confirmation = replies(&SURFACE, &reply);
+        assert_eq!(
+            confirmation,
+            [mcu(MCU_HOST_CONNECTION_CONFIRMATION, b"GOBTWN1")]
+        );
+        assert_eq!(replies(&HOST, &confirmation), []);
+
+        assert_eq!(
+            replies(&SURFACE, &[mcu(MCU_VERSION_REQUEST, &[])]),
+            [mcu(MCU_VERSION_REPLY, b"V1.02")]
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_mcu_challenge_responses() {
+        let reply = mcu(MCU_HOST_CONNECTION_REPLY, b"GOBTWN1\x49\x1A\x48\x76");
+        let error = replies(&SURFACE, &[reply]);
+        assert_eq!(error, [mcu(MCU_HOST_CONNECTION_ERROR, b"GOBTWN1")]);
+        assert_eq!(replies(&HOST, &error), []);
+
+        // Too short to have a response at all
+        let reply = mcu(MCU_HOST_CONNECTION_REPLY, b"GOBTWN1");
+        assert_eq!(replies(&SURFACE, &[reply]), error);
+        assert_eq!(
+            replies(&HOST, &[mcu(MCU_HOST_CONNECTION_QUERY, b"GOBTWN1")]),
+            []
+        );
+    }
+
+    #[test]
+    fn ignores_messages_for_the_other_end() {
+        let messages = [
+            mcu(MCU_DEVICE_QUERY, &[]),
+            mcu(MCU_HOST_CONNECTION_QUERY, b"GOBTWN1\x13\x37\x42\x07"),
+            mcu(MCU_HOST_CONNECTION_REPLY, b"GOBTWN1\x49\x1A\x48\x75"),
+            mcu(MCU_VERSION_REQUEST, &[]),
+        ];
+        assert_eq!(replies(&HOST, &messages[2..]), []);
+        assert_eq!(replies(&SURFACE, &messages[1..2]), []);
+        assert_eq!(
+            replies(
+                &Protocol::Hui {
+                    role: Role::Surface
+                },
+                &messages
+            ),
+            []
+        );
+    }
+
+    #[test]
+    fn answers_hui_pings() {
+        let host = Protocol::Hui { role: Role::Host };
+        let surface = Protocol::Hui {
+            role: Role::Surface,
+        };
+        assert_eq!(replies(&surface, &[hui_ping(false)]), [hui_ping(true)]);
+        assert_eq!(replies(&surface, &[hui_ping(true)]), []);
+        assert_eq!(replies(&host, &[hui_ping(false), hui_ping(true)]), []);
+        assert!(host.handshake().is_empty());
+        assert!(surface.handshake().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pings_hui_surfaces() {
+        let (start_tx, start_rx) = watch::channel(false);
+        let mut devices = JoinSet::new();
+        let device = Device::spawn(
+            &mut devices,
+            "surface",
+            &Queue::default(),
+            Lifecycle::default(),
+            start_rx,
+            Loopback::<Message>::new(true),
+        );
+        let mut rx = device.subscribe();
+        let mut protocols = JoinSet::new();
+        Protocol::Hui { role: Role::Host }.spawn(&device, &mut protocols);
+        start_tx.send(true).unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(rx.recv().await.unwrap(), Message::Midi(hui_ping(false)));
+        }
+
+        devices.abort_all();
+        let result = protocols.join_next().await.unwrap().unwrap();
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    /// Sends a HUI ping, then disconnects.
+    struct PingThenDisconnect(bool);
+
+    impl device::Transport for PingThenDisconnect {
+        type Message = Event;
+
+        async fn open(&mut self) -> Result<(), device::Error> {
+            Ok(())
+        }
+
+        async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+            let pinged = std::mem::replace(&mut self.0, true);
+            Ok((!pinged).then(|| vec![hui_ping(false)]))
+        }
+
+        async fn write(&mut self, _: Event) -> Result<(), device::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn finishes_when_the_device_has_gone_before_the_reply() {
+        let (start_tx, start_rx) = watch::channel(false);
+        let mut devices = JoinSet::new();
+        let device = Device::spawn(
+            &mut devices,
+            "host",
+            &Queue::default(),
+            Lifecycle::default(),
+            start_rx,
+            PingThenDisconnect(false),
+        );
+        let mut protocols = JoinSet::new();
+        Protocol::Hui {
+            role: Role::Surface,
+        }
+        .spawn(&device, &mut protocols);
+        start_tx.send(true).unwrap();
+
+        let result = protocols.join_next().await.unwrap().unwrap();
+        assert!(result.is_ok(), "{result:?}");
+    }
+}
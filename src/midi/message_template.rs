@@ -1,9 +1,26 @@
-use midly::{live::LiveEvent, MidiMessage};
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use midly::{
+    live::{LiveEvent, SystemCommon, SystemRealtime},
+    num::u7,
+    MidiMessage,
+};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use serde_with::{serde_as, OneOrMany};
 use try_match::match_ok;
 
-use crate::message::{self, Match, Number, Template};
+use crate::{
+    message::{self, Match, Number, NumberMatch, Template},
+    midi::{
+        state::{self, Controller14, HuiPort, Nrpn, State},
+        Event,
+    },
+    profile::Table,
+    text::{self, Align, Charset, Format},
+};
+
+/// The status byte of a Song Position Pointer message.
+const SONG_POSITION: u8 = 0xF2;
 
 #[serde_as]
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -49,6 +66,11 @@ pub enum MessageTemplate {
         #[serde(default = "Number::default_vec")]
         #[serde_as(as = "OneOrMany<_>")]
         program: Vec<Number>,
+        /// If given, the program change is preceded by a bank select (CC 0).
+        #[serde(default)]
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        #[serde_as(as = "OneOrMany<_>")]
+        bank: Vec<Number>,
     },
     PolyPressure {
         #[serde(default = "Number::default_vec")]
@@ -77,22 +99,314 @@ pub enum MessageTemplate {
         #[serde_as(as = "OneOrMany<_>")]
         bend: Vec<Number>,
     },
+    /// A Non-Registered Parameter Number message,
+    /// sent as four control changes (99, 98, 6, 38).
+    /// Both the parameter and value are 14-bit numbers.
+    Nrpn {
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        channel: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        parameter: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        value: Vec<Number>,
+    },
+    /// A 14-bit control change,
+    /// sent as the MSB on `controller` (0-31) followed by the LSB on `controller + 32`.
+    ControlChange14 {
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        channel: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        controller: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        value: Vec<Number>,
+    },
+    /// A switch on a HUI surface being pressed (`state: 1`) or released (`state: 0`),
+    /// sent from the surface to the host as a zone and a port (CC 0x0F, 0x2F).
+    HuiSwitch {
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        zone: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        port: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        state: Vec<Number>,
+    },
+    /// An LED on a HUI surface being turned on (`state: 1`) or off (`state: 0`),
+    /// sent from the host to the surface as a zone and a port (CC 0x0C, 0x2C).
+    HuiLed {
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        zone: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        port: Vec<Number>,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        state: Vec<Number>,
+    },
+    /// The data bytes of a System Exclusive message,
+    /// not including the leading `0xF0` or trailing `0xF7`.
+    SysEx {
+        #[serde(deserialize_with = "deserialize_sysex_data")]
+        data: Vec<SysExByte>,
+    },
+
+    /// An Active Sensing message,
+    /// which some devices expect every 300 ms to know that they're still connected.
+    ActiveSensing,
+
+    /// A MIDI clock tick, 24 of which make a beat.
+    TimingClock,
+
+    /// Starts whatever's following the clock from the beginning.
+    Start,
+
+    /// Starts whatever's following the clock from its song position.
+    Continue,
+
+    /// Stops whatever's following the clock.
+    Stop,
+
+    /// Sets the song position of whatever's following the clock,
+    /// in 16th notes (i.e. 6 clock ticks) from the beginning.
+    SongPosition {
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        position: Vec<Number>,
+    },
+
+    /// Any of the messages which keep a device in sync with a clock
+    /// ([`TimingClock`](MessageTemplate::TimingClock), `Start`, `Continue`, `Stop`
+    /// and `SongPosition`),
+    /// so that one mapping can pass them all on.
+    Sync,
+}
+
+/// One part of the data in a [`MessageTemplate::SysEx`].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SysExByte {
+    /// A literal byte, e.g. `0x12`.
+    Byte(u8),
+
+    /// A string of ASCII characters, e.g. `{text: Hello}`,
+    /// which can be formatted from the fields of the matched message,
+    /// e.g. `{text: "Ch {channel+1}", width: 7, align: Right}`.
+    /// See [`Format`] for the syntax.
+    ///
+    /// When matching, text with placeholders matches any `width` bytes
+    /// (or the rest of the message, if there's no `width`).
+    Text {
+        text: Format,
+        /// Pads or truncates the text to this many characters.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        width: Option<usize>,
+        #[serde(default)]
+        align: Align,
+        #[serde(default)]
+        charset: Charset,
+        /// Tables for showing fields in real units, e.g. `{value_db}`,
+        /// taken from the control which the message came from.
+        #[serde(skip)]
+        units: HashMap<String, Table>,
+    },
+
+    /// A byte which is a field of the message,
+    /// e.g. `{field: strip, value: 0-7}`.
+    Field {
+        field: String,
+        #[serde(default = "Number::default_vec")]
+        #[serde_as(as = "OneOrMany<_>")]
+        value: Vec<Number>,
+    },
+}
+
+/// Deserializes the data of a [`MessageTemplate::SysEx`],
+/// rejecting any byte which isn't a data byte (i.e. more than `0x7F`).
+fn deserialize_sysex_data<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<SysExByte>, D::Error> {
+    let data = Vec::<SysExByte>::deserialize(deserializer)?;
+    for element in data.iter() {
+        if let SysExByte::Byte(byte @ 0x80..) = element {
+            return Err(de::Error::custom(format!(
+                "SysEx data can't have byte {byte:#04X}, which is more than 0x7F"
+            )));
+        }
+    }
+    Ok(data)
+}
+
+/// Matches the data bytes of a SysEx message against a [`MessageTemplate::SysEx`].
+fn matches_sysex(template: &[SysExByte], mut bytes: &[u8]) -> Option<Match> {
+    let mut mat = Match::new();
+
+    for element in template {
+        match element {
+            SysExByte::Byte(byte) => {
+                let (first, rest) = bytes.split_first()?;
+                if first != byte {
+                    return None;
+                }
+                bytes = rest;
+            }
+            SysExByte::Text {
+                text,
+                width,
+                align,
+                charset,
+                ..
+            } => {
+                if text.is_literal() {
+                    let expected = text::fit(&text.to_string(), *width, *align, *charset);
+                    bytes = bytes.strip_prefix(expected.as_bytes())?;
+                } else {
+                    bytes = bytes.get(width.unwrap_or(bytes.len())..)?;
+                }
+            }
+            SysExByte::Field { field, value } => {
+                let (first, rest) = bytes.split_first()?;
+                mat.insert(field.clone(), message::matches_many(value, *first as u32)?);
+                bytes = rest;
+            }
+        }
+    }
+
+    if bytes.is_empty() {
+        Some(mat)
+    } else {
+        None
+    }
 }
 
 impl Template for MessageTemplate {
-    type Message = LiveEvent<'static>;
+    type Message = Event;
+    type State = State;
 
     /// Checks if the given message matches the template,
     /// and if it does,
     /// returns a [`Match`] describing the qualities of the match.
-    fn matches(&self, live_event: LiveEvent<'static>) -> Option<Match> {
-        let (channel, message) = match_ok!(
-            live_event,
-            LiveEvent::Midi {
-                channel: _0,
-                message: _1
+    fn matches(&self, state: &mut State, event: Event) -> Option<Match> {
+        let (channel, message) = match event.live_event()? {
+            LiveEvent::Midi { channel, message } => (channel, message),
+            LiveEvent::Common(SystemCommon::SysEx(bytes)) => {
+                let data = match_ok!(self, MessageTemplate::SysEx { data })?;
+                return matches_sysex(data, u7::slice_as_int(bytes));
+            }
+            LiveEvent::Common(SystemCommon::SongPosition(position)) => {
+                let position = position.as_int() as u32;
+                return match self {
+                    MessageTemplate::SongPosition { position: template } => {
+                        Some(Match::from_iter([(
+                            "position".to_string(),
+                            message::matches_many(template, position)?,
+                        )]))
+                    }
+                    MessageTemplate::Sync => Some(Match::from_iter([
+                        (
+                            "status".to_string(),
+                            (0, NumberMatch::Value(SONG_POSITION.into())),
+                        ),
+                        ("position".to_string(), (0, NumberMatch::Value(position))),
+                    ])),
+                    _ => None,
+                };
+            }
+            LiveEvent::Realtime(realtime) => {
+                use SystemRealtime::*;
+                return match (self, realtime) {
+                    (MessageTemplate::ActiveSensing, ActiveSensing)
+                    | (MessageTemplate::TimingClock, TimingClock)
+                    | (MessageTemplate::Start, Start)
+                    | (MessageTemplate::Continue, Continue)
+                    | (MessageTemplate::Stop, Stop) => Some(Match::new()),
+                    (MessageTemplate::Sync, TimingClock | Start | Continue | Stop) => {
+                        Some(Match::from_iter([(
+                            "status".to_string(),
+                            (0, NumberMatch::Value(realtime.encode().into())),
+                        )]))
+                    }
+                    _ => None,
+                };
             }
-        )?;
+            _ => return None,
+        };
+
+        // Multi-part messages
+        let completed = state.update(channel, &message);
+        match self {
+            MessageTemplate::Nrpn {
+                channel: channel_template,
+                parameter,
+                value,
+            } => {
+                let nrpn = completed.nrpn?;
+                let channel = message::matches_many(channel_template, channel.as_int() as u32)?;
+                let parameter = message::matches_many(parameter, nrpn.parameter as u32)?;
+                let value = message::matches_many(value, nrpn.value as u32)?;
+
+                return Some(Match::from_iter([
+                    ("channel".to_string(), channel),
+                    ("parameter".to_string(), parameter),
+                    ("value".to_string(), value),
+                ]));
+            }
+
+            MessageTemplate::ControlChange14 {
+                channel: channel_template,
+                controller,
+                value,
+            } => {
+                let controller_14 = completed.controller_14?;
+                let channel = message::matches_many(channel_template, channel.as_int() as u32)?;
+                let controller =
+                    message::matches_many(controller, controller_14.controller as u32)?;
+                let value = message::matches_many(value, controller_14.value as u32)?;
+
+                return Some(Match::from_iter([
+                    ("channel".to_string(), channel),
+                    ("controller".to_string(), controller),
+                    ("value".to_string(), value),
+                ]));
+            }
+
+            MessageTemplate::HuiSwitch {
+                zone,
+                port,
+                state: state_template,
+            }
+            | MessageTemplate::HuiLed {
+                zone,
+                port,
+                state: state_template,
+            } => {
+                let hui_port = if matches!(self, MessageTemplate::HuiSwitch { .. }) {
+                    completed.hui_switch?
+                } else {
+                    completed.hui_led?
+                };
+                let zone = message::matches_many(zone, hui_port.zone as u32)?;
+                let port = message::matches_many(port, hui_port.port as u32)?;
+                let state = message::matches_many(state_template, hui_port.on as u32)?;
+
+                return Some(Match::from_iter([
+                    ("zone".to_string(), zone),
+                    ("port".to_string(), port),
+                    ("state".to_string(), state),
+                ]));
+            }
+
+            _ => (),
+        }
 
         match message {
             MidiMessage::NoteOn { key, vel } => {
@@ -177,15 +491,31 @@ impl Template for MessageTemplate {
             }
 
             MidiMessage::ProgramChange { program } => {
-                let template =
-                    match_ok!(self, MessageTemplate::ProgramChange { channel, program })?;
+                let template = match_ok!(
+                    self,
+                    MessageTemplate::ProgramChange {
+                        channel,
+                        program,
+                        bank
+                    }
+                )?;
+                let bank_match = if template.bank.is_empty() {
+                    None
+                } else {
+                    let bank = state.bank(channel)?;
+                    Some(message::matches_many(template.bank, bank as u32)?)
+                };
                 let channel = message::matches_many(template.channel, channel.as_int() as u32)?;
                 let program = message::matches_many(template.program, program.as_int() as u32)?;
 
-                Some(Match::from_iter([
+                let mut mat = Match::from_iter([
                     ("channel".to_string(), channel),
                     ("program".to_string(), program),
-                ]))
+                ]);
+                if let Some(bank) = bank_match {
+                    mat.insert("bank".to_string(), bank);
+                }
+                Some(mat)
             }
 
             MidiMessage::ChannelAftertouch { vel } => {
@@ -215,30 +545,24 @@ impl Template for MessageTemplate {
 
     /// Given the qualities of a matched message,
     /// generates the appropriate output message.
-    fn generate(&self, mut matched: Match) -> Option<LiveEvent<'static>> {
-        match self {
+    fn generate(&self, mut matched: Match) -> Option<Vec<Event>> {
+        let live_event = match self {
             MessageTemplate::NoteOn {
                 channel,
                 note,
                 velocity,
             } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (note_ix, note_match) = matched.remove("note")?;
-                let (velocity_ix, velocity_match) = matched.remove("velocity")?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let key = message::generate_many(note, matched.remove("note"))?;
+                let vel = message::generate_many(velocity, matched.remove("velocity"))?;
 
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let key = note.get(note_ix as usize)?.generate(note_match)?;
-                let vel = velocity
-                    .get(velocity_ix as usize)?
-                    .generate(velocity_match)?;
-
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::NoteOn {
                         key: (key as u8).into(),
                         vel: (vel as u8).into(),
                     },
-                })
+                }
             }
 
             MessageTemplate::NoteOff {
@@ -246,23 +570,17 @@ impl Template for MessageTemplate {
                 note,
                 velocity,
             } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (note_ix, note_match) = matched.remove("note")?;
-                let (velocity_ix, velocity_match) = matched.remove("velocity")?;
-
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let key = note.get(note_ix as usize)?.generate(note_match)?;
-                let vel = velocity
-                    .get(velocity_ix as usize)?
-                    .generate(velocity_match)?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let key = message::generate_many(note, matched.remove("note"))?;
+                let vel = message::generate_many(velocity, matched.remove("velocity"))?;
 
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::NoteOff {
                         key: (key as u8).into(),
                         vel: (vel as u8).into(),
                     },
-                })
+                }
             }
 
             MessageTemplate::ControlChange {
@@ -270,23 +588,17 @@ impl Template for MessageTemplate {
                 controller,
                 value,
             } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (controller_ix, controller_match) = matched.remove("controller")?;
-                let (value_ix, value_match) = matched.remove("value")?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let controller = message::generate_many(controller, matched.remove("controller"))?;
+                let value = message::generate_many(value, matched.remove("value"))?;
 
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let controller = controller
-                    .get(controller_ix as usize)?
-                    .generate(controller_match)?;
-                let value = value.get(value_ix as usize)?.generate(value_match)?;
-
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::Controller {
                         controller: (controller as u8).into(),
                         value: (value as u8).into(),
                     },
-                })
+                }
             }
 
             MessageTemplate::PolyPressure {
@@ -294,67 +606,267 @@ impl Template for MessageTemplate {
                 note,
                 pressure,
             } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (note_ix, note_match) = matched.remove("note")?;
-                let (pressure_ix, pressure_match) = matched.remove("pressure")?;
-
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let key = note.get(note_ix as usize)?.generate(note_match)?;
-                let vel = pressure
-                    .get(pressure_ix as usize)?
-                    .generate(pressure_match)?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let key = message::generate_many(note, matched.remove("note"))?;
+                let vel = message::generate_many(pressure, matched.remove("pressure"))?;
 
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::Aftertouch {
                         key: (key as u8).into(),
                         vel: (vel as u8).into(),
                     },
-                })
+                }
             }
-            MessageTemplate::ProgramChange { channel, program } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (program_ix, program_match) = matched.remove("program")?;
-
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let program = program.get(program_ix as usize)?.generate(program_match)?;
+            MessageTemplate::ProgramChange {
+                channel,
+                program,
+                bank,
+            } => {
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let program = message::generate_many(program, matched.remove("program"))?;
 
-                Some(LiveEvent::Midi {
+                let program_change = LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::ProgramChange {
                         program: (program as u8).into(),
                     },
-                })
+                };
+
+                if bank.is_empty() {
+                    program_change
+                } else {
+                    let bank = message::generate_many(bank, matched.remove("bank"))?;
+                    let mut events = controllers(channel, [state::bank_select(bank as u8)]);
+                    events.push(program_change.into());
+                    return Some(events);
+                }
             }
             MessageTemplate::ChannelPressure { channel, pressure } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (pressure_ix, pressure_match) = matched.remove("pressure")?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let vel = message::generate_many(pressure, matched.remove("pressure"))?;
 
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let vel = pressure
-                    .get(pressure_ix as usize)?
-                    .generate(pressure_match)?;
-
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::ChannelAftertouch {
                         vel: (vel as u8).into(),
                     },
-                })
+                }
             }
             MessageTemplate::PitchBend { channel, bend } => {
-                let (channel_ix, channel_match) = matched.remove("channel")?;
-                let (bend_ix, bend_match) = matched.remove("bend")?;
-
-                let channel = channel.get(channel_ix as usize)?.generate(channel_match)?;
-                let bend = bend.get(bend_ix as usize)?.generate(bend_match)?;
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let bend = message::generate_many(bend, matched.remove("bend"))?;
 
-                Some(LiveEvent::Midi {
+                LiveEvent::Midi {
                     channel: (channel as u8).into(),
                     message: MidiMessage::PitchBend {
                         bend: midly::PitchBend((bend as u16).into()),
                     },
-                })
+                }
+            }
+            MessageTemplate::ActiveSensing => LiveEvent::Realtime(SystemRealtime::ActiveSensing),
+            MessageTemplate::TimingClock => LiveEvent::Realtime(SystemRealtime::TimingClock),
+            MessageTemplate::Start => LiveEvent::Realtime(SystemRealtime::Start),
+            MessageTemplate::Continue => LiveEvent::Realtime(SystemRealtime::Continue),
+            MessageTemplate::Stop => LiveEvent::Realtime(SystemRealtime::Stop),
+            MessageTemplate::SongPosition { position } => {
+                let position = message::generate_many(position, matched.remove("position"))?;
+                LiveEvent::Common(SystemCommon::SongPosition((position as u16).into()))
+            }
+            MessageTemplate::Sync => {
+                // Passes on the message which was matched by another `Sync`
+                let status = message::generate_many(&[Number::Any], matched.remove("status"))?;
+                match status as u8 {
+                    SONG_POSITION => {
+                        let position =
+                            message::generate_many(&[Number::Any], matched.remove("position"))?;
+                        LiveEvent::Common(SystemCommon::SongPosition((position as u16).into()))
+                    }
+                    status => match SystemRealtime::new(status) {
+                        realtime @ (SystemRealtime::TimingClock
+                        | SystemRealtime::Start
+                        | SystemRealtime::Continue
+                        | SystemRealtime::Stop) => LiveEvent::Realtime(realtime),
+                        _ => return None,
+                    },
+                }
+            }
+            MessageTemplate::SysEx { data } => {
+                // Text can use any of the fields,
+                // including those which are also used for bytes
+                let fields = matched.clone();
+                let mut bytes = Vec::new();
+                for element in data.iter() {
+                    match element {
+                        SysExByte::Byte(byte) => bytes.push(*byte),
+                        SysExByte::Text {
+                            text,
+                            width,
+                            align,
+                            charset,
+                            units,
+                        } => {
+                            let rendered = text.render(&fields, units);
+                            bytes.extend(text::fit(&rendered, *width, *align, *charset).bytes());
+                        }
+                        SysExByte::Field { field, value } => {
+                            let byte = message::generate_many(value, matched.remove(field))?;
+                            bytes.push(u8::try_from(byte).ok()?);
+                        }
+                    }
+                }
+
+                // Any byte more than 0x7F can't be sent
+                return Some(vec![Event::sysex(&bytes)?]);
+            }
+            MessageTemplate::Nrpn {
+                channel,
+                parameter,
+                value,
+            } => {
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let parameter = message::generate_many(parameter, matched.remove("parameter"))?;
+                let value = message::generate_many(value, matched.remove("value"))?;
+
+                let nrpn = Nrpn {
+                    parameter: parameter as u16,
+                    value: value as u16,
+                };
+                return Some(controllers(channel, nrpn.controllers()));
+            }
+            MessageTemplate::ControlChange14 {
+                channel,
+                controller,
+                value,
+            } => {
+                let channel = message::generate_many(channel, matched.remove("channel"))?;
+                let controller = message::generate_many(controller, matched.remove("controller"))?;
+                let value = message::generate_many(value, matched.remove("value"))?;
+
+                let controller_14 = Controller14 {
+                    controller: controller as u8,
+                    value: value as u16,
+                };
+                return Some(controllers(channel, controller_14.controllers()));
+            }
+            MessageTemplate::HuiSwitch { zone, port, state }
+            | MessageTemplate::HuiLed { zone, port, state } => {
+                let zone = message::generate_many(zone, matched.remove("zone"))?;
+                let port = message::generate_many(port, matched.remove("port"))?;
+                let state = message::generate_many(state, matched.remove("state"))?;
+
+                let hui_port = HuiPort {
+                    zone: zone as u8,
+                    port: port as u8,
+                    on: state != 0,
+                };
+                return Some(if matches!(self, MessageTemplate::HuiSwitch { .. }) {
+                    controllers(0, hui_port.switch_controllers())
+                } else {
+                    controllers(0, hui_port.led_controllers())
+                });
+            }
+        };
+
+        Some(vec![Event::from(live_event)])
+    }
+}
+
+/// Makes a control change message for each `(controller, value)` pair.
+fn controllers<I>(channel: u32, controllers: I) -> Vec<Event>
+where
+    I: IntoIterator<Item = (u8, u8)>,
+{
+    controllers
+        .into_iter()
+        .map(|(controller, value)| {
+            Event::from(LiveEvent::Midi {
+                channel: (channel as u8).into(),
+                message: MidiMessage::Controller {
+                    controller: controller.into(),
+                    value: value.into(),
+                },
+            })
+        })
+        .collect()
+}
+
+impl MessageTemplate {
+    /// Returns the [`Number`]s for the named field of the template,
+    /// or `None` if this kind of message has no such field.
+    pub fn field_mut(&mut self, field: &str) -> Option<&mut Vec<Number>> {
+        use MessageTemplate::*;
+        match (self, field) {
+            (
+                NoteOn { channel, .. }
+                | NoteOff { channel, .. }
+                | ControlChange { channel, .. }
+                | ProgramChange { channel, .. }
+                | PolyPressure { channel, .. }
+                | ChannelPressure { channel, .. }
+                | PitchBend { channel, .. }
+                | Nrpn { channel, .. }
+                | ControlChange14 { channel, .. },
+                "channel",
+            ) => Some(channel),
+            (
+                NoteOn { note, .. } | NoteOff { note, .. } | PolyPressure { note, .. },
+                "note",
+            ) => Some(note),
+            (NoteOn { velocity, .. } | NoteOff { velocity, .. }, "velocity") => Some(velocity),
+            (
+                ControlChange { controller, .. } | ControlChange14 { controller, .. },
+                "controller",
+            ) => Some(controller),
+            (
+                ControlChange { value, .. } | Nrpn { value, .. } | ControlChange14 { value, .. },
+                "value",
+            ) => Some(value),
+            (HuiSwitch { zone, .. } | HuiLed { zone, .. }, "zone") => Some(zone),
+            (HuiSwitch { port, .. } | HuiLed { port, .. }, "port") => Some(port),
+            (HuiSwitch { state, .. } | HuiLed { state, .. }, "state") => Some(state),
+            (ProgramChange { program, .. }, "program") => Some(program),
+            (ProgramChange { bank, .. }, "bank") => Some(bank),
+            (Nrpn { parameter, .. }, "parameter") => Some(parameter),
+            (PolyPressure { pressure, .. } | ChannelPressure { pressure, .. }, "pressure") => {
+                Some(pressure)
+            }
+            (PitchBend { bend, .. }, "bend") => Some(bend),
+            (SongPosition { position }, "position") => Some(position),
+            (SysEx { data }, field) => data.iter_mut().find_map(|element| match element {
+                SysExByte::Field {
+                    field: name, value, ..
+                } if name == field => Some(value),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Replaces the format of the template's text,
+    /// or returns `None` if the template has no text.
+    pub fn set_text(&mut self, format: Format) -> Option<()> {
+        let MessageTemplate::SysEx { data } = self else {
+            return None;
+        };
+        let mut found = None;
+        for element in data.iter_mut() {
+            if let SysExByte::Text { text, .. } = element {
+                *text = format.clone();
+                found = Some(());
+            }
+        }
+        found
+    }
+
+    /// Gives the template's text the tables to use
+    /// for showing fields in real units.
+    pub fn set_units(&mut self, tables: &HashMap<String, Table>) {
+        if let MessageTemplate::SysEx { data } = self {
+            for element in data.iter_mut() {
+                if let SysExByte::Text { units, .. } = element {
+                    *units = tables.clone();
+                }
             }
         }
     }
@@ -1,4 +1,17 @@
 pub mod device;
 
+pub mod event;
+pub use event::Event;
+
+pub mod mackie;
+
 pub mod message_template;
 pub use message_template::MessageTemplate;
+
+pub mod playback;
+
+pub mod port;
+
+pub mod state;
+
+pub mod stream;
@@ -0,0 +1,388 @@
+//! Replaying a Standard MIDI File as if it were a device,
+//! e.g. one recorded with `gobetween record`.
+
+use std::{fs, path::Path, time::Duration};
+
+use midly::{live::LiveEvent, MetaMessage, Smf, Timing, Track, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+
+use crate::{
+    device::{self, Transport},
+    midi::{device::Error, Event},
+};
+
+/// The tempo of a file which doesn't give one, in microseconds per beat (i.e. 120 bpm).
+const DEFAULT_TEMPO: u32 = 500_000;
+
+/// How quickly to play the file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Speed {
+    /// With the timing in the file.
+    #[default]
+    Original,
+
+    /// As fast as possible.
+    Fast,
+}
+
+/// Plays the messages in a MIDI file (or one of its tracks),
+/// as if they came from a device.
+///
+/// Messages sent to the device are dropped.
+/// Unless it loops, the device disconnects at the end of the file.
+pub struct Playback {
+    file: String,
+    events: Vec<(Duration, Event)>,
+    speed: Speed,
+    looped: bool,
+
+    /// The index of the next event to play.
+    next: usize,
+
+    /// When the file started playing (this time round).
+    start: Instant,
+}
+
+impl Playback {
+    /// Reads the file, ready to be played.
+    pub fn new(
+        path: &Path,
+        track: Option<&str>,
+        speed: Speed,
+        looped: bool,
+    ) -> Result<Self, Error> {
+        Ok(Playback {
+            file: path.display().to_string(),
+            events: load(path, track)?,
+            speed,
+            looped,
+            next: 0,
+            start: Instant::now(),
+        })
+    }
+}
+
+impl Transport for Playback {
+    type Message = Event;
+
+    async fn open(&mut self) -> Result<(), device::Error> {
+        log::info!("Playing `{}`", self.file);
+        self.start = Instant::now();
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+        if self.next == self.events.len() {
+            if !self.looped || self.events.is_empty() {
+                log::info!("Playback of `{}` finished", self.file);
+                return Ok(None);
+            }
+            self.next = 0;
+            self.start = Instant::now();
+        }
+
+        let (time, event) = &self.events[self.next];
+        match self.speed {
+            Speed::Original => time::sleep_until(self.start + *time).await,
+            // Give the subscribers a chance to keep up
+            Speed::Fast => tokio::task::yield_now().await,
+        }
+        // Only moves on once the event is played,
+        // in case this is cancelled while waiting
+        self.next += 1;
+        Ok(Some(vec![event.clone()]))
+    }
+
+    async fn write(&mut self, event: Event) -> Result<(), device::Error> {
+        log::trace!(
+            "Dropping message for playback of `{}`: {event:?}",
+            self.file
+        );
+        Ok(())
+    }
+}
+
+/// Reads the messages in a MIDI file,
+/// with the time of each from the start of the file.
+///
+/// If a track is named, only its messages are played,
+/// otherwise the messages of every track are merged together.
+/// Meta messages are skipped, except for tempo changes, which are obeyed.
+pub fn load(path: &Path, track: Option<&str>) -> Result<Vec<(Duration, Event)>, Error> {
+    let bytes = fs::read(path)?;
+    let smf = Smf::parse(&bytes)?;
+
+    let tracks: Vec<&Track> = match track {
+        None => smf.tracks.iter().collect(),
+        Some(wanted) => {
+            let found: Vec<&Track> = smf
+                .tracks
+                .iter()
+                .filter(|track| track_name(track) == Some(wanted))
+                .collect();
+            if found.is_empty() {
+                return Err(Error::NoSuchTrack(
+                    wanted.to_string(),
+                    path.display().to_string(),
+                    smf.tracks
+                        .iter()
+                        .filter_map(|track| track_name(track).map(str::to_string))
+                        .collect(),
+                ));
+            }
+            found
+        }
+    };
+
+    // Tempo changes apply to every track, wherever they are
+    // @Todo: in a sequential file, each track should have its own tempo
+    let mut tempos: Vec<(u64, u32)> = smf
+        .tracks
+        .iter()
+        .flat_map(|track| absolute(track))
+        .filter_map(|(tick, kind)| match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => Some((tick, tempo.as_int())),
+            _ => None,
+        })
+        .collect();
+    tempos.sort_by_key(|(tick, _)| *tick);
+
+    let mut events: Vec<(Duration, Event)> = tracks
+        .into_iter()
+        .flat_map(|track| absolute(track))
+        .filter_map(|(tick, kind)| {
+            let live_event = match kind {
+                // Escapes are how system common and realtime messages are stored
+                TrackEventKind::Escape(bytes) => LiveEvent::parse(bytes).ok(),
+                kind => kind.as_live_event(),
+            }?;
+            Some((
+                to_duration(smf.header.timing, &tempos, tick),
+                Event::from(live_event),
+            ))
+        })
+        .collect();
+    // Stable, so messages at the same time stay in order
+    events.sort_by_key(|(time, _)| *time);
+
+    Ok(events)
+}
+
+/// The name of a track, from its first `TrackName` meta message.
+fn track_name<'a>(track: &[TrackEvent<'a>]) -> Option<&'a str> {
+    track.iter().find_map(|event| match event.kind {
+        TrackEventKind::Meta(MetaMessage::TrackName(name)) => std::str::from_utf8(name).ok(),
+        _ => None,
+    })
+}
+
+/// The events of a track, with the number of ticks from the start of the track.
+fn absolute<'a, 'b>(
+    track: &'b [TrackEvent<'a>],
+) -> impl Iterator<Item = (u64, TrackEventKind<'a>)> + 'b {
+    track.iter().scan(0, |tick: &mut u64, event| {
+        *tick += u64::from(event.delta.as_int());
+        Some((*tick, event.kind))
+    })
+}
+
+/// Converts a number of ticks from the start of the file into time,
+/// given the tempo changes (in ticks, microseconds per beat), in order.
+fn to_duration(timing: Timing, tempos: &[(u64, u32)], tick: u64) -> Duration {
+    match timing {
+        Timing::Metrical(ticks_per_beat) => {
+            let ticks_per_beat = u64::from(ticks_per_beat.as_int().max(1));
+            let mut micros = 0;
+            let mut last = (0, DEFAULT_TEMPO);
+            for &(change, tempo) in tempos.iter().take_while(|(change, _)| *change < tick) {
+                micros += (change - last.0) * u64::from(last.1) / ticks_per_beat;
+                last = (change, tempo);
+            }
+            micros += (tick - last.0) * u64::from(last.1) / ticks_per_beat;
+            Duration::from_micros(micros)
+        }
+        Timing::Timecode(fps, subframes) => {
+            let ticks_per_second = fps.as_f32() * f32::from(subframes.max(1));
+            Duration::from_secs_f64(tick as f64 / f64::from(ticks_per_second))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use midly::{
+        num::{u15, u24, u4},
+        Format, Fps, Header, MidiMessage,
+    };
+
+    use super::*;
+
+    #[test]
+    fn follows_tempo_changes() {
+        let timing = Timing::Metrical(u15::new(480));
+        let at = |tempos: &[(u64, u32)], tick| to_duration(timing, tempos, tick);
+
+        // 120 bpm until told otherwise
+        assert_eq!(at(&[], 480), Duration::from_millis(500));
+        assert_eq!(at(&[], 1440), Duration::from_millis(1500));
+
+        // Halves the length of each beat after the second
+        let tempos = [(960, 250_000)];
+        assert_eq!(at(&tempos, 480), Duration::from_millis(500));
+        assert_eq!(at(&tempos, 960), Duration::from_millis(1000));
+        assert_eq!(at(&tempos, 1440), Duration::from_millis(1250));
+
+        // From the very start, and more than once
+        let tempos = [(0, 1_000_000), (480, 500_000), (720, 2_000_000)];
+        assert_eq!(at(&tempos, 0), Duration::ZERO);
+        assert_eq!(at(&tempos, 480), Duration::from_millis(1000));
+        assert_eq!(at(&tempos, 720), Duration::from_millis(1250));
+        assert_eq!(at(&tempos, 960), Duration::from_millis(2250));
+    }
+
+    #[test]
+    fn follows_timecode() {
+        let timing = Timing::Timecode(Fps::Fps25, 40);
+        assert_eq!(to_duration(timing, &[], 1500), Duration::from_millis(1500));
+        // Which ignores the tempo
+        let tempos = [(0, 250_000)];
+        assert_eq!(
+            to_duration(timing, &tempos, 1500),
+            Duration::from_millis(1500)
+        );
+
+        let timing = Timing::Timecode(Fps::Fps30, 80);
+        assert_eq!(to_duration(timing, &[], 1200), Duration::from_millis(500));
+    }
+
+    fn note_on(key: u8) -> TrackEventKind<'static> {
+        TrackEventKind::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 127.into(),
+            },
+        }
+    }
+
+    fn event(delta: u32, kind: TrackEventKind) -> TrackEvent {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    /// Saves a file with a track of tempo changes (60 bpm, then 120 bpm after a beat),
+    /// and a track of notes, a beat apart.
+    fn save_file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "gobetween-playback-{name}-{}.mid",
+            std::process::id()
+        ));
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Metrical(u15::new(96))),
+            tracks: vec![
+                vec![
+                    event(0, TrackEventKind::Meta(MetaMessage::TrackName(b"tempo"))),
+                    event(
+                        0,
+                        TrackEventKind::Meta(MetaMessage::Tempo(u24::new(1_000_000))),
+                    ),
+                    event(
+                        96,
+                        TrackEventKind::Meta(MetaMessage::Tempo(u24::new(500_000))),
+                    ),
+                    event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+                ],
+                vec![
+                    event(0, TrackEventKind::Meta(MetaMessage::TrackName(b"notes"))),
+                    event(0, note_on(60)),
+                    event(96, note_on(62)),
+                    event(96, note_on(64)),
+                    event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+                ],
+            ],
+        };
+        smf.save(&path).unwrap();
+        path
+    }
+
+    fn notes() -> Vec<(Duration, Event)> {
+        [(0, 60), (1000, 62), (1500, 64)]
+            .into_iter()
+            .map(|(millis, key)| {
+                let event = Event::from(note_on(key).as_live_event().unwrap());
+                (Duration::from_millis(millis), event)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loads_tracks() {
+        let path = save_file("tracks");
+        let all = load(&path, None);
+        let notes_track = load(&path, Some("notes"));
+        let tempo_track = load(&path, Some("tempo"));
+        let missing = load(&path, Some("drums"));
+        let _ = fs::remove_file(&path);
+
+        // Tempo changes from every track apply, but aren't played
+        assert_eq!(all.unwrap(), notes());
+        assert_eq!(notes_track.unwrap(), notes());
+        assert_eq!(tempo_track.unwrap(), []);
+        match missing {
+            Err(Error::NoSuchTrack(wanted, _, tracks)) => {
+                assert_eq!(wanted, "drums");
+                assert_eq!(tracks, ["tempo", "notes"]);
+            }
+            other => panic!("expected NoSuchTrack, got {other:?}"),
+        }
+    }
+
+    /// Plays the file, and returns when each message is played.
+    async fn play(looped: bool, count: usize) -> Vec<(Duration, Option<Vec<Event>>)> {
+        let path = save_file(if looped { "looped" } else { "once" });
+        let mut playback = Playback::new(&path, None, Speed::Original, looped).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let start = Instant::now();
+        playback.open().await.unwrap();
+        let mut played = Vec::new();
+        for _ in 0..count {
+            let events = playback.read().await.unwrap();
+            played.push((start.elapsed(), events));
+        }
+        played
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn finishes_at_the_end() {
+        let mut expected: Vec<_> = notes()
+            .into_iter()
+            .map(|(time, event)| (time, Some(vec![event])))
+            .collect();
+        expected.push((Duration::from_millis(1500), None));
+        assert_eq!(play(false, 4).await, expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loops_from_the_end() {
+        let expected: Vec<_> = [notes(), notes()]
+            .into_iter()
+            .enumerate()
+            .flat_map(|(i, notes)| {
+                notes.into_iter().map(move |(time, event)| {
+                    (
+                        time + Duration::from_millis(1500) * i as u32,
+                        Some(vec![event]),
+                    )
+                })
+            })
+            .collect();
+        assert_eq!(play(true, 6).await, expected);
+    }
+}
@@ -0,0 +1,565 @@
+//! Finding native MIDI ports by name.
+
+use std::{fmt, str::FromStr};
+
+use midir::{MidiIO, MidiInput, MidiOutput};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+
+use crate::midi::device::Error;
+
+/// The native MIDI ports available on this machine.
+#[derive(Serialize, Debug, Clone)]
+pub struct Ports {
+    pub inputs: Vec<Port>,
+    pub outputs: Vec<Port>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Port {
+    /// The position of the port in the system's list of ports.
+    pub index: usize,
+
+    /// The name of the port, as used for `midi_in` and `midi_out`.
+    pub name: String,
+}
+
+impl Ports {
+    /// Lists the native MIDI input and output ports.
+    pub fn list() -> Result<Self, Error> {
+        let input = MidiInput::new("gobetween")?;
+        let output = MidiOutput::new("gobetween")?;
+
+        Ok(Ports {
+            inputs: list(&input)?,
+            outputs: list(&output)?,
+        })
+    }
+}
+
+fn list<IO: MidiIO>(io: &IO) -> Result<Vec<Port>, Error> {
+    io.ports()
+        .iter()
+        .enumerate()
+        .map(|(index, port)| {
+            Ok(Port {
+                index,
+                name: io.port_name(port)?,
+            })
+        })
+        .collect()
+}
+
+/// Chooses a native MIDI port by its name.
+///
+/// Either the exact name of the port, e.g. `PreSonus FP8`,
+/// or a pattern, e.g. `{glob: "*FP8*"}` or `{regex: "FP8( #\d+)?$"}`,
+/// with an optional `occurrence` to choose between several matching ports,
+/// e.g. `{name: PreSonus FP8, occurrence: 2}`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PortMatcher {
+    Name(String),
+    Pattern {
+        #[serde(flatten)]
+        pattern: Pattern,
+
+        /// Which of the matching ports to use, counting from 1,
+        /// in the order the system lists them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        occurrence: Option<usize>,
+    },
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    /// The exact name of the port.
+    Name(String),
+
+    /// A regular expression which matches (part of) the name of the port.
+    Regex(#[serde_as(as = "DisplayFromStr")] Regex),
+
+    /// A glob which matches the whole name of the port,
+    /// where `*` matches any characters, and `?` matches any one character.
+    Glob(#[serde_as(as = "DisplayFromStr")] Glob),
+}
+
+/// A glob, e.g. `*FP8*`,
+/// which is turned into a regex once, when it's parsed.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    glob: String,
+    regex: Regex,
+}
+
+impl PortMatcher {
+    /// Finds the port matching this, in a list of `(port, name)` pairs.
+    ///
+    /// Unless an `occurrence` is given,
+    /// exactly one port must match a glob or regex.
+    /// If several ports have the exact name, the first is used.
+    pub fn find<'a, P>(
+        &self,
+        direction: &'static str,
+        ports: &'a [(P, String)],
+    ) -> Result<&'a P, Error> {
+        let (pattern, occurrence) = match self {
+            PortMatcher::Name(name) => (Pattern::Name(name.clone()), None),
+            PortMatcher::Pattern {
+                pattern,
+                occurrence,
+            } => (pattern.clone(), *occurrence),
+        };
+
+        let matching: Vec<&(P, String)> = ports
+            .iter()
+            .filter(|(_, name)| pattern.matches(name))
+            .collect();
+        let names = |ports: &[&(P, String)]| ports.iter().map(|(_, name)| name.clone()).collect();
+
+        match (occurrence, &matching[..]) {
+            (_, []) => Err(Error::NoMatchingPort(
+                direction,
+                self.to_string(),
+                ports.iter().map(|(_, name)| name.clone()).collect(),
+            )),
+            (None, [(port, _)]) => Ok(port),
+            (None, [(port, _), ..]) if matches!(pattern, Pattern::Name(_)) => Ok(port),
+            (None, _) => Err(Error::AmbiguousPort(
+                direction,
+                self.to_string(),
+                names(&matching),
+            )),
+            (Some(n), _) => match n.checked_sub(1).and_then(|i| matching.get(i)) {
+                Some((port, _)) => Ok(port),
+                None => Err(Error::NoMatchingPort(
+                    direction,
+                    self.to_string(),
+                    names(&matching),
+                )),
+            },
+        }
+    }
+}
+
+impl Pattern {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Name(exact) => name == exact,
+            Pattern::Regex(regex) => regex.is_match(name),
+            Pattern::Glob(glob) => glob.regex.is_match(name),
+        }
+    }
+}
+
+impl FromStr for Glob {
+    type Err = regex::Error;
+
+    /// Converts the glob into an equivalent regex, anchored at both ends.
+    fn from_str(glob: &str) -> Result<Self, Self::Err> {
+        let mut regex = String::from("^");
+        for c in glob.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+        // Everything except the wildcards was escaped,
+        // so this only fails if the glob is too big
+        Ok(Glob {
+            glob: glob.to_string(),
+            regex: Regex::new(&regex)?,
+        })
+    }
+}
+
+impl fmt::Display for Glob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.glob)
+    }
+}
+
+impl fmt::Display for PortMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortMatcher::Name(name) => write!(f, "`{name}`"),
+            PortMatcher::Pattern {
+                pattern,
+                occurrence,
+            } => {
+                match pattern {
+                    Pattern::Name(name) => write!(f, "`{name}`")?,
+                    Pattern::Regex(regex) => write!(f, "regex `{regex}`")?,
+                    Pattern::Glob(glob) => write!(f, "glob `{glob}`")?,
+                }
+                if let Some(n) = occurrence {
+                    write!(f, " (occurrence {n})")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Lists the names of the native MIDI ports, in the order the system gives them.
+///
+/// This is a trait so that the [`Supervisor`] can be driven by something other than
+/// the real MIDI ports.
+pub trait PortEnumerator {
+    fn inputs(&self) -> Result<Vec<String>, Error>;
+    fn outputs(&self) -> Result<Vec<String>, Error>;
+}
+
+/// Enumerates the real MIDI ports, using midir.
+pub struct MidirPorts {
+    input: MidiInput,
+    output: MidiOutput,
+}
+
+impl MidirPorts {
+    pub fn new(client_name: &str) -> Result<Self, Error> {
+        Ok(MidirPorts {
+            input: MidiInput::new(client_name)?,
+            output: MidiOutput::new(client_name)?,
+        })
+    }
+}
+
+impl PortEnumerator for MidirPorts {
+    fn inputs(&self) -> Result<Vec<String>, Error> {
+        Ok(list(&self.input)?
+            .into_iter()
+            .map(|port| port.name)
+            .collect())
+    }
+
+    fn outputs(&self) -> Result<Vec<String>, Error> {
+        Ok(list(&self.output)?
+            .into_iter()
+            .map(|port| port.name)
+            .collect())
+    }
+}
+
+/// A port chosen by a [`PortMatcher`].
+///
+/// This isn't the port's position in the system's list of ports,
+/// which changes whenever a port before it is plugged in or unplugged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    pub name: String,
+
+    /// Which of the ports with this name it is, counting from 1,
+    /// in the order the system lists them.
+    pub occurrence: usize,
+}
+
+impl Selected {
+    /// Finds the port of a MIDI input or output.
+    pub fn port<IO: MidiIO>(&self, direction: &'static str, io: &IO) -> Result<IO::Port, Error> {
+        io.ports()
+            .into_iter()
+            .filter(|port| io.port_name(port).ok().as_ref() == Some(&self.name))
+            .nth(self.occurrence - 1)
+            .ok_or_else(|| Error::NoMatchingPort(direction, format!("`{}`", self.name), Vec::new()))
+    }
+}
+
+/// What the [`Supervisor`] noticed when it last looked at the ports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The device's ports are present, and should be connected to.
+    Appeared { input: Selected, output: Selected },
+
+    /// The device's ports have gone (or changed), and should be disconnected from.
+    Disappeared,
+}
+
+/// Watches the MIDI ports for the ones which a device wants,
+/// so that the device can be connected whenever they're plugged in.
+pub struct Supervisor<E> {
+    enumerator: E,
+    in_port: PortMatcher,
+    out_port: PortMatcher,
+    connected: Option<(Selected, Selected)>,
+
+    /// The last reason the ports couldn't be found or connected to,
+    /// so that it's only logged when it changes.
+    problem: Option<String>,
+}
+
+impl<E: PortEnumerator> Supervisor<E> {
+    pub fn new(enumerator: E, in_port: PortMatcher, out_port: PortMatcher) -> Self {
+        Supervisor {
+            enumerator,
+            in_port,
+            out_port,
+            connected: None,
+            problem: None,
+        }
+    }
+
+    /// Looks at the ports,
+    /// and returns whether the device should now be connected or disconnected.
+    ///
+    /// If the ports can't be listed (e.g. because one went while they were being listed),
+    /// nothing changes until the next poll, which tries again.
+    pub fn poll(&mut self) -> Option<Change> {
+        let found = match self.select() {
+            Ok(found) => Some(found),
+            Err(err @ Error::NoMatchingPort(..)) => {
+                self.report(format!("{err}; waiting for it to be plugged in"));
+                None
+            }
+            Err(err @ Error::AmbiguousPort(..)) => {
+                self.report(err.to_string());
+                None
+            }
+            Err(err) => {
+                self.report(format!("Couldn't list the MIDI ports: {err}; trying again"));
+                return None;
+            }
+        };
+
+        if found == self.connected {
+            if found.is_some() {
+                // Connected, and still there
+                self.problem = None;
+            }
+            return None;
+        }
+
+        // If the ports have changed while connected,
+        // this is `Appeared` with the new ports,
+        // and the old connection should be replaced.
+        self.connected = found.clone();
+        Some(match found {
+            Some((input, output)) => Change::Appeared { input, output },
+            None => Change::Disappeared,
+        })
+    }
+
+    /// Forgets about the current connection,
+    /// because connecting to the ports (or sending to them) failed,
+    /// so that the next [`poll`](Supervisor::poll) tries again.
+    ///
+    /// The problem is logged, unless it's the same as last time.
+    pub fn reset(&mut self, problem: String) {
+        self.connected = None;
+        self.report(problem);
+    }
+
+    fn report(&mut self, problem: String) {
+        if self.problem.as_ref() != Some(&problem) {
+            log::warn!("{problem}");
+            self.problem = Some(problem);
+        }
+    }
+
+    fn select(&self) -> Result<(Selected, Selected), Error> {
+        let select = |matcher: &PortMatcher, direction, names: Vec<String>| -> Result<_, Error> {
+            let ports: Vec<(usize, String)> = names.into_iter().enumerate().collect();
+            let index = *matcher.find(direction, &ports)?;
+            let name = &ports[index].1;
+            Ok(Selected {
+                name: name.clone(),
+                occurrence: ports[..index]
+                    .iter()
+                    .filter(|(_, other)| other == name)
+                    .count()
+                    + 1,
+            })
+        };
+
+        Ok((
+            select(&self.in_port, "input", self.enumerator.inputs()?)?,
+            select(&self.out_port, "output", self.enumerator.outputs()?)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use super::*;
+
+    fn ports(names: &[&str]) -> Vec<(usize, String)> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (i, name.to_string()))
+            .collect()
+    }
+
+    fn matcher(yaml: &str) -> PortMatcher {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn finds_ports() {
+        let ports = ports(&["Midi Through", "PreSonus FP8", "PreSonus FP8 #2"]);
+        let find = |yaml| matcher(yaml).find("input", &ports).copied();
+
+        assert_eq!(find("PreSonus FP8").unwrap(), 1);
+        assert_eq!(find("{glob: '*FP8 #2'}").unwrap(), 2);
+        assert_eq!(find("{regex: 'FP8', occurrence: 2}").unwrap(), 2);
+        assert!(matches!(
+            find("{glob: '*FP8*'}"),
+            Err(Error::AmbiguousPort(..))
+        ));
+        assert!(matches!(find("FP8"), Err(Error::NoMatchingPort(..))));
+        assert!(matches!(
+            find("{regex: 'FP8', occurrence: 3}"),
+            Err(Error::NoMatchingPort(..))
+        ));
+    }
+
+    #[test]
+    fn parses_patterns_once() {
+        let ports = ports(&["FP8 (1)", "FP8 [2]", "FP8.3"]);
+        let find = |yaml| matcher(yaml).find("input", &ports).copied();
+
+        // Only the wildcards are special in a glob
+        assert_eq!(find("{glob: 'FP8 (?)'}").unwrap(), 0);
+        assert_eq!(find("{glob: 'FP8 [*]'}").unwrap(), 1);
+        assert_eq!(find("{glob: 'FP8.?'}").unwrap(), 2);
+        assert_eq!(find("{glob: 'FP8?3'}").unwrap(), 2);
+        assert_eq!(matcher("{glob: 'FP8 [*]'}").to_string(), "glob `FP8 [*]`");
+
+        // A bad regex is a mistake in the config
+        assert!(serde_yaml::from_str::<PortMatcher>("{regex: 'FP8 ('}").is_err());
+    }
+
+    #[test]
+    fn uses_the_first_port_with_an_exact_name() {
+        let ports = ports(&["FP8", "Midi Through", "FP8"]);
+        assert_eq!(*matcher("FP8").find("input", &ports).unwrap(), 0);
+        assert_eq!(*matcher("{name: FP8}").find("input", &ports).unwrap(), 0);
+        assert_eq!(
+            *matcher("{name: FP8, occurrence: 2}")
+                .find("input", &ports)
+                .unwrap(),
+            2
+        );
+    }
+
+    /// Ports which the test can plug in and unplug,
+    /// or `None` when listing them fails.
+    #[derive(Clone, Default)]
+    struct MockPorts(Rc<RefCell<Option<Vec<String>>>>);
+
+    impl MockPorts {
+        fn set(&self, names: Option<&[&str]>) {
+            *self.0.borrow_mut() = names.map(|names| names.iter().map(|n| n.to_string()).collect());
+        }
+    }
+
+    impl PortEnumerator for MockPorts {
+        fn inputs(&self) -> Result<Vec<String>, Error> {
+            self.0
+                .borrow()
+                .clone()
+                .ok_or_else(|| io::Error::other("port went while listing").into())
+        }
+
+        fn outputs(&self) -> Result<Vec<String>, Error> {
+            self.inputs()
+        }
+    }
+
+    fn appeared(name: &str, occurrence: usize) -> Option<Change> {
+        let selected = Selected {
+            name: name.to_string(),
+            occurrence,
+        };
+        Some(Change::Appeared {
+            input: selected.clone(),
+            output: selected,
+        })
+    }
+
+    #[test]
+    fn supervises_ports() {
+        let ports = MockPorts::default();
+        let fp8 = PortMatcher::Name("FP8".to_string());
+        let mut supervisor = Supervisor::new(ports.clone(), fp8.clone(), fp8);
+
+        // Not plugged in yet
+        ports.set(Some(&["Midi Through"]));
+        assert_eq!(supervisor.poll(), None);
+
+        // Appears
+        ports.set(Some(&["Midi Through", "FP8"]));
+        assert_eq!(supervisor.poll(), appeared("FP8", 1));
+        assert_eq!(supervisor.poll(), None);
+
+        // Stays connected while other ports come and go
+        ports.set(Some(&["FP8"]));
+        assert_eq!(supervisor.poll(), None);
+        ports.set(Some(&["Other", "Midi Through", "FP8"]));
+        assert_eq!(supervisor.poll(), None);
+
+        // Disappears
+        ports.set(Some(&["Midi Through"]));
+        assert_eq!(supervisor.poll(), Some(Change::Disappeared));
+        assert_eq!(supervisor.poll(), None);
+
+        // Reappears somewhere else
+        ports.set(Some(&["FP8", "Midi Through"]));
+        assert_eq!(supervisor.poll(), appeared("FP8", 1));
+
+        // Listing the ports fails once, e.g. while another is being unplugged
+        ports.set(None);
+        assert_eq!(supervisor.poll(), None);
+        ports.set(Some(&["FP8"]));
+        assert_eq!(supervisor.poll(), None);
+
+        // ...or fails while it's being unplugged
+        ports.set(None);
+        assert_eq!(supervisor.poll(), None);
+        ports.set(Some(&[]));
+        assert_eq!(supervisor.poll(), Some(Change::Disappeared));
+    }
+
+    #[test]
+    fn tells_ports_with_the_same_name_apart() {
+        let ports = MockPorts::default();
+        let second = matcher("{name: FP8, occurrence: 2}");
+        let mut supervisor = Supervisor::new(ports.clone(), second.clone(), second);
+
+        ports.set(Some(&["FP8", "FP8"]));
+        assert_eq!(supervisor.poll(), appeared("FP8", 2));
+        ports.set(Some(&["Midi Through", "FP8", "Other", "FP8"]));
+        assert_eq!(supervisor.poll(), None);
+
+        // The other one goes, so this one is now the first
+        ports.set(Some(&["Midi Through", "FP8"]));
+        assert_eq!(supervisor.poll(), Some(Change::Disappeared));
+
+        // Without an occurrence, it's the first, even when another is plugged in
+        let first = PortMatcher::Name("FP8".to_string());
+        let mut supervisor = Supervisor::new(ports.clone(), first.clone(), first);
+        assert_eq!(supervisor.poll(), appeared("FP8", 1));
+        ports.set(Some(&["FP8", "FP8"]));
+        assert_eq!(supervisor.poll(), None);
+    }
+
+    #[test]
+    fn tries_again_after_failing_to_connect() {
+        let ports = MockPorts::default();
+        ports.set(Some(&["FP8"]));
+        let fp8 = PortMatcher::Name("FP8".to_string());
+        let mut supervisor = Supervisor::new(ports.clone(), fp8.clone(), fp8);
+
+        assert_eq!(supervisor.poll(), appeared("FP8", 1));
+        supervisor.reset("Couldn't connect".to_string());
+        assert_eq!(supervisor.poll(), appeared("FP8", 1));
+        assert_eq!(supervisor.poll(), None);
+    }
+}
@@ -0,0 +1,240 @@
+use midly::{num::u4, MidiMessage};
+
+/// Controller numbers used to send NRPNs.
+const NRPN_MSB: u8 = 99;
+const NRPN_LSB: u8 = 98;
+const RPN_MSB: u8 = 101;
+const RPN_LSB: u8 = 100;
+const DATA_ENTRY_MSB: u8 = 6;
+const DATA_ENTRY_LSB: u8 = 38;
+const DATA_INCREMENT: u8 = 96;
+const DATA_DECREMENT: u8 = 97;
+
+/// Controller number used to select a bank before a program change.
+const BANK_SELECT: u8 = 0;
+const BANK_SELECT_LSB: u8 = 32;
+
+/// Controller numbers used by HUI to send switch presses (from the surface)
+/// and to set LEDs (from the host).
+/// Each sends the zone, followed by the port.
+const HUI_SWITCH_ZONE: u8 = 0x0F;
+const HUI_SWITCH_PORT: u8 = 0x2F;
+const HUI_LED_ZONE: u8 = 0x0C;
+const HUI_LED_PORT: u8 = 0x2C;
+
+/// What we remember about earlier messages from a MIDI device,
+/// for matching messages which are sent in several parts,
+/// such as NRPNs and program changes with a bank select.
+#[derive(Debug, Clone, Default)]
+pub struct State {
+    channels: [ChannelState; 16],
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    parameter_msb: Option<u8>,
+    parameter_lsb: Option<u8>,
+    value_msb: Option<u8>,
+    bank: Option<u8>,
+    controller_msb: [Option<u8>; 32],
+    hui_switch_zone: Option<u8>,
+    hui_led_zone: Option<u8>,
+}
+
+/// The multi-part messages completed by a single MIDI message.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Completed {
+    pub nrpn: Option<Nrpn>,
+    pub controller_14: Option<Controller14>,
+    pub hui_switch: Option<HuiPort>,
+    pub hui_led: Option<HuiPort>,
+}
+
+/// A complete NRPN message, with 14-bit parameter and value.
+#[derive(Debug, Clone, Copy)]
+pub struct Nrpn {
+    pub parameter: u16,
+    pub value: u16,
+}
+
+/// A complete 14-bit control change,
+/// sent as the MSB on `controller` (0-31) followed by the LSB on `controller + 32`.
+#[derive(Debug, Clone, Copy)]
+pub struct Controller14 {
+    pub controller: u8,
+    pub value: u16,
+}
+
+/// A HUI switch or LED,
+/// which is addressed by a zone and a port within that zone.
+#[derive(Debug, Clone, Copy)]
+pub struct HuiPort {
+    pub zone: u8,
+    pub port: u8,
+    pub on: bool,
+}
+
+impl State {
+    /// Updates the state with a message from the device,
+    /// and returns any multi-part messages which it completes.
+    pub fn update(&mut self, channel: u4, message: &MidiMessage) -> Completed {
+        let state = &mut self.channels[channel.as_int() as usize];
+        let mut completed = Completed::default();
+        let MidiMessage::Controller { controller, value } = message else {
+            return completed;
+        };
+        let controller = controller.as_int();
+        let value = value.as_int();
+
+        match controller {
+            NRPN_MSB => state.parameter_msb = Some(value),
+            NRPN_LSB => state.parameter_lsb = Some(value),
+            // An RPN means any following data entry isn't for our NRPN
+            RPN_MSB | RPN_LSB => {
+                state.parameter_msb = None;
+                state.parameter_lsb = None;
+            }
+            DATA_ENTRY_LSB => {
+                if let (Some(msb), Some(lsb), Some(value_msb)) =
+                    (state.parameter_msb, state.parameter_lsb, state.value_msb)
+                {
+                    completed.nrpn = Some(Nrpn {
+                        parameter: (msb as u16) << 7 | lsb as u16,
+                        value: (value_msb as u16) << 7 | value as u16,
+                    });
+                }
+            }
+            BANK_SELECT => state.bank = Some(value),
+            HUI_SWITCH_ZONE => state.hui_switch_zone = Some(value),
+            HUI_LED_ZONE => state.hui_led_zone = Some(value),
+            _ => (),
+        }
+
+        match controller {
+            HUI_SWITCH_PORT => {
+                completed.hui_switch = state.hui_switch_zone.map(|zone| HuiPort::new(zone, value));
+            }
+            HUI_LED_PORT => {
+                completed.hui_led = state.hui_led_zone.map(|zone| HuiPort::new(zone, value));
+            }
+            _ => (),
+        }
+
+        match controller {
+            0..=31 => state.controller_msb[controller as usize] = Some(value),
+            32..=63 => {
+                if let Some(msb) = state.controller_msb[controller as usize - 32] {
+                    completed.controller_14 = Some(Controller14 {
+                        controller: controller - 32,
+                        value: (msb as u16) << 7 | value as u16,
+                    });
+                }
+            }
+            _ => (),
+        }
+
+        if controller == DATA_ENTRY_MSB {
+            state.value_msb = Some(value);
+        }
+
+        completed
+    }
+
+    /// The most recently selected bank on the given channel, if any.
+    pub fn bank(&self, channel: u4) -> Option<u8> {
+        self.channels[channel.as_int() as usize].bank
+    }
+}
+
+impl Nrpn {
+    /// The controller messages which make up the NRPN, in the order they should be sent.
+    pub fn controllers(&self) -> [(u8, u8); 4] {
+        [
+            (NRPN_MSB, (self.parameter >> 7) as u8 & 0x7F),
+            (NRPN_LSB, self.parameter as u8 & 0x7F),
+            (DATA_ENTRY_MSB, (self.value >> 7) as u8 & 0x7F),
+            (DATA_ENTRY_LSB, self.value as u8 & 0x7F),
+        ]
+    }
+}
+
+impl Controller14 {
+    /// The controller messages which make up the 14-bit control change,
+    /// in the order they should be sent.
+    pub fn controllers(&self) -> [(u8, u8); 2] {
+        [
+            (self.controller & 0x1F, (self.value >> 7) as u8 & 0x7F),
+            ((self.controller & 0x1F) + 32, self.value as u8 & 0x7F),
+        ]
+    }
+}
+
+impl HuiPort {
+    fn new(zone: u8, value: u8) -> Self {
+        HuiPort {
+            zone,
+            port: value & 0x0F,
+            on: value & 0x40 != 0,
+        }
+    }
+
+    /// The controller messages which press or release this switch.
+    pub fn switch_controllers(&self) -> [(u8, u8); 2] {
+        [
+            (HUI_SWITCH_ZONE, self.zone & 0x7F),
+            (HUI_SWITCH_PORT, self.port_value()),
+        ]
+    }
+
+    /// The controller messages which turn this LED on or off.
+    pub fn led_controllers(&self) -> [(u8, u8); 2] {
+        [
+            (HUI_LED_ZONE, self.zone & 0x7F),
+            (HUI_LED_PORT, self.port_value()),
+        ]
+    }
+
+    fn port_value(&self) -> u8 {
+        (self.port & 0x0F) | if self.on { 0x40 } else { 0 }
+    }
+}
+
+/// Whether a controller only means something as part of a sequence of messages
+/// (e.g. the parameter number of an NRPN),
+/// rather than setting something by itself.
+pub fn is_sequence_controller(controller: u8) -> bool {
+    matches!(
+        controller,
+        NRPN_MSB
+            | NRPN_LSB
+            | RPN_MSB
+            | RPN_LSB
+            | DATA_ENTRY_MSB
+            | DATA_ENTRY_LSB
+            | DATA_INCREMENT
+            | DATA_DECREMENT
+            | BANK_SELECT
+            | BANK_SELECT_LSB
+            | HUI_SWITCH_ZONE
+            | HUI_SWITCH_PORT
+            | HUI_LED_ZONE
+            | HUI_LED_PORT
+    )
+}
+
+/// Whether a controller's value makes its earlier values redundant,
+/// which isn't so for a controller which is part of a sequence (see [`is_sequence_controller`]),
+/// except for bank select, as long as it isn't moved past a program change.
+pub fn is_replaceable_controller(controller: u8) -> bool {
+    !is_sequence_controller(controller) || matches!(controller, BANK_SELECT | BANK_SELECT_LSB)
+}
+
+/// Whether a controller sets the value of an NRPN or RPN.
+pub fn is_data_entry(controller: u8) -> bool {
+    matches!(controller, DATA_ENTRY_MSB | DATA_ENTRY_LSB)
+}
+
+/// The controller message which selects the given bank.
+pub fn bank_select(bank: u8) -> (u8, u8) {
+    (BANK_SELECT, bank & 0x7F)
+}
@@ -0,0 +1,142 @@
+//! Splitting the bytes from a MIDI connection (e.g. MIDI over TCP) into messages.
+
+use midly::{live::LiveEvent, stream::MidiStream};
+
+/// Splits a stream of bytes into MIDI messages, like [`MidiStream`],
+/// except that System Common messages (e.g. Song Position) are passed on
+/// as soon as they're complete, rather than once the next message starts.
+///
+/// Otherwise, a realtime message which comes straight after one (e.g. a Continue)
+/// would be passed on before it.
+#[derive(Default)]
+pub struct Stream {
+    stream: MidiStream,
+
+    /// How many more data bytes the current System Common message needs, if there is one.
+    remaining: Option<usize>,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream::default()
+    }
+
+    /// Feeds some bytes to the stream,
+    /// calling `handle_ev` for each message they complete.
+    pub fn feed(&mut self, bytes: &[u8], mut handle_ev: impl FnMut(LiveEvent)) {
+        for &byte in bytes {
+            self.stream.feed(&[byte], &mut handle_ev);
+            self.remaining = match (byte, self.remaining) {
+                // Realtime messages can come in the middle of others
+                (0xF8..=0xFF, remaining) => remaining,
+                // MTC Quarter Frame and Song Select
+                (0xF1 | 0xF3, _) => Some(1),
+                // Song Position
+                (0xF2, _) => Some(2),
+                (0x80..=0xF7, _) => None,
+                (_, Some(1)) => {
+                    self.stream.flush(&mut handle_ev);
+                    None
+                }
+                (_, remaining) => remaining.map(|remaining| remaining - 1),
+            };
+        }
+    }
+
+    /// Passes on any message which is still waiting for the next one to start,
+    /// e.g. at the end of the stream.
+    pub fn flush(&mut self, handle_ev: impl FnMut(LiveEvent)) {
+        self.remaining = None;
+        self.stream.flush(handle_ev);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{
+        live::{SystemCommon, SystemRealtime},
+        num::u14,
+        MidiMessage,
+    };
+
+    use super::*;
+    use crate::midi::Event;
+
+    /// Feeds each group of bytes to a new stream in turn,
+    /// and returns the messages after each.
+    fn feed(groups: &[&[u8]]) -> Vec<Vec<Event>> {
+        let mut stream = Stream::new();
+        groups
+            .iter()
+            .map(|bytes| {
+                let mut events = Vec::new();
+                stream.feed(bytes, |event| events.push(Event::from(event)));
+                events
+            })
+            .collect()
+    }
+
+    fn note_on(key: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::NoteOn {
+                key: key.into(),
+                vel: 127.into(),
+            },
+        })
+    }
+
+    fn song_position(position: u16) -> Event {
+        Event::from(LiveEvent::Common(SystemCommon::SongPosition(u14::new(
+            position,
+        ))))
+    }
+
+    fn realtime(message: SystemRealtime) -> Event {
+        Event::from(LiveEvent::Realtime(message))
+    }
+
+    #[test]
+    fn passes_on_song_positions_once_complete() {
+        assert_eq!(
+            feed(&[&[0xF2, 0x10], &[0x01], &[0xFB]]),
+            [
+                vec![],
+                vec![song_position(0x90)],
+                vec![realtime(SystemRealtime::Continue)]
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_on_realtime_messages_in_the_middle_of_others() {
+        assert_eq!(
+            feed(&[&[0x90, 0x3C, 0xF8, 0x7F]]),
+            [vec![realtime(SystemRealtime::TimingClock), note_on(0x3C)]]
+        );
+        assert_eq!(
+            feed(&[&[0xF2, 0x10, 0xF8], &[0x01]]),
+            [
+                vec![realtime(SystemRealtime::TimingClock)],
+                vec![song_position(0x90)]
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_running_status() {
+        assert_eq!(
+            feed(&[&[0x90, 0x3C, 0x7F, 0x3E], &[0x7F]]),
+            [vec![note_on(0x3C)], vec![note_on(0x3E)]]
+        );
+        // Realtime messages don't interrupt it
+        assert_eq!(
+            feed(&[&[0x90, 0x3C, 0x7F, 0xF8, 0x3E, 0x7F]]),
+            [vec![
+                note_on(0x3C),
+                realtime(SystemRealtime::TimingClock),
+                note_on(0x3E)
+            ]]
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! A stand-in for a TCP MIDI device,
+//! for trying out configs without the real hardware.
+
+use std::net::SocketAddr;
+
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+
+use gobetween::{
+    midi::{stream::Stream, Event},
+    monitor::{Direction, Monitor},
+};
+
+/// Listens for TCP MIDI connections, e.g. from gobetween,
+/// and prints every message it's sent.
+///
+/// Messages typed into stdin as hex bytes (e.g. `90 4d 21`) are sent to every connection,
+/// and if `echo` is set, every message received is sent straight back.
+pub async fn mock_tcp(address: &str, echo: bool) -> Result<(), Box<dyn std::error::Error>> {
+    // Allows just giving a port
+    let address = match address.parse::<u16>() {
+        Ok(port) => format!("localhost:{port}"),
+        Err(_) => address.to_string(),
+    };
+    let listener = TcpListener::bind(&address).await?;
+    log::info!("Listening for TCP MIDI connections on {address}");
+    println!("Type MIDI messages as hex bytes (e.g. `90 4d 21`) to send them.");
+
+    let monitor = Monitor::new();
+    let (stdin_tx, _stdin_rx) = broadcast::channel(16);
+
+    let cloned_stdin_tx = stdin_tx.clone();
+    tokio::spawn(async move {
+        let stdin_tx = cloned_stdin_tx;
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match parse_hex(&line) {
+                Ok(events) => {
+                    for event in events {
+                        // Ignore the return value;
+                        // error case is when nothing has connected yet,
+                        // which we don't care about.
+                        let _ = stdin_tx.send(event);
+                    }
+                }
+                Err(err) => log::error!("Couldn't parse `{line}`: {err}"),
+            }
+        }
+    });
+
+    loop {
+        let (socket, peer) = listener.accept().await?;
+        socket.set_nodelay(true)?;
+        log::info!("Accepted connection from {peer}");
+        let stdin_rx = stdin_tx.subscribe();
+        tokio::spawn(async move {
+            match serve(socket, peer, stdin_rx, echo, monitor).await {
+                Ok(()) => log::info!("Connection from {peer} closed"),
+                Err(err) => log::error!("Connection from {peer} failed: {err}"),
+            }
+        });
+    }
+}
+
+/// Prints the messages from one connection,
+/// and sends it the messages from stdin.
+async fn serve(
+    mut socket: TcpStream,
+    peer: SocketAddr,
+    mut stdin_rx: broadcast::Receiver<Event>,
+    echo: bool,
+    monitor: Monitor,
+) -> Result<(), std::io::Error> {
+    let name = peer.to_string();
+    let mut buf = BytesMut::new();
+    let mut stream = Stream::new();
+
+    loop {
+        tokio::select! {
+            bytes_read = socket.read_buf(&mut buf) => {
+                if bytes_read? == 0 {
+                    return Ok(());
+                }
+                let mut events = Vec::new();
+                stream.feed(&buf, |live_event| events.push(Event::from(live_event)));
+                buf.clear();
+
+                for event in events {
+                    monitor.print(&name, Direction::In, &event, None);
+                    if echo {
+                        monitor.print(&name, Direction::Out, &event, None);
+                        socket.write_all(event.as_bytes()).await?;
+                    }
+                }
+            }
+            Ok(event) = stdin_rx.recv() => {
+                monitor.print(&name, Direction::Out, &event, None);
+                socket.write_all(event.as_bytes()).await?;
+            }
+        }
+    }
+}
+
+/// Parses MIDI messages written as hex bytes separated by spaces.
+fn parse_hex(line: &str) -> Result<Vec<Event>, String> {
+    let bytes = line
+        .split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("`{byte}` isn't a hex byte")))
+        .collect::<Result<Vec<u8>, String>>()?;
+
+    let mut events = Vec::new();
+    let mut stream = Stream::new();
+    stream.feed(&bytes, |live_event| events.push(Event::from(live_event)));
+    // Finishes off a SysEx message without its `f7`
+    stream.flush(|live_event| events.push(Event::from(live_event)));
+
+    if events.is_empty() && !bytes.is_empty() {
+        return Err("no complete MIDI messages".to_string());
+    }
+    Ok(events)
+}
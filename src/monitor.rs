@@ -0,0 +1,88 @@
+//! Prints the messages going in and out of each device as they happen.
+
+use std::{
+    fmt,
+    io::{self, IsTerminal, Write},
+    time::Instant,
+};
+
+/// ANSI escape codes for the colours of each part of a line.
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const YELLOW: &str = "\x1b[33m";
+
+/// Prints a timestamped line for each message to stdout.
+#[derive(Debug, Clone, Copy)]
+pub struct Monitor {
+    start: Instant,
+    colour: bool,
+}
+
+/// Whether a message came from a device, or is being sent to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Monitor {
+    /// Starts the clock for the timestamps.
+    /// Lines are only coloured if stdout is a terminal.
+    pub fn new() -> Self {
+        Monitor {
+            start: Instant::now(),
+            colour: io::stdout().is_terminal(),
+        }
+    }
+
+    /// Prints a message which came from or was sent to the named device,
+    /// along with the mapping which sent it, if any.
+    pub fn print(
+        &self,
+        device: &str,
+        direction: Direction,
+        message: &dyn fmt::Display,
+        mapping: Option<&str>,
+    ) {
+        let seconds = self.start.elapsed().as_secs_f64();
+        let (dim, bold, colour, reset) = if self.colour {
+            let colour = match direction {
+                Direction::In => GREEN,
+                Direction::Out => CYAN,
+            };
+            (DIM, BOLD, colour, RESET)
+        } else {
+            ("", "", "", "")
+        };
+
+        let mut line = format!(
+            "{dim}{seconds:>10.3}{reset} {bold}{device:<12}{reset} {colour}{direction:<3} {message}{reset}"
+        );
+        if let Some(mapping) = mapping {
+            let yellow = if self.colour { YELLOW } else { "" };
+            line.push_str(&format!("  {yellow}({mapping}){reset}"));
+        }
+
+        // Ignore errors, e.g. if stdout has been closed
+        let _ = writeln!(io::stdout().lock(), "{line}");
+    }
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor::new()
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Allows padding with e.g. `{direction:<3}`
+        f.pad(match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        })
+    }
+}
@@ -0,0 +1,1040 @@
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DeserializeFromStr, OneOrMany, SerializeDisplay};
+
+use crate::{
+    config::MessageTemplate,
+    message::{Number, Range},
+};
+
+/// Profiles which are compiled into the binary,
+/// as pairs of `(name, YAML source)`.
+/// These are searched after all the directories in the [`SearchPath`].
+const BUILTIN: &[(&str, &str)] = &[
+    ("faderport8", include_str!("../profiles/faderport8.yaml")),
+    ("faderport16", include_str!("../profiles/faderport16.yaml")),
+    ("sq", include_str!("../profiles/sq.yaml")),
+    ("mcu", include_str!("../profiles/mcu.yaml")),
+    ("hui", include_str!("../profiles/hui.yaml")),
+    ("clock", include_str!("../profiles/clock.yaml")),
+];
+
+/// Describes the controls of a particular kind of device,
+/// so that mappings can refer to e.g. `fader 3`
+/// instead of the raw MIDI messages.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "ProfileRepr")]
+pub struct Profile {
+    /// The name of the profile, e.g. `faderport8`.
+    pub name: String,
+
+    /// The controls of the device,
+    /// keyed by a pattern such as `play`, `fader[1..8]`, or `input[1..48].level`.
+    pub controls: HashMap<String, Control>,
+
+    /// Tables for converting the raw values of controls into real units, e.g. dB.
+    #[serde(default)]
+    pub tables: HashMap<String, Table>,
+
+    /// The patterns of the controls, parsed when the profile is loaded,
+    /// along with the keys of the controls.
+    #[serde(skip)]
+    patterns: Vec<(Pattern, String)>,
+}
+
+/// A [`Profile`] as it's written,
+/// so that deserializing one always checks it with [`Profile::new`].
+#[derive(Deserialize)]
+struct ProfileRepr {
+    name: String,
+    controls: HashMap<String, Control>,
+    #[serde(default)]
+    tables: HashMap<String, Table>,
+}
+
+impl TryFrom<ProfileRepr> for Profile {
+    type Error = Error;
+
+    fn try_from(repr: ProfileRepr) -> Result<Self, Self::Error> {
+        Profile::new(repr.name, repr.controls, repr.tables)
+    }
+}
+
+/// A single named control (or indexed group of controls) in a [`Profile`].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Control {
+    /// For indexed controls,
+    /// the fields of the template which change with each index,
+    /// in the same order as the indices appear in the control's pattern.
+    #[serde(default)]
+    #[serde_as(as = "OneOrMany<_>")]
+    pub index: Vec<Index>,
+
+    /// Which of the profile's [`Table`]s to use for each field,
+    /// e.g. `{value: level}`.
+    #[serde(default)]
+    pub units: HashMap<String, String>,
+
+    #[serde(flatten)]
+    pub message_template: MessageTemplate,
+}
+
+/// Describes how an index in a control pattern changes a field of the template.
+/// The first index in the pattern gives the first value of the field,
+/// and each subsequent index adds `stride`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Index {
+    Field(String),
+    Strided { field: String, stride: u32 },
+}
+
+impl Index {
+    fn field(&self) -> &str {
+        match self {
+            Index::Field(field) | Index::Strided { field, .. } => field,
+        }
+    }
+
+    fn stride(&self) -> u32 {
+        match self {
+            Index::Field(_) => 1,
+            Index::Strided { stride, .. } => *stride,
+        }
+    }
+}
+
+impl Profile {
+    /// A profile with the given controls and tables,
+    /// checking that each of its controls makes sense.
+    pub fn new(
+        name: String,
+        controls: HashMap<String, Control>,
+        tables: HashMap<String, Table>,
+    ) -> Result<Self, Error> {
+        let mut profile = Profile {
+            name,
+            controls,
+            tables,
+            patterns: Vec::new(),
+        };
+        profile.prepare()?;
+        Ok(profile)
+    }
+
+    /// Parses a profile from YAML,
+    /// and checks that each of its controls makes sense.
+    pub fn from_yaml(source: &str) -> Result<Self, Error> {
+        // Not straight into a `Profile`, so that errors from the checks keep their type
+        let repr: ProfileRepr = serde_yaml::from_str(source)?;
+        repr.try_into()
+    }
+
+    /// Names the tables, parses the patterns of the controls,
+    /// and checks that each of the controls makes sense,
+    /// and that no two of them have the same name.
+    fn prepare(&mut self) -> Result<(), Error> {
+        for (name, table) in self.tables.iter_mut() {
+            table.name = name.clone();
+            table.check()?;
+        }
+
+        let mut patterns: Vec<(Pattern, String)> = Vec::new();
+        // Sorted, so that any error is the same every time
+        let mut keys: Vec<&String> = self.controls.keys().collect();
+        keys.sort();
+        for pattern in keys {
+            let control = &self.controls[pattern];
+            let parsed = pattern
+                .parse::<Pattern>()
+                .map_err(|reason| Error::BadPattern(pattern.clone(), reason))?;
+            if let Some((_, other)) = patterns.iter().find(|(other, _)| other.overlaps(&parsed)) {
+                return Err(Error::Overlap(other.clone(), pattern.clone()));
+            }
+
+            let indices = parsed.segments.iter().filter(|s| s.range.is_some()).count();
+            if indices != control.index.len() {
+                return Err(Error::BadIndex(
+                    pattern.clone(),
+                    "number of indices doesn't match number of `index` fields".to_string(),
+                ));
+            }
+
+            let mut template = control.message_template.clone();
+            for index in control.index.iter() {
+                let numbers = template.field_mut(index.field()).ok_or_else(|| {
+                    Error::BadIndex(
+                        pattern.clone(),
+                        format!("template has no field `{}`", index.field()),
+                    )
+                })?;
+                if !matches!(numbers[..], [Number::Value(_)] | [Number::Range(_)]) {
+                    return Err(Error::BadIndex(
+                        pattern.clone(),
+                        format!(
+                            "field `{}` should be a single value or range",
+                            index.field()
+                        ),
+                    ));
+                }
+            }
+
+            for table in control.units.values() {
+                if !self.tables.contains_key(table) {
+                    return Err(Error::TableNotFound(table.clone(), pattern.clone()));
+                }
+            }
+
+            patterns.push((parsed, pattern.clone()));
+        }
+
+        self.patterns = patterns;
+        Ok(())
+    }
+
+    /// Finds the control with the given name,
+    /// and returns its template with any indexed fields filled in,
+    /// and the given fields replaced.
+    pub fn resolve(
+        &self,
+        control_ref: &ControlRef,
+        fields: &HashMap<String, Vec<FieldValue>>,
+    ) -> Result<MessageTemplate, Error> {
+        let (control, mut template) = self.find(control_ref)?;
+
+        for (field, values) in fields.iter() {
+            let numbers = values
+                .iter()
+                .map(|value| match value {
+                    FieldValue::Number(number) => Ok(number.clone()),
+                    FieldValue::Quantity(quantity) => {
+                        let table = control
+                            .units
+                            .get(field)
+                            .and_then(|table| self.tables.get(table))
+                            .ok_or_else(|| {
+                                Error::NoUnits(control_ref.to_string(), field.clone())
+                            })?;
+                        table.number(quantity)
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+
+            *template.field_mut(field).ok_or_else(|| {
+                Error::NoSuchField(control_ref.to_string(), field.clone())
+            })? = numbers;
+        }
+
+        Ok(template)
+    }
+
+    /// Finds the control with the given name,
+    /// and returns the table used by each of its fields which has units.
+    pub fn units(&self, control_ref: &ControlRef) -> Result<HashMap<String, Table>, Error> {
+        let (control, _) = self.find(control_ref)?;
+        Ok(control
+            .units
+            .iter()
+            .filter_map(|(field, table)| Some((field.clone(), self.tables.get(table)?.clone())))
+            .collect())
+    }
+
+    /// Finds the control with the given name,
+    /// and returns it along with its template with any indexed fields filled in.
+    fn find(&self, control_ref: &ControlRef) -> Result<(&Control, MessageTemplate), Error> {
+        for (pattern, key) in self.patterns.iter() {
+            if let Some(offsets) = pattern.matches(control_ref) {
+                let control = &self.controls[key];
+                let mut template = control.message_template.clone();
+                for (index, offset) in control.index.iter().zip(offsets) {
+                    // Also checked when the profile was loaded
+                    let Some(numbers) = template.field_mut(index.field()) else {
+                        continue;
+                    };
+                    let base = match numbers.first() {
+                        Some(Number::Value(n)) | Some(Number::Range(Range(n, _))) => *n,
+                        _ => continue,
+                    };
+                    *numbers = vec![Number::Value(base + offset * index.stride())];
+                }
+                return Ok((control, template));
+            }
+        }
+
+        Err(Error::ControlNotFound(
+            control_ref.to_string(),
+            self.name.clone(),
+        ))
+    }
+}
+
+/// Converts between the raw values of a control and real units,
+/// by interpolating linearly between the given points.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Table {
+    /// The name of the table in its profile, e.g. `level`.
+    #[serde(skip)]
+    pub name: String,
+
+    /// The name of the units, e.g. `dB`.
+    pub unit: String,
+
+    /// Pairs of `[raw value, value in units]`,
+    /// in increasing order of both.
+    /// The first value in units may be `-.inf`.
+    pub points: Vec<(u32, f64)>,
+}
+
+impl Table {
+    /// Checks that the table has some points,
+    /// and that both their raw values and values in units are strictly increasing.
+    fn check(&self) -> Result<(), Error> {
+        if self.points.is_empty() {
+            return Err(Error::BadTable(self.name.clone(), "no points".to_string()));
+        }
+        for (i, pair) in self.points.windows(2).enumerate() {
+            let [(raw_a, a), (raw_b, b)] = [pair[0], pair[1]];
+            // Only the first value in units can be `-.inf`
+            let units_increase = a < b && b.is_finite() && (i == 0 || a.is_finite());
+            if raw_a >= raw_b || !units_increase {
+                return Err(Error::BadTable(
+                    self.name.clone(),
+                    format!("point {:?} should be after {:?}", pair[1], pair[0]),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts a value in units into the nearest raw value.
+    pub fn to_raw(&self, units: f64) -> u32 {
+        let Some(&(first_raw, first)) = self.points.first() else {
+            return 0;
+        };
+        if units <= first {
+            return first_raw;
+        }
+
+        for pair in self.points.windows(2) {
+            let [(raw_a, a), (raw_b, b)] = [pair[0], pair[1]];
+            if units <= b {
+                if !a.is_finite() {
+                    return raw_b;
+                }
+                let position = (units - a) / (b - a);
+                return (raw_a as f64 + (raw_b - raw_a) as f64 * position).round() as u32;
+            }
+        }
+
+        // Past the end of the table
+        self.points[self.points.len() - 1].0
+    }
+
+    /// Converts a raw value into units.
+    pub fn to_units(&self, raw: u32) -> f64 {
+        let Some(&(first_raw, first)) = self.points.first() else {
+            return 0.0;
+        };
+        if raw <= first_raw {
+            return first;
+        }
+
+        for pair in self.points.windows(2) {
+            let [(raw_a, a), (raw_b, b)] = [pair[0], pair[1]];
+            if raw <= raw_b {
+                if !a.is_finite() {
+                    return b;
+                }
+                let position = (raw - raw_a) as f64 / (raw_b - raw_a) as f64;
+                return a + (b - a) * position;
+            }
+        }
+
+        // Past the end of the table
+        self.points[self.points.len() - 1].1
+    }
+
+    /// Converts a quantity into a [`Number`] of raw values.
+    fn number(&self, quantity: &Quantity) -> Result<Number, Error> {
+        if quantity.unit != self.unit {
+            return Err(Error::WrongUnit(quantity.to_string(), self.unit.clone()));
+        }
+
+        let a = self.to_raw(quantity.a);
+        Ok(match quantity.b {
+            None => Number::Value(a),
+            Some(b) => Number::Range(Range(a, self.to_raw(b))),
+        })
+    }
+}
+
+/// The value of a field given when referring to a control,
+/// either as a raw [`Number`] or as a [`Quantity`] in the units of the control.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FieldValue {
+    Number(Number),
+    Quantity(Quantity),
+}
+
+/// A value or range in real units,
+/// parsed from e.g. `-10 dB`, or `-inf dB .. +5 dB`.
+#[derive(SerializeDisplay, DeserializeFromStr, Debug, Clone)]
+pub struct Quantity {
+    pub a: f64,
+    pub b: Option<f64>,
+    pub unit: String,
+}
+
+impl FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Splits e.g. `-10dB` into `-10.0` and `dB`,
+        // by finding the longest prefix which is a number.
+        fn parse_one(s: &str) -> Result<(f64, String), String> {
+            let s = s.trim();
+            for (i, _) in s.char_indices().rev() {
+                let unit = s[i..].trim();
+                if unit.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+                    break;
+                }
+                if let Ok(n) = s[..i].trim().parse::<f64>() {
+                    return Ok((n, unit.to_string()));
+                }
+            }
+            Err(format!("expected a number with units, found `{s}`"))
+        }
+
+        match s.split_once("..") {
+            None => {
+                let (a, unit) = parse_one(s)?;
+                Ok(Quantity { a, b: None, unit })
+            }
+            Some((a, b)) => {
+                let (a, unit_a) = parse_one(a)?;
+                let (b, unit_b) = parse_one(b)?;
+                if unit_a != unit_b {
+                    return Err(format!("different units `{unit_a}` and `{unit_b}`"));
+                }
+                Ok(Quantity {
+                    a,
+                    b: Some(b),
+                    unit: unit_a,
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.a, self.unit)?;
+        if let Some(b) = self.b {
+            write!(f, " .. {} {}", b, self.unit)?;
+        }
+        Ok(())
+    }
+}
+
+/// The list of directories in which to look for profiles.
+#[derive(Debug, Clone, Default)]
+pub struct SearchPath(pub Vec<PathBuf>);
+
+impl SearchPath {
+    /// The environment variable which can be used to add directories to the search path.
+    pub const ENV_VAR: &'static str = "GOBETWEEN_PROFILE_PATH";
+
+    /// Builds the search path for the given config file.
+    /// In order, this searches:
+    ///  - the directories listed in the config's `profile_path`
+    ///    (relative to the config file);
+    ///  - the directories listed in `$GOBETWEEN_PROFILE_PATH`;
+    ///  - the `profiles` directory next to the config file.
+    pub fn new(config_dir: &Path, profile_path: &[PathBuf]) -> Self {
+        let mut dirs: Vec<PathBuf> = profile_path.iter().map(|p| config_dir.join(p)).collect();
+        if let Some(var) = std::env::var_os(Self::ENV_VAR) {
+            dirs.extend(std::env::split_paths(&var));
+        }
+        dirs.push(config_dir.join("profiles"));
+        SearchPath(dirs)
+    }
+
+    /// Loads the profile with the given name,
+    /// from `<name>.yaml` or `<name>.yml` in the first directory which has one,
+    /// or else from the profiles built into the binary.
+    pub fn load(&self, name: &str) -> Result<Profile, Error> {
+        for dir in self.0.iter() {
+            for ext in ["yaml", "yml"] {
+                let path = dir.join(format!("{name}.{ext}"));
+                match fs::read_to_string(&path) {
+                    Ok(source) => {
+                        log::debug!("Loading profile `{name}` from {}", path.display());
+                        return Profile::from_yaml(&source)
+                            .map_err(|err| Error::InFile(path, Box::new(err)));
+                    }
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        if let Some((_, source)) = BUILTIN.iter().find(|(builtin, _)| *builtin == name) {
+            log::debug!("Loading built-in profile `{name}`");
+            return Profile::from_yaml(source);
+        }
+
+        Err(Error::ProfileNotFound(name.to_string(), self.0.clone()))
+    }
+}
+
+/// A reference to a named control,
+/// e.g. `play`, `fader 3`, `input 12 level`, or `input[12].level`.
+#[derive(SerializeDisplay, DeserializeFromStr, Debug, Clone, PartialEq, Eq)]
+pub struct ControlRef {
+    pub segments: Vec<(String, Option<u32>)>,
+}
+
+impl FromStr for ControlRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments: Vec<(String, Option<u32>)> = Vec::new();
+
+        for token in s.split(|c: char| c.is_whitespace() || matches!(c, '.' | '[' | ']')) {
+            if token.is_empty() {
+                continue;
+            }
+
+            if let Ok(n) = token.parse::<u32>() {
+                match segments.last_mut() {
+                    Some((_, index @ None)) => *index = Some(n),
+                    Some((name, Some(_))) => {
+                        return Err(format!("`{name}` has more than one index"));
+                    }
+                    None => return Err(format!("index {n} doesn't follow a control name")),
+                }
+            } else {
+                segments.push((token.to_string(), None));
+            }
+        }
+
+        if segments.is_empty() {
+            Err("empty control name".to_string())
+        } else {
+            Ok(ControlRef { segments })
+        }
+    }
+}
+
+impl fmt::Display for ControlRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, index)) in self.segments.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{name}")?;
+            if let Some(index) = index {
+                write!(f, "[{index}]")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A parsed control pattern, e.g. `fader[1..8]` or `input[1..48].level`.
+#[derive(Debug, Clone)]
+struct Pattern {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    name: String,
+    range: Option<(u32, u32)>,
+}
+
+impl Pattern {
+    /// If the reference matches this pattern,
+    /// returns the offset of each index from the start of its range.
+    fn matches(&self, control_ref: &ControlRef) -> Option<Vec<u32>> {
+        if self.segments.len() != control_ref.segments.len() {
+            return None;
+        }
+
+        let mut offsets = Vec::new();
+        for (segment, (name, index)) in self.segments.iter().zip(control_ref.segments.iter()) {
+            if segment.name != *name {
+                return None;
+            }
+            match (segment.range, index) {
+                (None, None) => (),
+                (Some((a, b)), Some(i)) if a <= *i && i <= &b => offsets.push(i - a),
+                _ => return None,
+            }
+        }
+        Some(offsets)
+    }
+
+    /// Whether some reference would match both this pattern and the other,
+    /// e.g. `fader[1..8]` and `fader[8..16]`.
+    fn overlaps(&self, other: &Pattern) -> bool {
+        self.segments.len() == other.segments.len()
+            && self
+                .segments
+                .iter()
+                .zip(other.segments.iter())
+                .all(|(a, b)| {
+                    a.name == b.name
+                        && match (a.range, b.range) {
+                            (None, None) => true,
+                            (Some((a1, a2)), Some((b1, b2))) => a1 <= b2 && b1 <= a2,
+                            _ => false,
+                        }
+                })
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut rest = s.trim();
+
+        while !rest.is_empty() {
+            let end = rest.find(['[', '.']).unwrap_or(rest.len());
+            let name = rest[..end].trim();
+            if name.is_empty() || name.parse::<u32>().is_ok() {
+                return Err(format!("bad control name `{name}`"));
+            }
+            rest = &rest[end..];
+
+            let mut range = None;
+            if let Some(after) = rest.strip_prefix('[') {
+                let close = after.find(']').ok_or("no closing `]`")?;
+                let inner = &after[..close];
+                let (a, b) = inner.split_once("..").unwrap_or((inner, inner));
+                let a = a.trim().parse().map_err(|_| format!("bad index `{inner}`"))?;
+                let b = b.trim().parse().map_err(|_| format!("bad index `{inner}`"))?;
+                if b < a {
+                    return Err(format!("index range `{inner}` is backwards"));
+                }
+                range = Some((a, b));
+                rest = &after[close + 1..];
+            }
+
+            segments.push(Segment {
+                name: name.to_string(),
+                range,
+            });
+
+            if let Some(after) = rest.strip_prefix('.') {
+                rest = after;
+            } else if !rest.is_empty() {
+                return Err(format!("unexpected `{rest}`"));
+            }
+        }
+
+        if segments.is_empty() {
+            Err("empty control pattern".to_string())
+        } else {
+            Ok(Pattern { segments })
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+
+    #[error("Couldn't parse profile: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+
+    #[error("In profile `{0}`: {1}")]
+    InFile(PathBuf, Box<Error>),
+
+    #[error("Couldn't find profile `{0}` (searched {1:?} and the built-in profiles)")]
+    ProfileNotFound(String, Vec<PathBuf>),
+
+    #[error("Bad control pattern `{0}`: {1}")]
+    BadPattern(String, String),
+
+    #[error("Controls `{0}` and `{1}` have some of the same names")]
+    Overlap(String, String),
+
+    #[error("Bad index for control `{0}`: {1}")]
+    BadIndex(String, String),
+
+    #[error("Couldn't find control `{0}` in profile `{1}`")]
+    ControlNotFound(String, String),
+
+    #[error("Control `{0}` has no field `{1}`")]
+    NoSuchField(String, String),
+
+    #[error("Couldn't find table `{0}` (used by control `{1}`)")]
+    TableNotFound(String, String),
+
+    #[error("Bad table `{0}`: {1}")]
+    BadTable(String, String),
+
+    #[error("Field `{1}` of control `{0}` has no units")]
+    NoUnits(String, String),
+
+    #[error("`{0}` should be in `{1}`")]
+    WrongUnit(String, String),
+
+    #[error("Device `{0}` has no profile, so can't use named control `{1}`")]
+    NoProfile(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message::{Match, Template},
+        midi::{message_template::SysExByte, state::State, Event},
+    };
+
+    fn control_ref(s: &str) -> ControlRef {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_patterns() {
+        let pattern: Pattern = "input[1..48].level".parse().unwrap();
+        assert_eq!(pattern.segments.len(), 2);
+        assert_eq!(pattern.segments[0].name, "input");
+        assert_eq!(pattern.segments[0].range, Some((1, 48)));
+        assert_eq!(pattern.segments[1].name, "level");
+        assert_eq!(pattern.segments[1].range, None);
+
+        let pattern: Pattern = "mute[3]".parse().unwrap();
+        assert_eq!(pattern.segments[0].range, Some((3, 3)));
+
+        for bad in [
+            "",
+            "fader[1..8",
+            "fader[8..1]",
+            "fader[a]",
+            "3",
+            "fader[1]x",
+            "a..b",
+        ] {
+            assert!(
+                bad.parse::<Pattern>().is_err(),
+                "`{bad}` should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_references() {
+        let pattern: Pattern = "input[1..48].level".parse().unwrap();
+        assert_eq!(
+            pattern.matches(&control_ref("input 12 level")),
+            Some(vec![11])
+        );
+        assert_eq!(
+            pattern.matches(&control_ref("input[1].level")),
+            Some(vec![0])
+        );
+        assert_eq!(pattern.matches(&control_ref("input 49 level")), None);
+        assert_eq!(pattern.matches(&control_ref("input level")), None);
+        assert_eq!(pattern.matches(&control_ref("input 12")), None);
+        assert_eq!(pattern.matches(&control_ref("output 12 level")), None);
+    }
+
+    #[test]
+    fn parses_references() {
+        assert_eq!(
+            control_ref("input 12 level"),
+            control_ref("input[12].level")
+        );
+        assert_eq!(control_ref("fader 3").to_string(), "fader[3]");
+        assert!("".parse::<ControlRef>().is_err());
+        assert!("3 fader".parse::<ControlRef>().is_err());
+        assert!("fader 3 4".parse::<ControlRef>().is_err());
+    }
+
+    #[test]
+    fn finds_overlaps() {
+        let overlaps = |a: &str, b: &str| {
+            let a: Pattern = a.parse().unwrap();
+            a.overlaps(&b.parse().unwrap())
+        };
+        assert!(overlaps("fader[1..8]", "fader[8..16]"));
+        assert!(overlaps("play", "play"));
+        assert!(!overlaps("fader[1..8]", "fader[9..16]"));
+        assert!(!overlaps("fader[1..8]", "fader"));
+        assert!(!overlaps("fader[1..8]", "fader[1..8].touch"));
+
+        let source = "
+name: overlapping
+controls:
+  fader[1..8]: {type: PitchBend, channel: 0-7, index: channel}
+  fader[8]: {type: PitchBend, channel: 8}
+";
+        assert!(matches!(
+            Profile::from_yaml(source),
+            Err(Error::Overlap(a, b)) if a == "fader[1..8]" && b == "fader[8]"
+        ));
+    }
+
+    #[test]
+    fn resolves_indexed_controls() {
+        let source = "
+name: desk
+controls:
+  play: {type: NoteOn, channel: 0, note: 94}
+  fader[1..8]: {type: PitchBend, channel: 0-7, index: channel}
+  mute[1..8]: {type: NoteOn, channel: 0, note: 16, index: {field: note, stride: 2}}
+";
+        let profile = Profile::from_yaml(source).unwrap();
+        let resolve = |s: &str| format!("{:?}", profile.resolve(&control_ref(s), &HashMap::new()));
+        assert!(resolve("fader 3").contains("channel: [Value(2)]"));
+        assert!(resolve("mute 3").contains("note: [Value(20)]"));
+        assert!(resolve("play").contains("note: [Value(94)]"));
+        assert!(matches!(
+            profile.resolve(&control_ref("fader 9"), &HashMap::new()),
+            Err(Error::ControlNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn checks_tables() {
+        let table = |points: &str| {
+            let source = format!(
+                "
+name: tables
+controls:
+  fader: {{type: PitchBend, channel: 0, units: {{bend: level}}}}
+tables:
+  level: {{unit: dB, points: {points}}}
+"
+            );
+            Profile::from_yaml(&source)
+        };
+
+        let profile = table("[[0, -.inf], [8192, -10], [16383, 10]]").unwrap();
+        let level = &profile.tables["level"];
+        assert_eq!(level.to_units(0), f64::NEG_INFINITY);
+        assert_eq!(level.to_units(16383), 10.0);
+        assert_eq!(level.to_raw(0.0), 12288);
+        assert_eq!(level.to_raw(20.0), 16383);
+
+        for bad in [
+            "[]",
+            "[[0, 0], [0, 10]]",
+            "[[100, 0], [0, 10]]",
+            "[[0, 10], [100, 0]]",
+            "[[0, 0], [100, 0]]",
+            "[[0, 0], [100, .inf]]",
+            "[[0, -.inf], [100, -.inf]]",
+            "[[0, 0], [100, .nan]]",
+        ] {
+            assert!(
+                matches!(table(bad), Err(Error::BadTable(..))),
+                "`{bad}` should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn checks_deserialized_profiles() {
+        let source = "
+name: overlapping
+controls:
+  fader[1..8]: {type: PitchBend, channel: 0-7, index: channel}
+  fader[8]: {type: PitchBend, channel: 8}
+";
+        let err = serde_yaml::from_str::<Profile>(source).unwrap_err();
+        assert!(err.to_string().contains("fader[1..8]"), "{err}");
+
+        let source = "
+name: desk
+controls:
+  fader[1..8]: {type: PitchBend, channel: 0-7, index: channel}
+";
+        let profile: Profile = serde_yaml::from_str(source).unwrap();
+        assert!(profile
+            .resolve(&control_ref("fader 3"), &HashMap::new())
+            .is_ok());
+    }
+
+    #[test]
+    fn converts_nrpn_values_with_added_tables() {
+        // The built-in `sq` profile has no tables,
+        // so this adds an example one as the README describes
+        let (_, source) = BUILTIN.iter().find(|(name, _)| *name == "sq").unwrap();
+        let mut profile: serde_yaml::Value = serde_yaml::from_str(source).unwrap();
+        profile["controls"]["input[1..48].level"]["units"] =
+            serde_yaml::from_str("{value: level}").unwrap();
+        profile["tables"] = serde_yaml::from_str(
+            "{level: {unit: dB, points: [[0, -.inf], [1, -90], [12288, 0], [16383, 10]]}}",
+        )
+        .unwrap();
+        let profile = Profile::from_yaml(&serde_yaml::to_string(&profile).unwrap()).unwrap();
+
+        let value = |quantity: &str| {
+            let fields = HashMap::from([(
+                "value".to_string(),
+                vec![FieldValue::Quantity(quantity.parse().unwrap())],
+            )]);
+            let template = profile
+                .resolve(&control_ref("input 12 level"), &fields)
+                .unwrap();
+            format!("{template:?}")
+        };
+        assert!(value("0 dB").contains("value: [Value(12288)]"));
+        assert!(value("-inf dB").contains("value: [Value(0)]"));
+        assert!(value("+10 dB").contains("value: [Value(16383)]"));
+        assert!(value("-inf dB .. 0 dB").contains("value: [Range(Range(0, 12288))]"));
+        assert!(matches!(
+            profile.resolve(
+                &control_ref("input 12 pan"),
+                &HashMap::from([(
+                    "value".to_string(),
+                    vec![FieldValue::Quantity("0 dB".parse().unwrap())]
+                )])
+            ),
+            Err(Error::NoUnits(..))
+        ));
+    }
+
+    #[test]
+    fn loads_builtin_profiles() {
+        for (name, source) in BUILTIN {
+            if let Err(err) = Profile::from_yaml(source) {
+                panic!("Built-in profile `{name}` didn't load: {err}");
+            }
+        }
+    }
+
+    /// Every reference which the pattern matches.
+    fn expand(pattern: &Pattern) -> Vec<ControlRef> {
+        let mut refs = vec![ControlRef {
+            segments: Vec::new(),
+        }];
+        for segment in pattern.segments.iter() {
+            let indices: Vec<Option<u32>> = match segment.range {
+                None => vec![None],
+                Some((a, b)) => (a..=b).map(Some).collect(),
+            };
+            refs = refs
+                .into_iter()
+                .flat_map(|control_ref| {
+                    indices.iter().map(move |index| {
+                        let mut control_ref = control_ref.clone();
+                        control_ref.segments.push((segment.name.clone(), *index));
+                        control_ref
+                    })
+                })
+                .collect();
+        }
+        refs
+    }
+
+    /// The messages for one example of the control,
+    /// with each field which isn't fixed set to some value it allows.
+    fn example(template: &MessageTemplate) -> Vec<Event> {
+        let mut template = template.clone();
+        let mut fields: Vec<String> = [
+            "channel",
+            "note",
+            "velocity",
+            "controller",
+            "value",
+            "program",
+            "pressure",
+            "bend",
+            "parameter",
+            "zone",
+            "port",
+            "state",
+            "position",
+        ]
+        .map(String::from)
+        .to_vec();
+        if let MessageTemplate::SysEx { data } = &template {
+            fields.extend(data.iter().filter_map(|element| match element {
+                SysExByte::Field { field, .. } => Some(field.clone()),
+                _ => None,
+            }));
+        }
+        for field in fields {
+            if let Some(numbers) = template.field_mut(&field) {
+                let value = match numbers.first() {
+                    Some(Number::Value(n)) | Some(Number::Range(Range(n, _))) => *n,
+                    _ => 1,
+                };
+                *numbers = vec![Number::Value(value)];
+            }
+        }
+        template.generate(Match::new()).unwrap()
+    }
+
+    #[test]
+    fn builtin_controls_have_different_messages() {
+        let search_path = SearchPath(Vec::new());
+        for (name, _) in BUILTIN {
+            let profile = search_path.load(name).unwrap();
+
+            // Grouped by their example's bytes up to the last,
+            // which is the value for most messages,
+            // since only controls in the same group can match each other's examples
+            let mut groups = HashMap::<_, Vec<_>>::new();
+            for (pattern, _) in profile.patterns.iter() {
+                for control_ref in expand(pattern) {
+                    let template = profile.resolve(&control_ref, &HashMap::new()).unwrap();
+                    // Matches the messages of the other clock controls on purpose
+                    if matches!(template, MessageTemplate::Sync) {
+                        continue;
+                    }
+                    let events = example(&template);
+                    let mut bytes: Vec<u8> =
+                        events.iter().flat_map(Event::as_bytes).copied().collect();
+                    bytes.pop();
+                    groups
+                        .entry(bytes)
+                        .or_default()
+                        .push((control_ref, template, events));
+                }
+            }
+
+            for group in groups.values() {
+                for (control_ref, _, events) in group.iter() {
+                    for (other_ref, other, _) in group.iter() {
+                        let mut state = State::default();
+                        let matched = events
+                            .iter()
+                            .any(|event| other.matches(&mut state, event.clone()).is_some());
+                        if other_ref == control_ref {
+                            assert!(
+                                matched,
+                                "In profile `{name}`, {events:?} from `{control_ref}` doesn't match itself"
+                            );
+                        } else {
+                            // Except for the parts of a control,
+                            // e.g. `vpot 1 ring dot` is `vpot 1 ring` in one of its modes
+                            assert!(
+                                !matched
+                                    || other_ref.segments.starts_with(&control_ref.segments)
+                                    || control_ref.segments.starts_with(&other_ref.segments),
+                                "In profile `{name}`, {events:?} from `{control_ref}` also matches `{other_ref}`"
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
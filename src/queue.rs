@@ -0,0 +1,368 @@
+//! The queues of messages waiting to be sent to devices,
+//! which can drop or combine messages when a device can't keep up.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc::error::SendError, Notify};
+
+/// What to do with a message when the queue is full.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Wait until there's space,
+    /// which holds up whatever's sending (e.g. a mapping).
+    #[default]
+    Block,
+
+    /// Throw away the message which has been waiting longest.
+    DropOldest,
+
+    /// Throw away the new message.
+    DropNewest,
+
+    /// Replace any waiting message which the new one [supersedes](Coalesce::supersedes),
+    /// whether or not the queue is full,
+    /// e.g. so that only the latest position of a fader is sent.
+    /// Otherwise, wait until there's space.
+    Coalesce,
+}
+
+/// Messages which can make earlier ones redundant.
+pub trait Coalesce {
+    /// Whether this message makes an earlier one redundant,
+    /// because it sets the same thing to a new value
+    /// (e.g. the same controller on the same channel).
+    fn supersedes(&self, earlier: &Self) -> bool;
+
+    /// Whether this message uses the ones before it
+    /// (e.g. a MIDI program change uses the bank select before it),
+    /// so none of them can be replaced by a later message.
+    fn depends_on_earlier(&self) -> bool {
+        false
+    }
+}
+
+/// Creates a queue which holds up to `capacity` messages,
+/// and deals with any more as `overflow` says.
+pub fn channel<T: Coalesce>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            queue: VecDeque::with_capacity(capacity),
+            senders: 1,
+            receiving: true,
+        }),
+        sent: Notify::new(),
+        received: Notify::new(),
+        // A queue with no space would never send anything
+        capacity: capacity.max(1),
+        overflow,
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+
+    /// Wakes the receiver when there's a new message,
+    /// or when the last sender has gone.
+    sent: Notify,
+
+    /// Wakes the senders when there's space,
+    /// or when the receiver has gone.
+    received: Notify,
+
+    capacity: usize,
+    overflow: Overflow,
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+
+    /// How many senders there are, so the receiver knows when nothing else will be sent.
+    senders: usize,
+
+    /// Whether the receiver is still there.
+    receiving: bool,
+}
+
+/// Sends messages to a queue.
+///
+/// This can be cloned to send from several places.
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Coalesce> Sender<T> {
+    /// Adds a message to the queue,
+    /// waiting for space if the queue is full and its overflow is [`Overflow::Block`].
+    ///
+    /// Returns the message if the receiver has gone.
+    pub async fn send(&self, message: T) -> Result<(), SendError<T>> {
+        loop {
+            // Made before looking at the queue, so that space made after that isn't missed
+            let received = self.shared.received.notified();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if !state.receiving {
+                    return Err(SendError(message));
+                }
+
+                if self.shared.overflow == Overflow::Coalesce {
+                    // The new message goes at the back,
+                    // so that it's still sent after anything sent before it
+                    let mut i = state
+                        .queue
+                        .iter()
+                        .rposition(Coalesce::depends_on_earlier)
+                        .map_or(0, |i| i + 1);
+                    while i < state.queue.len() {
+                        if message.supersedes(&state.queue[i]) {
+                            state.queue.remove(i);
+                        } else {
+                            i += 1;
+                        }
+                    }
+                }
+
+                if state.queue.len() < self.shared.capacity {
+                    state.queue.push_back(message);
+                    self.shared.sent.notify_waiters();
+                    return Ok(());
+                }
+
+                match self.shared.overflow {
+                    Overflow::DropOldest => {
+                        log::debug!("Queue is full, dropping its oldest message");
+                        state.queue.pop_front();
+                        state.queue.push_back(message);
+                        self.shared.sent.notify_waiters();
+                        return Ok(());
+                    }
+                    Overflow::DropNewest => {
+                        log::debug!("Queue is full, dropping a new message");
+                        return Ok(());
+                    }
+                    Overflow::Block | Overflow::Coalesce => {}
+                }
+            }
+
+            received.await;
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.sent.notify_waiters();
+        }
+    }
+}
+
+/// Receives the messages from a queue, in order.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next message,
+    /// or returns `None` once the queue is empty and every sender has gone.
+    ///
+    /// This is cancel safe.
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            // Made before looking at the queue, so that messages sent after that aren't missed
+            let sent = self.shared.sent.notified();
+
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some(message) = state.queue.pop_front() {
+                    self.shared.received.notify_waiters();
+                    return Some(message);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+
+            sent.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().receiving = false;
+        self.shared.received.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+    use tokio::time;
+
+    use super::*;
+
+    /// Sets a control (the first number) to a value,
+    /// or with no control, depends on the messages before it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Set(Option<u8>, u8);
+
+    impl Coalesce for Set {
+        fn supersedes(&self, earlier: &Self) -> bool {
+            self.0.is_some() && self.0 == earlier.0
+        }
+
+        fn depends_on_earlier(&self) -> bool {
+            self.0.is_none()
+        }
+    }
+
+    fn set(control: u8, value: u8) -> Set {
+        Set(Some(control), value)
+    }
+
+    /// Receives whatever's waiting in the queue.
+    fn drain(rx: &mut Receiver<Set>) -> Vec<Set> {
+        std::iter::from_fn(|| rx.recv().now_or_never().flatten()).collect()
+    }
+
+    #[tokio::test]
+    async fn blocks_until_there_is_space() {
+        let (tx, mut rx) = channel(1, Overflow::Block);
+        tx.send(set(1, 1)).await.unwrap();
+
+        let blocked = tx.send(set(1, 2));
+        tokio::pin!(blocked);
+        assert!((&mut blocked).now_or_never().is_none());
+
+        assert_eq!(rx.recv().await, Some(set(1, 1)));
+        time::timeout(Duration::from_secs(1), blocked)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(drain(&mut rx), [set(1, 2)]);
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_message() {
+        let (tx, mut rx) = channel(2, Overflow::DropOldest);
+        for value in 1..=3 {
+            tx.send(set(value, value)).await.unwrap();
+        }
+        assert_eq!(drain(&mut rx), [set(2, 2), set(3, 3)]);
+    }
+
+    #[tokio::test]
+    async fn drops_the_newest_message() {
+        let (tx, mut rx) = channel(2, Overflow::DropNewest);
+        for value in 1..=3 {
+            tx.send(set(value, value)).await.unwrap();
+        }
+        assert_eq!(drain(&mut rx), [set(1, 1), set(2, 2)]);
+    }
+
+    #[tokio::test]
+    async fn coalesces_messages_for_the_same_control() {
+        let (tx, mut rx) = channel(4, Overflow::Coalesce);
+        for message in [set(1, 1), set(2, 1), set(1, 2), set(1, 3)] {
+            tx.send(message).await.unwrap();
+        }
+        assert_eq!(drain(&mut rx), [set(2, 1), set(1, 3)]);
+
+        // Still blocks once it's full of different controls
+        for control in 1..=4 {
+            tx.send(set(control, 1)).await.unwrap();
+        }
+        assert!(tx.send(set(5, 1)).now_or_never().is_none());
+        assert_eq!(drain(&mut rx).len(), 4);
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_which_later_ones_depend_on() {
+        let (tx, mut rx) = channel(8, Overflow::Coalesce);
+        for message in [set(1, 1), Set(None, 1), set(1, 2), set(1, 3)] {
+            tx.send(message).await.unwrap();
+        }
+        assert_eq!(drain(&mut rx), [set(1, 1), Set(None, 1), set(1, 3)]);
+    }
+
+    #[tokio::test]
+    async fn finishes_once_every_sender_has_gone() {
+        let (tx, mut rx) = channel(2, Overflow::Block);
+        let tx2 = tx.clone();
+        tx.send(set(1, 1)).await.unwrap();
+        drop(tx);
+        assert_eq!(rx.recv().await, Some(set(1, 1)));
+        assert!(rx.recv().now_or_never().is_none());
+
+        // Anything still waiting is received first
+        tx2.send(set(1, 2)).await.unwrap();
+        drop(tx2);
+        assert_eq!(rx.recv().await, Some(set(1, 2)));
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn stops_sending_once_the_receiver_has_gone() {
+        let (tx, rx) = channel(1, Overflow::Block);
+        tx.send(set(1, 1)).await.unwrap();
+
+        let blocked = tx.send(set(1, 2));
+        tokio::pin!(blocked);
+        assert!((&mut blocked).now_or_never().is_none());
+
+        drop(rx);
+        let result = time::timeout(Duration::from_secs(1), blocked)
+            .await
+            .unwrap();
+        assert_eq!(result.unwrap_err().0, set(1, 2));
+        assert_eq!(tx.send(set(1, 3)).await.unwrap_err().0, set(1, 3));
+    }
+
+    #[tokio::test]
+    async fn receiving_is_cancel_safe() {
+        let (tx, mut rx) = channel(2, Overflow::Block);
+
+        // Gives up on receiving part way through, as a `select!` would
+        tokio::select! {
+            _ = rx.recv() => panic!("nothing was sent"),
+            _ = time::sleep(Duration::from_millis(10)) => {}
+        }
+        tx.send(set(1, 1)).await.unwrap();
+        assert_eq!(rx.recv().await, Some(set(1, 1)));
+
+        // And a message sent while waiting isn't lost if the wait is given up
+        {
+            let recv = rx.recv();
+            tokio::pin!(recv);
+            assert!((&mut recv).now_or_never().is_none());
+            tx.send(set(1, 2)).await.unwrap();
+        }
+        assert_eq!(rx.recv().await, Some(set(1, 2)));
+    }
+}
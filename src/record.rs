@@ -0,0 +1,203 @@
+//! Records the messages sent by devices to a Standard MIDI File.
+
+use std::{
+    io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use midly::{
+    num::u28, Arena, Format, Fps, Header, MetaMessage, Smf, Timing, Track, TrackEvent,
+    TrackEventKind,
+};
+use tokio::{
+    sync::oneshot,
+    task::{JoinError, JoinHandle},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+use crate::{device::Device, message::Message, midi::Event};
+
+/// Frames per second and subframes per frame of the file's timing,
+/// which together give one tick per millisecond.
+const FPS: Fps = Fps::Fps25;
+const SUBFRAMES: u8 = 40;
+
+/// The most ticks which an event in the file can come after the one before it.
+const MAX_DELTA: u64 = (1 << 28) - 1;
+
+/// Records the MIDI messages from some devices until it's [finished](Recorder::finish).
+pub struct Recorder {
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<Recording>,
+}
+
+/// The messages recorded from each device,
+/// with the time since the recording started.
+#[derive(Debug, Clone, Default)]
+pub struct Recording {
+    pub tracks: Vec<(String, Vec<(Duration, Event)>)>,
+}
+
+impl Recorder {
+    /// Starts recording the messages from the given devices.
+    pub fn start<'a, I>(devices: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Device<Message>>,
+    {
+        let mut recording = Recording::default();
+        let mut streams = Vec::new();
+        for (i, device) in devices.into_iter().enumerate() {
+            recording.tracks.push((device.name.clone(), Vec::new()));
+            streams.push(BroadcastStream::new(device.subscribe()).map(move |msg| (i, msg)));
+        }
+        let mut stream = futures::stream::select_all(streams);
+
+        let (stop, mut stopped) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let start = Instant::now();
+            loop {
+                tokio::select! {
+                    Some((i, msg)) = stream.next() => match msg {
+                        Ok(Message::Midi(event)) => recording.tracks[i].1.push((start.elapsed(), event)),
+                        // Only MIDI can go in a MIDI file
+                        Ok(_) => (),
+                        Err(err) => log::warn!("Missed messages from {} while recording: {err}", recording.tracks[i].0),
+                    },
+                    _ = &mut stopped => break,
+                    else => break,
+                }
+            }
+            recording
+        });
+
+        Recorder { stop, task }
+    }
+
+    /// Stops recording, and returns everything recorded,
+    /// or an error if the recording task panicked.
+    pub async fn finish(self) -> Result<Recording, JoinError> {
+        // The task might have already finished, if all the devices did
+        let _ = self.stop.send(());
+        self.task.await
+    }
+}
+
+impl Recording {
+    /// Saves the recording as a Standard MIDI File,
+    /// with a track for each device, named after the device.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let arena = Arena::new();
+        let mut tracks = Vec::new();
+
+        for (name, events) in self.tracks.iter() {
+            let mut track: Track = vec![TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::TrackName(arena.add(name.as_bytes()))),
+            }];
+
+            let mut last_tick: u64 = 0;
+            for (time, event) in events.iter() {
+                let Some(live_event) = event.live_event() else {
+                    continue;
+                };
+                let tick = u64::try_from(time.as_millis()).unwrap_or(u64::MAX);
+                let mut delta = tick.saturating_sub(last_tick);
+                // A gap longer than an event can wait for (about 74 hours)
+                // is made up with empty markers
+                while delta > MAX_DELTA {
+                    track.push(TrackEvent {
+                        delta: u28::max_value(),
+                        kind: TrackEventKind::Meta(MetaMessage::Marker(&[])),
+                    });
+                    delta -= MAX_DELTA;
+                }
+                track.push(TrackEvent {
+                    delta: u28::new(delta as u32),
+                    kind: live_event.as_track_event(&arena),
+                });
+                last_tick = tick;
+            }
+
+            track.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
+            });
+            tracks.push(track);
+        }
+
+        let smf = Smf {
+            header: Header::new(Format::Parallel, Timing::Timecode(FPS, SUBFRAMES)),
+            tracks,
+        };
+        smf.save(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use midly::{
+        live::{LiveEvent, SystemRealtime},
+        num::u4,
+        MidiMessage,
+    };
+
+    use super::*;
+    use crate::midi::playback;
+
+    fn control_change(value: u8) -> Event {
+        Event::from(LiveEvent::Midi {
+            channel: u4::new(0),
+            message: MidiMessage::Controller {
+                controller: 7.into(),
+                value: value.into(),
+            },
+        })
+    }
+
+    #[test]
+    fn saves_recordings_which_play_back_the_same() {
+        let path =
+            std::env::temp_dir().join(format!("gobetween-record-{}.mid", std::process::id()));
+        let fader = vec![
+            (Duration::ZERO, control_change(1)),
+            (Duration::from_millis(1), control_change(2)),
+            (Duration::from_millis(1001), control_change(3)),
+            (Duration::from_millis(1001), control_change(4)),
+            // Further apart than one event can wait for
+            (Duration::from_secs(80 * 60 * 60), control_change(5)),
+            // Past where a 32-bit count of milliseconds wraps
+            (Duration::from_secs(50 * 24 * 60 * 60), control_change(6)),
+        ];
+        let desk = vec![
+            (
+                Duration::from_millis(500),
+                Event::sysex(&[0x00, 0x01]).unwrap(),
+            ),
+            (
+                Duration::from_millis(1500),
+                Event::from(LiveEvent::Realtime(SystemRealtime::Start)),
+            ),
+        ];
+        let recording = Recording {
+            tracks: vec![
+                ("fader".to_string(), fader.clone()),
+                ("desk".to_string(), desk.clone()),
+            ],
+        };
+        recording.save(&path).unwrap();
+
+        let fader_played = playback::load(&path, Some("fader"));
+        let desk_played = playback::load(&path, Some("desk"));
+        let all_played = playback::load(&path, None);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(fader_played.unwrap(), fader);
+        assert_eq!(desk_played.unwrap(), desk);
+        let mut all = [fader, desk].concat();
+        all.sort_by_key(|(time, _)| *time);
+        assert_eq!(all_played.unwrap(), all);
+    }
+}
@@ -0,0 +1,206 @@
+//! Checks that a config's mappings turn messages into the expected ones,
+//! without connecting to any devices.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    path::Path,
+    process::ExitCode,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use serde_with::{serde_as, DurationMilliSeconds};
+use tokio::time::Instant;
+
+use gobetween::{
+    config::{self, MessageTemplate, TemplateRef},
+    engine::{self, Route},
+    message::Message,
+    message::Template,
+    profile::Profile,
+};
+
+/// One test case, e.g.
+///
+/// ```yaml
+/// - name: fader 1 sets the level of input 1
+///   send:
+///     - {device: fp, control: fader 1, value: 0 dB}
+///   expect:
+///     sq:
+///       - {control: "input[1].level", value: 0 dB}
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct Case {
+    pub name: String,
+
+    /// The messages sent by the devices, in order.
+    pub send: Vec<DeviceMessage>,
+
+    /// The messages which should be sent to each device, in order.
+    /// Any device not listed should be sent nothing.
+    #[serde(default)]
+    pub expect: HashMap<String, Vec<TemplateRef>>,
+}
+
+/// A message, and the device it comes from or goes to.
+#[serde_as]
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeviceMessage {
+    pub device: String,
+
+    /// When a message is sent, in milliseconds from the start of the case,
+    /// e.g. to hold a button down.
+    /// By default, it's sent at the same time as the message before.
+    #[serde_as(as = "Option<DurationMilliSeconds<u64>>")]
+    #[serde(default)]
+    pub at: Option<Duration>,
+
+    #[serde(flatten)]
+    pub message: TemplateRef,
+}
+
+/// Runs each of the test cases through the config's mappings,
+/// and prints which of them passed.
+///
+/// Returns an error status if any of them failed.
+pub fn test(config_path: &Path, cases_path: &Path) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let config = engine::load_config(config_path)?;
+    let profiles = engine::load_profiles(config_path, &config)?;
+    let cases: Vec<Case> = serde_yaml::from_reader(File::open(cases_path)?)?;
+
+    let device_names: Vec<&String> = config.devices.iter().map(|device| &device.name).collect();
+    let check_device = |name: &String| {
+        if device_names.contains(&name) {
+            Ok(())
+        } else {
+            Err(config::Error::DeviceNotFound(name.clone()))
+        }
+    };
+
+    let mut failed = 0;
+    for case in cases.iter() {
+        // Each case starts from scratch,
+        // so that e.g. a half-sent NRPN doesn't carry over
+        let mut routes = engine::resolve_mappings(config.mappings.clone(), &profiles)?;
+        for route in routes.iter() {
+            check_device(&route.from)?;
+            check_device(&route.to)?;
+        }
+
+        // The time is made up, so that e.g. holding a button doesn't take any real time
+        let start = Instant::now();
+        let mut now = start;
+        let mut sent: HashMap<String, Vec<Message>> = HashMap::new();
+        for DeviceMessage {
+            device,
+            at,
+            message,
+        } in case.send.iter()
+        {
+            check_device(device)?;
+            if let Some(at) = at {
+                if start + *at < now {
+                    return Err(Error::BackInTime(case.name.clone(), at.as_millis()).into());
+                }
+                now = start + *at;
+                tick(&mut routes, Some(now), &mut sent);
+            }
+            for message in generate(&case.name, device, message, &profiles)? {
+                for route in routes.iter_mut().filter(|route| &route.from == device) {
+                    let out = route.transformer.transform_message(message.clone(), now);
+                    sent.entry(route.to.clone()).or_default().extend(out);
+                }
+            }
+        }
+        // Anything still waiting (e.g. a tap waiting to see if there's a double press) is sent
+        tick(&mut routes, None, &mut sent);
+
+        let mut expected: HashMap<String, Vec<Message>> = HashMap::new();
+        for (device, messages) in case.expect.iter() {
+            check_device(device)?;
+            for message in messages.iter() {
+                expected
+                    .entry(device.clone())
+                    .or_default()
+                    .extend(generate(&case.name, device, message, &profiles)?);
+            }
+        }
+
+        // Sorted, so that failures are printed in a consistent order
+        let devices: BTreeSet<&String> = sent.keys().chain(expected.keys()).collect();
+        let wrong: Vec<&String> = devices
+            .into_iter()
+            .filter(|device| {
+                sent.get(*device).unwrap_or(&Vec::new())
+                    != expected.get(*device).unwrap_or(&Vec::new())
+            })
+            .collect();
+
+        if wrong.is_empty() {
+            println!("ok     {}", case.name);
+        } else {
+            failed += 1;
+            println!("FAILED {}", case.name);
+            for device in wrong {
+                println!("  {device}: expected");
+                for message in expected.get(device).into_iter().flatten() {
+                    println!("    {message}");
+                }
+                println!("  but was sent");
+                for message in sent.get(device).into_iter().flatten() {
+                    println!("    {message}");
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {failed} failed", cases.len() - failed);
+    Ok(if failed > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Sends the messages which the mappings send by themselves (e.g. once a button is held down)
+/// up to `until`, or until they have nothing left to send.
+fn tick(routes: &mut [Route], until: Option<Instant>, sent: &mut HashMap<String, Vec<Message>>) {
+    loop {
+        let due = routes
+            .iter()
+            .filter_map(|route| route.transformer.next_due())
+            .min();
+        let Some(due) = due.filter(|due| until.is_none_or(|until| *due <= until)) else {
+            return;
+        };
+        for route in routes.iter_mut() {
+            let out = route.transformer.tick(due);
+            sent.entry(route.to.clone()).or_default().extend(out);
+        }
+    }
+}
+
+/// Generates the messages for a template which gives a single value for every field.
+fn generate(
+    case: &str,
+    device: &str,
+    message: &TemplateRef,
+    profiles: &HashMap<String, Profile>,
+) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+    let template = message.resolve(device, profiles.get(device))?;
+    template
+        .generate(Default::default())
+        .map(|events| events.into_iter().map(Message::from).collect())
+        .ok_or_else(|| Error::Incomplete(case.to_string(), device.to_string(), template).into())
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("Message for `{1}` in test case `{0}` doesn't give one value for every field: {2:?}")]
+    Incomplete(String, String, MessageTemplate),
+
+    #[error("Message in test case `{0}` is sent at {1} ms, before the message before it")]
+    BackInTime(String, u128),
+}
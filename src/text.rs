@@ -0,0 +1,457 @@
+//! Text for displays such as scribble strips and LCDs,
+//! generated from the fields of a matched message.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{DeserializeFromStr, SerializeDisplay};
+
+use crate::{
+    message::{Match, NumberMatch},
+    profile::Table,
+};
+
+/// A format string such as `Ch {channel+1}: {value_db:.1} dB`.
+///
+/// Each placeholder names a field of the matched message,
+/// optionally followed by a number to add or subtract,
+/// and optionally followed by `:` and a format spec
+/// of the form `[0][width][.precision]`.
+///
+/// A field with units (e.g. `value` for a level with a dB table)
+/// can also be converted into those units
+/// by adding the name of the table or units to the field name,
+/// e.g. `{value_db}` or `{value_level}`.
+///
+/// Use `{{` and `}}` for literal braces.
+#[derive(SerializeDisplay, DeserializeFromStr, Debug, Clone, Default)]
+pub struct Format {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Placeholder {
+        name: String,
+        offset: i64,
+        spec: Spec,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Spec {
+    zero_pad: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// Where to put the text when it's shorter than the display.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Align {
+    #[default]
+    Left,
+    Right,
+    Centre,
+}
+
+/// The characters a display can show.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    /// Printable ASCII.
+    #[default]
+    Ascii,
+
+    /// Printable ASCII, with letters in upper case.
+    Uppercase,
+}
+
+/// A value to be formatted into a placeholder.
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Int(i64),
+    Float(f64),
+}
+
+impl Format {
+    /// Whether the format has no placeholders,
+    /// i.e. it always gives the same text.
+    pub fn is_literal(&self) -> bool {
+        self.parts
+            .iter()
+            .all(|part| matches!(part, Part::Literal(_)))
+    }
+
+    /// Formats the text from the fields of a match,
+    /// converting fields into real units using `units` where asked.
+    ///
+    /// Fields matched against a range are given as a percentage through the range.
+    /// Any placeholder which can't be filled in is shown as `?`.
+    pub fn render(&self, matched: &Match, units: &HashMap<String, Table>) -> String {
+        let mut out = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Placeholder { name, offset, spec } => {
+                    match lookup(name, matched, units) {
+                        Some(value) => out.push_str(&spec.format(value, *offset)),
+                        None => {
+                            log::debug!("Couldn't fill in `{{{name}}}` in `{}`", self.source);
+                            out.push('?');
+                        }
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Finds the value of a placeholder.
+fn lookup(name: &str, matched: &Match, units: &HashMap<String, Table>) -> Option<Value> {
+    let raw = |field: &str| match matched.get(field)? {
+        (_, NumberMatch::Value(n)) => Some(*n),
+        (_, NumberMatch::Range(_)) => None,
+    };
+
+    if let Some((_, number_match)) = matched.get(name) {
+        return Some(match number_match {
+            NumberMatch::Value(n) => Value::Int(*n as i64),
+            NumberMatch::Range(position) => Value::Int((position * 100.0).round() as i64),
+        });
+    }
+
+    // e.g. `value_db`
+    for (i, _) in name.match_indices('_') {
+        let (field, suffix) = (&name[..i], &name[i + 1..]);
+        let Some(table) = units.get(field) else {
+            continue;
+        };
+        if table.name == suffix || table.unit.to_lowercase() == suffix.to_lowercase() {
+            return Some(Value::Float(table.to_units(raw(field)?)));
+        }
+    }
+
+    None
+}
+
+impl Spec {
+    fn format(&self, value: Value, offset: i64) -> String {
+        let s = match value {
+            Value::Int(n) => (n + offset).to_string(),
+            Value::Float(x) if x.is_infinite() => {
+                if x < 0.0 { "-inf" } else { "inf" }.to_string()
+            }
+            Value::Float(x) => {
+                format!("{:.*}", self.precision.unwrap_or(1), x + offset as f64)
+            }
+        };
+
+        if s.len() >= self.width {
+            s
+        } else if self.zero_pad {
+            // Keep any sign at the front
+            let (sign, digits) = match s.strip_prefix('-') {
+                Some(digits) => ("-", digits),
+                None => ("", &s[..]),
+            };
+            let zeros = "0".repeat(self.width - s.len());
+            format!("{sign}{zeros}{digits}")
+        } else {
+            format!("{s:>width$}", width = self.width)
+        }
+    }
+}
+
+/// Fits text onto a display:
+/// replaces characters the display can't show,
+/// then pads or truncates it to `width` characters (if given).
+pub fn fit(text: &str, width: Option<usize>, align: Align, charset: Charset) -> String {
+    let text: String = text
+        .chars()
+        .map(|c| {
+            let c = transliterate(c);
+            let c = if (' '..='~').contains(&c) { c } else { '?' };
+            match charset {
+                Charset::Ascii => c,
+                Charset::Uppercase => c.to_ascii_uppercase(),
+            }
+        })
+        .collect();
+
+    let Some(width) = width else {
+        return text;
+    };
+
+    let text: String = text.chars().take(width).collect();
+    match align {
+        Align::Left => format!("{text:<width$}"),
+        Align::Right => format!("{text:>width$}"),
+        Align::Centre => format!("{text:^width$}"),
+    }
+}
+
+/// Replaces common accented letters with their plain ASCII equivalents.
+fn transliterate(c: char) -> char {
+    match c {
+        'À'..='Å' => 'A',
+        'à'..='å' => 'a',
+        'Ç' => 'C',
+        'ç' => 'c',
+        'È'..='Ë' => 'E',
+        'è'..='ë' => 'e',
+        'Ì'..='Ï' => 'I',
+        'ì'..='ï' => 'i',
+        'Ñ' => 'N',
+        'ñ' => 'n',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'ò'..='ö' | 'ø' => 'o',
+        'Ù'..='Ü' => 'U',
+        'ù'..='ü' => 'u',
+        'Ý' => 'Y',
+        'ý' | 'ÿ' => 'y',
+        'ß' => 's',
+        '\t' => ' ',
+        _ => c,
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '}' => return Err(format!("unmatched `}}` in `{s}`")),
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => return Err(format!("unmatched `{{` in `{s}`")),
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(parse_placeholder(&placeholder)?);
+                }
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Format {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+/// Parses the inside of a placeholder, e.g. `channel+1:02`.
+fn parse_placeholder(s: &str) -> Result<Part, String> {
+    let (expr, spec) = s.split_once(':').unwrap_or((s, ""));
+    let expr = expr.trim();
+
+    let (name, offset) = match expr.find(['+', '-']) {
+        Some(i) => {
+            let offset: i64 = expr[i + 1..]
+                .trim()
+                .parse()
+                .map_err(|_| format!("bad number in `{{{s}}}`"))?;
+            let offset = if expr[i..].starts_with('-') {
+                -offset
+            } else {
+                offset
+            };
+            (expr[..i].trim(), offset)
+        }
+        None => (expr, 0),
+    };
+
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(format!("bad field name in `{{{s}}}`"));
+    }
+
+    let (zero_pad, spec) = match spec.strip_prefix('0') {
+        Some(rest) => (true, rest),
+        None => (false, spec),
+    };
+    let (width, precision) = spec.split_once('.').unwrap_or((spec, ""));
+    let bad_spec = |_| format!("bad format spec in `{{{s}}}`");
+    let spec = Spec {
+        zero_pad,
+        width: if width.is_empty() {
+            0
+        } else {
+            width.parse().map_err(bad_spec)?
+        },
+        precision: if precision.is_empty() {
+            None
+        } else {
+            Some(precision.parse().map_err(bad_spec)?)
+        },
+    };
+
+    Ok(Part::Placeholder {
+        name: name.to_string(),
+        offset,
+        spec,
+    })
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(format: &str, fields: &[(&str, NumberMatch)]) -> String {
+        let format: Format = format.parse().unwrap();
+        let matched = fields
+            .iter()
+            .map(|(name, number_match)| (name.to_string(), (0, number_match.clone())))
+            .collect();
+        let units = HashMap::from([(
+            "value".to_string(),
+            Table {
+                name: "level".to_string(),
+                unit: "dB".to_string(),
+                points: vec![(0, f64::NEG_INFINITY), (8192, -10.0), (16383, 10.0)],
+            },
+        )]);
+        format.render(&matched, &units)
+    }
+
+    #[test]
+    fn parses_formats() {
+        let format: Format = "Ch {channel+1}: {{{value_db:.1}}}".parse().unwrap();
+        assert!(!format.is_literal());
+        assert_eq!(format.to_string(), "Ch {channel+1}: {{{value_db:.1}}}");
+        assert!("Just {{text}}".parse::<Format>().unwrap().is_literal());
+        assert!("".parse::<Format>().unwrap().is_literal());
+
+        for bad in [
+            "{channel",
+            "channel}",
+            "{}",
+            "{ch an}",
+            "{channel+}",
+            "{channel+x}",
+            "{channel:x}",
+            "{channel:2.x}",
+            "{channel:-2}",
+        ] {
+            assert!(bad.parse::<Format>().is_err(), "`{bad}` should be rejected");
+        }
+    }
+
+    #[test]
+    fn fills_in_fields() {
+        let channel = [("channel", NumberMatch::Value(2))];
+        assert_eq!(render("Ch {channel}", &channel), "Ch 2");
+        assert_eq!(render("Ch {channel+1}", &channel), "Ch 3");
+        assert_eq!(render("Ch { channel - 3 }", &channel), "Ch -1");
+        assert_eq!(render("{{{channel}}}", &channel), "{2}");
+        assert_eq!(render("{note}", &channel), "?");
+
+        // As a percentage through the range
+        let value = [("value", NumberMatch::Range(0.255))];
+        assert_eq!(render("{value}%", &value), "26%");
+    }
+
+    #[test]
+    fn pads_to_width() {
+        let fields = [
+            ("a", NumberMatch::Value(7)),
+            ("b", NumberMatch::Value(12345)),
+        ];
+        assert_eq!(render("[{a:3}]", &fields), "[  7]");
+        assert_eq!(render("[{a:03}]", &fields), "[007]");
+        assert_eq!(render("[{a-10:04}]", &fields), "[-003]");
+        assert_eq!(render("[{a-10:4}]", &fields), "[  -3]");
+        // Never shortened
+        assert_eq!(render("[{b:03}]", &fields), "[12345]");
+        // Precision is only for values in units
+        assert_eq!(render("[{a:.2}]", &fields), "[7]");
+    }
+
+    #[test]
+    fn converts_into_units() {
+        let value = |n| [("value", NumberMatch::Value(n))];
+        assert_eq!(render("{value_db}", &value(8192)), "-10.0");
+        assert_eq!(render("{value_dB:.2}", &value(12288)), "0.00");
+        assert_eq!(render("{value_level:.0}", &value(16383)), "10");
+        assert_eq!(render("{value_db:06.1}", &value(8192)), "-010.0");
+        assert_eq!(render("{value_db+5:.0}", &value(8192)), "-5");
+        assert_eq!(render("{value_db:5}", &value(0)), " -inf");
+        assert_eq!(render("{value_pan}", &value(0)), "?");
+        // Only the raw value can be converted
+        assert_eq!(
+            render("{value_db}", &[("value", NumberMatch::Range(0.5))]),
+            "?"
+        );
+    }
+
+    #[test]
+    fn fits_text() {
+        assert_eq!(fit("Vocals", None, Align::Left, Charset::Ascii), "Vocals");
+        assert_eq!(fit("Vocals", Some(4), Align::Left, Charset::Ascii), "Voca");
+        assert_eq!(
+            fit("Vocals", Some(6), Align::Right, Charset::Ascii),
+            "Vocals"
+        );
+        assert_eq!(fit("Vox", Some(7), Align::Left, Charset::Ascii), "Vox    ");
+        assert_eq!(fit("Vox", Some(7), Align::Right, Charset::Ascii), "    Vox");
+        assert_eq!(
+            fit("Vox", Some(7), Align::Centre, Charset::Ascii),
+            "  Vox  "
+        );
+        assert_eq!(fit("Vox", Some(6), Align::Centre, Charset::Ascii), " Vox  ");
+        assert_eq!(fit("Vox", Some(0), Align::Left, Charset::Ascii), "");
+        assert_eq!(
+            fit("Vox 1", Some(5), Align::Left, Charset::Uppercase),
+            "VOX 1"
+        );
+    }
+
+    #[test]
+    fn transliterates_text() {
+        assert_eq!(
+            fit("Café Noël", None, Align::Left, Charset::Ascii),
+            "Cafe Noel"
+        );
+        assert_eq!(
+            fit("Straße\tØ", None, Align::Left, Charset::Uppercase),
+            "STRASE O"
+        );
+        // Anything else is replaced, one character each, before fitting
+        assert_eq!(fit("Bass ♯", None, Align::Left, Charset::Ascii), "Bass ?");
+        assert_eq!(fit("日本語", Some(5), Align::Left, Charset::Ascii), "???  ");
+        assert_eq!(fit("a\nb", None, Align::Left, Charset::Ascii), "a?b");
+    }
+}
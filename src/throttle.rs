@@ -0,0 +1,309 @@
+//! Limits how often a mapping sends new values for each control,
+//! for devices which can't keep up with e.g. motor faders.
+
+use std::{collections::HashMap, time::Duration};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+use tokio::time::Instant;
+
+use crate::message::Message;
+
+/// The lowest `rate` a mapping can have, in messages per second.
+const MIN_RATE: f64 = 0.01;
+
+/// How often a mapping can send each control, e.g. `{rate: 50, deadband: 2}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// The most messages per second to send for each control.
+    /// The last value of a burst is always sent, once it's allowed.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_rate",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub rate: Option<f64>,
+
+    /// Changes smaller than this (in the value sent, e.g. 0-16383 for an NRPN)
+    /// aren't sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadband: Option<u32>,
+}
+
+/// Holds back the messages from a mapping which would go over its [`Limits`],
+/// and gives them back once they're allowed.
+///
+/// The messages generated from one incoming message are dealt with together,
+/// so e.g. an NRPN's four control changes are kept together.
+/// Only MIDI messages which set a value are limited;
+/// anything else (e.g. notes) is sent straight away.
+// @Todo: limit OSC and DMX messages too
+pub struct Throttle {
+    limits: Limits,
+
+    /// Keyed by what the messages control, without their values.
+    controls: HashMap<Vec<u8>, Control>,
+}
+
+struct Control {
+    /// When messages for the control were last sent.
+    sent_at: Instant,
+
+    /// The value they set it to.
+    value: u32,
+
+    /// The latest messages for the control, and their value,
+    /// if they're waiting to be sent.
+    pending: Option<(u32, Vec<Message>)>,
+}
+
+impl Throttle {
+    pub fn new(limits: Limits) -> Self {
+        Throttle {
+            limits,
+            controls: HashMap::new(),
+        }
+    }
+
+    /// The shortest time between messages for each control.
+    fn interval(&self) -> Duration {
+        match self.limits.rate {
+            // Config files can't go below the minimum,
+            // but this keeps other limits from overflowing
+            Some(rate) if rate > 0.0 => Duration::from_secs_f64(1.0 / rate.max(MIN_RATE)),
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Returns the messages which can be sent now,
+    /// holding them back if they're too soon after the last ones for the same control,
+    /// or dropping them if they're within the deadband.
+    pub fn offer(&mut self, messages: Vec<Message>, now: Instant) -> Vec<Message> {
+        if self.limits == Limits::default() {
+            return messages;
+        }
+        let Some((key, value)) = control_value(&messages) else {
+            return messages;
+        };
+        let interval = self.interval();
+
+        let Some(control) = self.controls.get_mut(&key) else {
+            self.controls.insert(
+                key,
+                Control {
+                    sent_at: now,
+                    value,
+                    pending: None,
+                },
+            );
+            return messages;
+        };
+
+        if let Some(deadband) = self.limits.deadband {
+            if value.abs_diff(control.value) < deadband {
+                // Anything still waiting is further from where the control has ended up
+                control.pending = None;
+                return Vec::new();
+            }
+        }
+
+        if now >= control.sent_at + interval {
+            control.sent_at = now;
+            control.value = value;
+            control.pending = None;
+            messages
+        } else {
+            control.pending = Some((value, messages));
+            Vec::new()
+        }
+    }
+
+    /// When the next held-back messages can be sent, if there are any.
+    pub fn next_due(&self) -> Option<Instant> {
+        let interval = self.interval();
+        self.controls
+            .values()
+            .filter(|control| control.pending.is_some())
+            .map(|control| control.sent_at + interval)
+            .min()
+    }
+
+    /// Takes the held-back messages which can be sent by `now`.
+    pub fn take_due(&mut self, now: Instant) -> Vec<Message> {
+        let interval = self.interval();
+        self.take(|control| now >= control.sent_at + interval, now)
+    }
+
+    /// Takes all the held-back messages, e.g. before the mapping finishes.
+    pub fn take_all(&mut self, now: Instant) -> Vec<Message> {
+        self.take(|_| true, now)
+    }
+
+    fn take(&mut self, due: impl Fn(&Control) -> bool, now: Instant) -> Vec<Message> {
+        let mut messages = Vec::new();
+        for control in self.controls.values_mut() {
+            if control.pending.is_none() || !due(control) {
+                continue;
+            }
+            if let Some((value, pending)) = control.pending.take() {
+                control.sent_at = now;
+                control.value = value;
+                messages.extend(pending);
+            }
+        }
+        messages
+    }
+}
+
+/// Deserializes [`Limits::rate`],
+/// rejecting any rate which isn't a number of at least [`MIN_RATE`].
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    let rate = Option::<f64>::deserialize(deserializer)?;
+    match rate {
+        Some(rate) if !(MIN_RATE..=f64::MAX).contains(&rate) => Err(de::Error::custom(format!(
+            "`rate` must be at least {MIN_RATE} messages per second, not {rate}"
+        ))),
+        _ => Ok(rate),
+    }
+}
+
+/// What some messages control, and the value they set it to,
+/// or `None` if they don't set a value.
+///
+/// The values of several messages (e.g. the MSB and LSB of an NRPN)
+/// are combined into one.
+/// A data entry which isn't sent with its NRPN's parameter number doesn't have a value.
+fn control_value(messages: &[Message]) -> Option<(Vec<u8>, u32)> {
+    let mut key = Vec::new();
+    let mut value = None;
+    for message in messages {
+        let Message::Midi(event) = message else {
+            return None;
+        };
+        if key.is_empty() && event.is_data_entry() {
+            // Without its parameter number, this sets whichever parameter was chosen last,
+            // which could be a different one by the time it's sent
+            return None;
+        }
+        let (control, event_value) = event.control_value();
+        key.extend_from_slice(control);
+        if let Some((event_value, bits)) = event_value {
+            value = Some((value.unwrap_or(0) << bits) | event_value);
+        }
+    }
+    value.map(|value| (key, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use midly::{live::LiveEvent, MidiMessage};
+
+    use super::*;
+    use crate::midi::Event;
+
+    fn control_change(controller: u8, value: u8) -> Message {
+        Message::Midi(Event::from(LiveEvent::Midi {
+            channel: 0.into(),
+            message: MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+        }))
+    }
+
+    fn nrpn(parameter: u8, value: u8) -> Vec<Message> {
+        vec![
+            control_change(99, 0),
+            control_change(98, parameter),
+            control_change(6, value),
+            control_change(38, 0),
+        ]
+    }
+
+    fn limits(rate: Option<f64>, deadband: Option<u32>) -> Limits {
+        Limits { rate, deadband }
+    }
+
+    #[test]
+    fn sends_the_last_value_of_a_burst() {
+        let mut throttle = Throttle::new(limits(Some(10.0), None));
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(
+            throttle.offer(vec![control_change(7, 1)], at(0)),
+            [control_change(7, 1)]
+        );
+        assert!(throttle
+            .offer(vec![control_change(7, 2)], at(10))
+            .is_empty());
+        assert!(throttle
+            .offer(vec![control_change(7, 3)], at(20))
+            .is_empty());
+        // Other controls have their own limits
+        assert_eq!(
+            throttle.offer(vec![control_change(8, 1)], at(30)),
+            [control_change(8, 1)]
+        );
+
+        assert_eq!(throttle.next_due(), Some(at(100)));
+        assert!(throttle.take_due(at(99)).is_empty());
+        assert_eq!(throttle.take_due(at(100)), [control_change(7, 3)]);
+        assert_eq!(throttle.next_due(), None);
+
+        assert!(throttle
+            .offer(vec![control_change(7, 4)], at(150))
+            .is_empty());
+        assert_eq!(throttle.take_all(at(160)), [control_change(7, 4)]);
+    }
+
+    #[test]
+    fn drops_changes_within_the_deadband() {
+        let mut throttle = Throttle::new(limits(None, Some(10)));
+        let now = Instant::now();
+
+        assert_eq!(throttle.offer(vec![control_change(7, 50)], now).len(), 1);
+        assert!(throttle.offer(vec![control_change(7, 59)], now).is_empty());
+        assert!(throttle.offer(vec![control_change(7, 41)], now).is_empty());
+        assert_eq!(throttle.offer(vec![control_change(7, 60)], now).len(), 1);
+        // Measured from the last value sent
+        assert!(throttle.offer(vec![control_change(7, 69)], now).is_empty());
+    }
+
+    #[test]
+    fn limits_the_parts_of_an_nrpn_together() {
+        let mut throttle = Throttle::new(limits(Some(10.0), Some(2)));
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        assert_eq!(throttle.offer(nrpn(1, 10), at(0)), nrpn(1, 10));
+        assert_eq!(throttle.offer(nrpn(2, 10), at(0)), nrpn(2, 10));
+        assert!(throttle.offer(nrpn(1, 20), at(10)).is_empty());
+        assert!(throttle.offer(nrpn(1, 30), at(20)).is_empty());
+        assert_eq!(throttle.take_due(at(100)), nrpn(1, 30));
+
+        // The deadband is in the NRPN's 14-bit value,
+        // in which a step in the data entry MSB is 128
+        assert_eq!(throttle.offer(nrpn(1, 31), at(300)), nrpn(1, 31));
+
+        // A data entry by itself could set any parameter, so isn't held back
+        let data_entry = vec![control_change(6, 1), control_change(38, 0)];
+        assert_eq!(throttle.offer(data_entry.clone(), at(301)), data_entry);
+    }
+
+    #[test]
+    fn only_loads_sensible_rates() {
+        let load = |yaml: &str| serde_yaml::from_str::<Limits>(yaml);
+        assert_eq!(load("{rate: 50}").unwrap().rate, Some(50.0));
+        assert_eq!(load("{deadband: 2}").unwrap().rate, None);
+        for rate in ["0", "-1", "1e-300", ".nan", ".inf"] {
+            assert!(load(&format!("{{rate: {rate}}}")).is_err(), "{rate}");
+        }
+
+        // Other limits don't overflow
+        let mut throttle = Throttle::new(limits(Some(1e-300), None));
+        let now = Instant::now();
+        throttle.offer(vec![control_change(7, 1)], now);
+        throttle.offer(vec![control_change(7, 2)], now);
+        assert!(throttle.next_due().is_some());
+    }
+}
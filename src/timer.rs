@@ -0,0 +1,361 @@
+//! Virtual devices which send a message whenever one of their timers fires,
+//! e.g. to keep a surface online, or to do something a while after a button is pressed.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    future,
+    time::Duration,
+};
+
+use midly::{live::LiveEvent, MidiMessage};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationMilliSeconds};
+use tokio::time::{self, Instant};
+
+use crate::{
+    device::{self, Transport},
+    message::Number,
+    midi::{Event, MessageTemplate},
+    profile::{self, Control, Profile},
+};
+
+/// The MIDI channel of the notes which stand for the timers.
+const CHANNEL: u8 = 0;
+
+/// The velocity of the notes which a [`Timers`] device sends,
+/// and which start a timer when they're sent to it.
+const VELOCITY: u8 = 127;
+
+/// One of the timers of a [`Timers`] device, e.g. `{every: 1000}` or `{after: 2000}`.
+///
+/// Times are in milliseconds.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum Timer {
+    /// Fires repeatedly, starting as soon as the device connects.
+    Every {
+        #[serde_as(as = "DurationMilliSeconds<u64>")]
+        every: Duration,
+    },
+
+    /// Fires once, the given time after it's started by a message sent to it.
+    After {
+        #[serde_as(as = "DurationMilliSeconds<u64>")]
+        after: Duration,
+    },
+}
+
+impl Timer {
+    /// How long until the timer fires, once it's (re)started.
+    fn time(&self) -> Duration {
+        match self {
+            Timer::Every { every } => *every,
+            Timer::After { after } => *after,
+        }
+    }
+}
+
+/// A virtual device with some named timers,
+/// which sends a note whenever one of them fires.
+///
+/// The timers are notes on channel 0, in order of their names,
+/// which [`profile`] names so that mappings can refer to them.
+/// Sending a timer's note to the device starts it, or starts it again if it's running;
+/// sending it with a velocity of 0 (or a note off) stops it.
+pub struct Timers {
+    timers: Vec<Timer>,
+
+    /// When each of the timers next fires, if it's running.
+    due: Vec<Option<Instant>>,
+}
+
+impl Timers {
+    pub fn new(timers: &BTreeMap<String, Timer>) -> Result<Self, Error> {
+        check(timers)?;
+        Ok(Timers {
+            timers: timers.values().copied().collect(),
+            due: vec![None; timers.len()],
+        })
+    }
+}
+
+impl Transport for Timers {
+    type Message = Event;
+
+    async fn open(&mut self) -> Result<(), device::Error> {
+        let now = Instant::now();
+        for (timer, due) in self.timers.iter().zip(self.due.iter_mut()) {
+            if let Timer::Every { every } = timer {
+                *due = Some(now + *every);
+            }
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self) -> Result<Option<Vec<Event>>, device::Error> {
+        let Some(next) = self.due.iter().flatten().min().copied() else {
+            // Nothing to do until a timer is started,
+            // which cancels this
+            return future::pending().await;
+        };
+        time::sleep_until(next).await;
+
+        let now = Instant::now();
+        let mut events = Vec::new();
+        for (note, (timer, due)) in self.timers.iter().zip(self.due.iter_mut()).enumerate() {
+            match *due {
+                Some(at) if at <= now => {
+                    *due = match timer {
+                        Timer::Every { every } => {
+                            let next = at + *every;
+                            // If it's fallen behind, carry on from now rather than catching up
+                            Some(if next > now { next } else { now + *every })
+                        }
+                        Timer::After { .. } => None,
+                    };
+                    events.push(note_on(note as u8, VELOCITY));
+                }
+                _ => (),
+            }
+        }
+        Ok(Some(events))
+    }
+
+    async fn write(&mut self, event: Event) -> Result<(), device::Error> {
+        let timer = match event.button() {
+            Some(([status, note], start)) if status == 0x90 | CHANNEL => self
+                .timers
+                .get(usize::from(note))
+                .map(|timer| (usize::from(note), timer, start)),
+            _ => None,
+        };
+        let Some((index, timer, start)) = timer else {
+            log::trace!("Dropping a message which isn't for any timer: {event:?}");
+            return Ok(());
+        };
+        self.due[index] = start.then(|| Instant::now() + timer.time());
+        Ok(())
+    }
+}
+
+/// A profile naming the note for each timer of a [`Timers`] device,
+/// so that mappings can use e.g. `from: ping`, or `to: {target: timers, control: fade}`.
+pub fn profile(timers: &BTreeMap<String, Timer>) -> Result<Profile, Error> {
+    check(timers)?;
+    let controls = timers
+        .keys()
+        .enumerate()
+        .map(|(note, name)| {
+            let control = Control {
+                index: Vec::new(),
+                units: HashMap::new(),
+                message_template: MessageTemplate::NoteOn {
+                    channel: vec![Number::Value(CHANNEL.into())],
+                    note: vec![Number::Value(note as u32)],
+                    velocity: vec![Number::Value(VELOCITY.into())],
+                },
+            };
+            (name.clone(), control)
+        })
+        .collect();
+
+    Ok(Profile::new("timers".to_string(), controls, HashMap::new())?)
+}
+
+/// Checks that there's a note for every timer,
+/// and that none of them would fire continuously.
+fn check(timers: &BTreeMap<String, Timer>) -> Result<(), Error> {
+    if timers.len() > 128 {
+        return Err(Error::TooMany(timers.len()));
+    }
+    for (name, timer) in timers.iter() {
+        if timer.time().is_zero() {
+            return Err(Error::Zero(name.clone()));
+        }
+    }
+    Ok(())
+}
+
+fn note_on(note: u8, velocity: u8) -> Event {
+    Event::from(LiveEvent::Midi {
+        channel: CHANNEL.into(),
+        message: MidiMessage::NoteOn {
+            key: note.into(),
+            vel: velocity.into(),
+        },
+    })
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("A timers device can have up to 128 timers, not {0}")]
+    TooMany(usize),
+
+    #[error("Timer `{0}` should have a time of more than 0 ms")]
+    Zero(String),
+
+    #[error("{0}")]
+    Profile(#[from] profile::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Template;
+
+    fn timers(yaml: &str) -> BTreeMap<String, Timer> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    /// Reads what the device sends next, and how long after `start` it's sent.
+    async fn next(device: &mut Timers, start: Instant) -> (Vec<Event>, Duration) {
+        let events = device.read().await.unwrap().unwrap();
+        (events, start.elapsed())
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn repeats_without_drifting() {
+        let mut device = Timers::new(&timers("{ping: {every: 100}}")).unwrap();
+        let start = Instant::now();
+        device.open().await.unwrap();
+        for n in 1..=5 {
+            // Whatever happens between reads doesn't put the timer back
+            time::advance(ms(30)).await;
+            assert_eq!(
+                next(&mut device, start).await,
+                (vec![note_on(0, VELOCITY)], ms(100 * n))
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_ahead_after_a_stall() {
+        let mut device = Timers::new(&timers("{ping: {every: 100}}")).unwrap();
+        let start = Instant::now();
+        device.open().await.unwrap();
+        assert_eq!(next(&mut device, start).await.1, ms(100));
+
+        time::advance(ms(350)).await;
+        // Once for the whole stall, rather than a burst of the ones missed
+        assert_eq!(
+            next(&mut device, start).await,
+            (vec![note_on(0, VELOCITY)], ms(450))
+        );
+        assert_eq!(
+            next(&mut device, start).await,
+            (vec![note_on(0, VELOCITY)], ms(550))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fires_once_after_being_started() {
+        let mut device = Timers::new(&timers("{fade: {after: 200}, ping: {every: 1000}}")).unwrap();
+        let start = Instant::now();
+        device.open().await.unwrap();
+
+        // Nothing until it's started
+        time::advance(ms(500)).await;
+        device.write(note_on(0, VELOCITY)).await.unwrap();
+        assert_eq!(
+            next(&mut device, start).await,
+            (vec![note_on(0, VELOCITY)], ms(700))
+        );
+        assert_eq!(
+            next(&mut device, start).await,
+            (vec![note_on(1, VELOCITY)], ms(1000))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn restarts_when_started_again() {
+        let mut device = Timers::new(&timers("{fade: {after: 200}}")).unwrap();
+        let start = Instant::now();
+        device.open().await.unwrap();
+
+        device.write(note_on(0, VELOCITY)).await.unwrap();
+        time::advance(ms(150)).await;
+        device.write(note_on(0, 1)).await.unwrap();
+        assert_eq!(
+            next(&mut device, start).await,
+            (vec![note_on(0, VELOCITY)], ms(350))
+        );
+        // It only fires once
+        assert!(time::timeout(ms(1000), device.read()).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stops_when_sent_a_zero_velocity_or_note_off() {
+        let note_off = Event::from(LiveEvent::Midi {
+            channel: CHANNEL.into(),
+            message: MidiMessage::NoteOff {
+                key: 0.into(),
+                vel: 64.into(),
+            },
+        });
+        for stop in [note_on(0, 0), note_off] {
+            let mut device = Timers::new(&timers("{fade: {after: 200}}")).unwrap();
+            device.open().await.unwrap();
+            device.write(note_on(0, VELOCITY)).await.unwrap();
+            time::advance(ms(100)).await;
+            device.write(stop).await.unwrap();
+            assert!(time::timeout(ms(1000), device.read()).await.is_err());
+        }
+    }
+
+    #[test]
+    fn checks_the_timers() {
+        assert!(matches!(
+            Timers::new(&timers("{ping: {every: 0}}")),
+            Err(Error::Zero(name)) if name == "ping"
+        ));
+        assert!(matches!(
+            profile(&timers("{fade: {after: 0}}")),
+            Err(Error::Zero(name)) if name == "fade"
+        ));
+
+        let many: BTreeMap<_, _> = (0..129)
+            .map(|n| (format!("t{n:03}"), Timer::Every { every: ms(100) }))
+            .collect();
+        assert!(matches!(Timers::new(&many), Err(Error::TooMany(129))));
+        let enough: BTreeMap<_, _> = many.into_iter().take(128).collect();
+        assert!(Timers::new(&enough).is_ok());
+        assert!(profile(&enough).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn names_the_notes_it_sends() {
+        let timers = timers("{ping: {every: 300}, fade: {after: 100}, blink: {every: 200}}");
+        let profile = profile(&timers).unwrap();
+        let mut device = Timers::new(&timers).unwrap();
+        device.open().await.unwrap();
+        device.write(note_on(1, VELOCITY)).await.unwrap();
+
+        let mut fired = Vec::new();
+        for _ in 0..3 {
+            for event in device.read().await.unwrap().unwrap() {
+                let name = profile
+                    .controls
+                    .iter()
+                    .find(|(_, control)| {
+                        control.message_template.generate(Default::default())
+                            == Some(vec![event.clone()])
+                    })
+                    .map(|(name, _)| name.clone());
+                fired.push(name);
+            }
+        }
+        assert_eq!(
+            fired,
+            [
+                Some("fade".into()),
+                Some("blink".into()),
+                Some("ping".into())
+            ]
+        );
+    }
+}